@@ -0,0 +1,383 @@
+use bech32::{FromBase32, Variant};
+use ckb_jsonrpc_types::{JsonBytes, Script, ScriptHashType};
+use ckb_types::{h256, H256};
+use std::{fmt, io};
+
+#[cfg(feature = "client")]
+use crate::rpc_client::RpcClient;
+use crate::types::{GroupScript, RpcSearchKey, RpcSearchKeyParam, ScriptOrAddress, ScriptType};
+
+/// Full format, bech32m encoded
+const FULL: u8 = 0x00;
+/// Deprecated short format for the well known locks
+const SHORT: u8 = 0x01;
+/// Deprecated full format with hash type data
+const FULL_DATA: u8 = 0x02;
+/// Deprecated full format with hash type type
+const FULL_TYPE: u8 = 0x04;
+
+const SECP256K1_BLAKE160: H256 =
+    h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8");
+const SECP256K1_MULTISIG: H256 =
+    h256!("0x5c5069eb0857efc65e1bca0c07df34c31663b3622fd3876c876320fc9634e2a8");
+const ACP_MAINNET: H256 =
+    h256!("0xd369597ff47f29fbc0d47d2e3775370d1250b85140c670e4718af712983a2354");
+const ACP_TESTNET: H256 =
+    h256!("0x3419a1c09eb2567f6552ee7a8ecffd64155cffe0f1796e6e61ec088d740c1356");
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetworkType {
+    Mainnet,
+    Testnet,
+}
+
+impl NetworkType {
+    /// Network of a ckb node by its chain name, everything but the mainnet
+    /// uses testnet addresses
+    pub fn from_chain(chain: &str) -> Self {
+        if chain == "ckb" {
+            NetworkType::Mainnet
+        } else {
+            NetworkType::Testnet
+        }
+    }
+
+    pub fn prefix(self) -> &'static str {
+        match self {
+            NetworkType::Mainnet => "ckb",
+            NetworkType::Testnet => "ckt",
+        }
+    }
+}
+
+impl fmt::Display for NetworkType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkType::Mainnet => write!(f, "mainnet"),
+            NetworkType::Testnet => write!(f, "testnet"),
+        }
+    }
+}
+
+/// Decode a ckb address of `network` into its lock script
+pub fn parse_address(address: &str, network: NetworkType) -> Result<Script, io::Error> {
+    let (hrp, data, variant) =
+        bech32::decode(address).map_err(|e| invalid(format!("invalid address, {}", e)))?;
+    if hrp != network.prefix() {
+        return Err(invalid(format!(
+            "address prefix {} does not belong to the {}, expected {}",
+            hrp,
+            network,
+            network.prefix()
+        )));
+    }
+    let payload =
+        Vec::<u8>::from_base32(&data).map_err(|e| invalid(format!("invalid address, {}", e)))?;
+
+    let (format, body) = payload
+        .split_first()
+        .ok_or_else(|| invalid("empty address payload".to_string()))?;
+    match (*format, variant) {
+        (FULL, Variant::Bech32m) => {
+            if body.len() < 33 {
+                return Err(invalid("full address payload is too short".to_string()));
+            }
+            let hash_type = match body[32] {
+                0 => ScriptHashType::Data,
+                1 => ScriptHashType::Type,
+                2 => ScriptHashType::Data1,
+                t => return Err(invalid(format!("unknown hash type {}", t))),
+            };
+            Ok(script(code_hash(&body[..32])?, hash_type, &body[33..]))
+        }
+        (SHORT, Variant::Bech32) => {
+            let (index, args) = body
+                .split_first()
+                .ok_or_else(|| invalid("short address payload is too short".to_string()))?;
+            let (code_hash, args_len) = match (*index, network) {
+                (0x00, _) => (SECP256K1_BLAKE160, 20..=20),
+                (0x01, _) => (SECP256K1_MULTISIG, 20..=20),
+                (0x02, NetworkType::Mainnet) => (ACP_MAINNET, 20..=22),
+                (0x02, NetworkType::Testnet) => (ACP_TESTNET, 20..=22),
+                (i, _) => {
+                    return Err(invalid(format!(
+                        "unknown short address code hash index {}",
+                        i
+                    )))
+                }
+            };
+            if !args_len.contains(&args.len()) {
+                return Err(invalid(format!(
+                    "short address args must be {} to {} bytes, got {}",
+                    args_len.start(),
+                    args_len.end(),
+                    args.len()
+                )));
+            }
+            Ok(script(code_hash, ScriptHashType::Type, args))
+        }
+        (FULL_DATA | FULL_TYPE, Variant::Bech32) => {
+            if body.len() < 32 {
+                return Err(invalid("full address payload is too short".to_string()));
+            }
+            let hash_type = if *format == FULL_DATA {
+                ScriptHashType::Data
+            } else {
+                ScriptHashType::Type
+            };
+            Ok(script(code_hash(&body[..32])?, hash_type, &body[32..]))
+        }
+        (f, v) => Err(invalid(format!(
+            "unsupported address format {:#04x} with {:?} encoding",
+            f, v
+        ))),
+    }
+}
+
+impl RpcSearchKeyParam {
+    /// Whether some script still has to be decoded from an address
+    pub fn has_address(&self) -> bool {
+        std::iter::once(&self.script)
+            .chain(self.group.iter().map(|g| &g.script))
+            .any(|s| matches!(s, ScriptOrAddress::Address(_)))
+    }
+
+    /// The search key with addresses decoded for `network`. Group scripts are
+    /// a set, duplicates are dropped and the rest kept in a canonical order.
+    pub fn resolve(self, network: NetworkType) -> Result<RpcSearchKey, io::Error> {
+        let script = resolve_script(self.script, &self.script_type, network)?;
+        let mut group = Vec::with_capacity(self.group.len());
+        for g in self.group {
+            let member = GroupScript {
+                script: resolve_script(g.script, &g.script_type, network)?,
+                script_type: g.script_type,
+            };
+            if (member.script != script || member.script_type != self.script_type)
+                && !group.contains(&member)
+            {
+                group.push(member);
+            }
+        }
+        group.sort_by_cached_key(|g| serde_json::to_string(g).unwrap());
+
+        Ok(RpcSearchKey {
+            script,
+            script_type: self.script_type,
+            script_search_mode: self.script_search_mode,
+            filter: self.filter,
+            group,
+        })
+    }
+}
+
+fn resolve_script(
+    script: ScriptOrAddress,
+    script_type: &ScriptType,
+    network: NetworkType,
+) -> Result<Script, io::Error> {
+    match script {
+        ScriptOrAddress::Script(script) => Ok(script),
+        ScriptOrAddress::Address(address) => {
+            if *script_type != ScriptType::Lock {
+                return Err(invalid(
+                    "an address can only be used with script_type lock".to_string(),
+                ));
+            }
+            parse_address(&address, network)
+        }
+    }
+}
+
+/// Decode the address of `key`, if any, for the network of the ckb node
+#[cfg(feature = "client")]
+pub async fn resolve_search_key(
+    client: &RpcClient,
+    key: RpcSearchKeyParam,
+) -> Result<RpcSearchKey, io::Error> {
+    let network = if key.has_address() {
+        NetworkType::from_chain(&client.get_blockchain_info().await?.chain)
+    } else {
+        // not looked at
+        NetworkType::Mainnet
+    };
+    key.resolve(network)
+}
+
+fn script(code_hash: H256, hash_type: ScriptHashType, args: &[u8]) -> Script {
+    Script {
+        code_hash,
+        hash_type,
+        args: JsonBytes::from_vec(args.to_vec()),
+    }
+}
+
+fn code_hash(bytes: &[u8]) -> Result<H256, io::Error> {
+    H256::from_slice(bytes).map_err(|e| invalid(format!("invalid code hash, {}", e)))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bech32::ToBase32;
+    use ckb_types::{h160, H160};
+
+    const ARGS: H160 = h160!("0xb39bbc0b3673c7d36450bc14cfcdad2d559c6c64");
+
+    fn encode(network: NetworkType, payload: &[u8], variant: Variant) -> String {
+        bech32::encode(network.prefix(), payload.to_base32(), variant).unwrap()
+    }
+
+    fn args() -> Vec<u8> {
+        ARGS.as_bytes().to_vec()
+    }
+
+    fn blake160(args: &[u8]) -> Script {
+        script(SECP256K1_BLAKE160, ScriptHashType::Type, args)
+    }
+
+    fn message(result: Result<Script, io::Error>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn full_format() {
+        // the examples of RFC 0021
+        assert_eq!(
+            parse_address(
+                "ckb1qzda0cr08m85hc8jlnfp3zer7xulejywt49kt2rr0vthywaa50xwsqdnnw7qkdnnclfkg59uzn8umtfd2kwxceqxwquc4",
+                NetworkType::Mainnet
+            )
+            .unwrap(),
+            blake160(&args())
+        );
+
+        for (byte, hash_type) in [
+            (0, ScriptHashType::Data),
+            (1, ScriptHashType::Type),
+            (2, ScriptHashType::Data1),
+        ] {
+            let mut payload = vec![FULL];
+            payload.extend(SECP256K1_MULTISIG.as_bytes());
+            payload.push(byte);
+            payload.extend(args());
+            let address = encode(NetworkType::Testnet, &payload, Variant::Bech32m);
+            assert_eq!(
+                parse_address(&address, NetworkType::Testnet).unwrap(),
+                script(SECP256K1_MULTISIG, hash_type, &args())
+            );
+        }
+    }
+
+    #[test]
+    fn full_format_needs_bech32m() {
+        let mut payload = vec![FULL];
+        payload.extend(SECP256K1_BLAKE160.as_bytes());
+        payload.push(1);
+        let address = encode(NetworkType::Mainnet, &payload, Variant::Bech32);
+        assert!(message(parse_address(&address, NetworkType::Mainnet))
+            .contains("unsupported address format 0x00"));
+    }
+
+    #[test]
+    fn full_format_unknown_hash_type() {
+        let mut payload = vec![FULL];
+        payload.extend(SECP256K1_BLAKE160.as_bytes());
+        payload.push(3);
+        let address = encode(NetworkType::Mainnet, &payload, Variant::Bech32m);
+        assert!(
+            message(parse_address(&address, NetworkType::Mainnet)).contains("unknown hash type 3")
+        );
+    }
+
+    #[test]
+    fn short_format() {
+        assert_eq!(
+            parse_address(
+                "ckb1qyqt8xaupvm8837nv3gtc9x0ekkj64vud3jqfwyw5v",
+                NetworkType::Mainnet
+            )
+            .unwrap(),
+            blake160(&args())
+        );
+
+        for (index, network, code_hash, args_len) in [
+            (0x01, NetworkType::Mainnet, SECP256K1_MULTISIG, 20),
+            (0x02, NetworkType::Mainnet, ACP_MAINNET, 21),
+            (0x02, NetworkType::Testnet, ACP_TESTNET, 22),
+        ] {
+            let args = vec![7; args_len];
+            let mut payload = vec![SHORT, index];
+            payload.extend(&args);
+            let address = encode(network, &payload, Variant::Bech32);
+            assert_eq!(
+                parse_address(&address, network).unwrap(),
+                script(code_hash, ScriptHashType::Type, &args)
+            );
+        }
+    }
+
+    #[test]
+    fn short_format_checks_index_and_args() {
+        let address = encode(NetworkType::Mainnet, &[SHORT, 0x03, 0, 0], Variant::Bech32);
+        assert!(message(parse_address(&address, NetworkType::Mainnet))
+            .contains("unknown short address code hash index 3"));
+
+        let mut payload = vec![SHORT, 0x00];
+        payload.extend([7; 21]);
+        let address = encode(NetworkType::Mainnet, &payload, Variant::Bech32);
+        assert!(message(parse_address(&address, NetworkType::Mainnet))
+            .contains("short address args must be 20 to 20 bytes, got 21"));
+    }
+
+    #[test]
+    fn deprecated_full_format() {
+        for (format, hash_type) in [
+            (FULL_DATA, ScriptHashType::Data),
+            (FULL_TYPE, ScriptHashType::Type),
+        ] {
+            let mut payload = vec![format];
+            payload.extend(SECP256K1_BLAKE160.as_bytes());
+            payload.extend(args());
+            let address = encode(NetworkType::Mainnet, &payload, Variant::Bech32);
+            assert_eq!(
+                parse_address(&address, NetworkType::Mainnet).unwrap(),
+                script(SECP256K1_BLAKE160, hash_type, &args())
+            );
+        }
+
+        let address = encode(NetworkType::Mainnet, &[FULL_TYPE, 0, 0], Variant::Bech32);
+        assert!(message(parse_address(&address, NetworkType::Mainnet))
+            .contains("full address payload is too short"));
+    }
+
+    #[test]
+    fn wrong_network_prefix() {
+        let address = "ckb1qyqt8xaupvm8837nv3gtc9x0ekkj64vud3jqfwyw5v";
+        assert_eq!(
+            message(parse_address(address, NetworkType::Testnet)),
+            "address prefix ckb does not belong to the testnet, expected ckt"
+        );
+
+        let address = encode(NetworkType::Testnet, &[SHORT, 0x00], Variant::Bech32);
+        assert_eq!(
+            message(parse_address(&address, NetworkType::Mainnet)),
+            "address prefix ckt does not belong to the mainnet, expected ckb"
+        );
+    }
+
+    #[test]
+    fn not_an_address() {
+        assert!(
+            message(parse_address("ckb1qyq", NetworkType::Mainnet)).starts_with("invalid address")
+        );
+        let address = encode(NetworkType::Mainnet, &[], Variant::Bech32);
+        assert_eq!(
+            message(parse_address(&address, NetworkType::Mainnet)),
+            "empty address payload"
+        );
+    }
+}
@@ -1,13 +1,20 @@
 use crate::{
-    types::{CellType, IndexerTip, Order, RpcSearchKey, Tx},
-    Rpc, Submit, SubmitProcess, TipState,
+    types::{CellType, IndexerTip, Order, RpcSearchKey, SearchKey, Tx, TxWithCells},
+    Rpc, Submit, SubmitProcess, TipState, CONFIRMATIONS,
 };
 
-use ckb_jsonrpc_types::{CellData, CellInfo, OutPoint};
-use ckb_types::{packed, prelude::Unpack};
-use std::collections::HashMap;
+use ckb_jsonrpc_types::{
+    BlockNumber, CellData, CellInfo, HeaderView, JsonBytes, OutPoint, TransactionView,
+};
+use ckb_types::{packed, prelude::Unpack, H256};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+};
 // H256 + U32
 const OUTPOINT_SIZE: usize = 32 + 4;
+/// Transactions asked from the indexer at a time, per search key
+const PAGE_SIZE: u32 = 32;
 
 pub struct CellProcess<T, P, R> {
     key: RpcSearchKey,
@@ -15,6 +22,13 @@ pub struct CellProcess<T, P, R> {
     client: R,
     process_fn: P,
     stop: bool,
+    /// Last block to scan, the process ends once it is submitted
+    end: Option<BlockNumber>,
+    /// The block of the last transaction collected and the number of its
+    /// transactions collected so far
+    position: Option<(BlockNumber, u32)>,
+    /// Transactions of the tip block to pass over, they were submitted before
+    skip: u32,
 }
 
 impl<T, P, R> CellProcess<T, P, R>
@@ -30,6 +44,35 @@ where
             client,
             process_fn: process,
             stop: false,
+            end: None,
+            position: None,
+            skip: 0,
+        }
+    }
+
+    /// Go on after the first `position` transactions of the tip block,
+    /// which were submitted before
+    pub fn resume_at(mut self, position: u32) -> Self {
+        self.skip = position;
+        self
+    }
+
+    /// Stop after block `end` instead of following the chain
+    pub fn with_end(mut self, end: Option<BlockNumber>) -> Self {
+        self.end = end;
+        self
+    }
+
+    /// Transactions of the block last collected, collected so far
+    fn position(&self) -> u32 {
+        self.position.map(|(_, count)| count).unwrap_or_default()
+    }
+
+    /// Whether every block up to the end block has been submitted
+    pub fn is_done(&self) -> bool {
+        match self.end {
+            Some(end) => self.scan_tip.load().block_number.value() > end.value(),
+            None => false,
         }
     }
 
@@ -37,145 +80,517 @@ where
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(8));
         interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
         loop {
-            if self.stop || self.process_fn.is_closed() {
+            if self.stop || self.process_fn.is_closed() || self.is_done() {
                 break;
             }
             self.scan(&mut interval).await;
         }
     }
 
-    #[allow(unused_assignments)]
     async fn scan(&mut self, interval: &mut tokio::time::Interval) {
         let indexer_tip = rpc_get!(self.client.get_indexer_tip());
         let old_tip = self.scan_tip.load().clone();
 
-        if indexer_tip.block_number.value().saturating_sub(24) > old_tip.block_number.value() {
-            // use tip - 24 as new tip
-            let new_tip = {
-                let new = rpc_get!(self.client.get_header_by_number(
-                    indexer_tip.block_number.value().saturating_sub(24).into(),
-                ));
-                IndexerTip {
+        // use tip - CONFIRMATIONS as new tip, or the block after the end
+        let target = {
+            let confirmed = indexer_tip
+                .block_number
+                .value()
+                .saturating_sub(CONFIRMATIONS);
+            match self.end {
+                Some(end) => confirmed.min(end.value() + 1),
+                None => confirmed,
+            }
+        };
+        if target > old_tip.block_number.value() {
+            // the new tip and the last block below it, which is above 0
+            let (new_tip, last) = {
+                let new = rpc_get!(self.client.get_header_by_number(target.into()));
+                let last = IndexerTip {
+                    block_hash: new.inner.parent_hash,
+                    block_number: (new.inner.number.value() - 1).into(),
+                };
+                let tip = IndexerTip {
                     block_hash: new.hash,
                     block_number: new.inner.number,
-                }
+                };
+                (tip, last)
             };
 
-            let search_key = self
-                .key
-                .clone()
-                .into_key(Some([old_tip.block_number, new_tip.block_number]));
+            let mut stream = TxStream::new(
+                self.key
+                    .search_keys(Some([old_tip.block_number, new_tip.block_number])),
+            );
 
-            let mut cursor = None;
             let mut submits = HashMap::new();
-            loop {
-                let txs = rpc_get!(self.client.get_transactions(
-                    search_key.clone(),
-                    Order::Asc,
-                    32.into(),
-                    cursor.clone()
-                ));
-
-                let tx_len = txs.objects.len();
-                let mut total_size = 0;
-                for tx in txs.objects {
-                    match tx {
-                        Tx::Grouped(tx_with_cells) => {
-                            let tx = rpc_get!(self.client.get_transaction(&tx_with_cells.tx_hash))
-                                .unwrap();
-                            let header = rpc_get!(self
-                                .client
-                                .get_header_by_number(tx_with_cells.block_number));
-                            let submit_entry =
-                                submits.entry(header.hash.clone()).or_insert(Submit {
-                                    header,
-                                    inputs: Default::default(),
-                                    outputs: Default::default(),
-                                });
-                            for (ty, idx) in tx_with_cells.cells {
-                                let index = idx.value() as usize;
-                                // header size
-                                total_size += 8;
-                                match ty {
-                                    CellType::Input => {
-                                        total_size += OUTPOINT_SIZE;
-                                        let outpoint =
-                                            tx.inner.inputs[index].previous_output.clone();
-                                        submit_entry.inputs.push(outpoint)
-                                    }
-                                    CellType::Output => {
-                                        total_size += OUTPOINT_SIZE;
-                                        let cell_info = {
-                                            let data = tx.inner.outputs_data.get(index).cloned();
-                                            total_size += data
-                                                .as_ref()
-                                                .map(|a| a.as_bytes().len())
-                                                .unwrap_or_default();
-                                            let output = tx.inner.outputs[index].clone();
-                                            total_size += packed::CellOutput::from(output.clone())
-                                                .total_size();
-                                            CellInfo {
-                                                output,
-                                                data: data.map(|d| CellData {
-                                                    hash: packed::CellOutput::calc_data_hash(
-                                                        d.as_bytes(),
-                                                    )
-                                                    .unpack(),
-                                                    content: d,
-                                                }),
-                                            }
-                                        };
-                                        let outpoint = OutPoint {
-                                            tx_hash: tx_with_cells.tx_hash.clone(),
-                                            index: idx,
-                                        };
-                                        submit_entry.outputs.push((outpoint, cell_info));
-                                    }
-                                }
-                            }
-                        }
-                        Tx::Ungrouped(_) => unreachable!(),
+            let mut total_size = 0;
+            while let Some(tx_with_cells) = rpc_get!(stream.next(&self.client)) {
+                match self.position {
+                    Some((block, ref mut count)) if block == tx_with_cells.block_number => {
+                        *count += 1
                     }
-
-                    if total_size > 1024 * 1024 {
-                        let mut cells = submits.drain().map(|(_, v)| v).collect::<Vec<Submit>>();
-                        cells.sort_unstable_by_key(|v| v.header.inner.number.value());
-                        let temp_tip = {
-                            let h = &cells.last().unwrap().header;
-                            IndexerTip {
-                                block_number: h.inner.number,
-                                block_hash: h.hash.clone(),
-                            }
-                        };
-
-                        if !self.process_fn.submit_cells(cells).await {
-                            self.stop = true;
-                            return;
-                        }
-                        self.scan_tip.update(temp_tip);
-                        total_size = 0;
+                    _ => self.position = Some((tx_with_cells.block_number, 1)),
+                }
+                if self.skip > 0 {
+                    if tx_with_cells.block_number == old_tip.block_number {
+                        self.skip -= 1;
+                        continue;
                     }
+                    self.skip = 0;
                 }
-                total_size = 0;
-                if !submits.is_empty() {
+
+                let tx = rpc_get!(self.client.get_transaction(&tx_with_cells.tx_hash)).unwrap();
+                let header = rpc_get!(self.client.get_header_by_number(tx_with_cells.block_number));
+                total_size += collect_cells(&mut submits, tx_with_cells, tx, header);
+
+                if total_size > 1024 * 1024 {
                     let mut cells = submits.drain().map(|(_, v)| v).collect::<Vec<Submit>>();
                     cells.sort_unstable_by_key(|v| v.header.inner.number.value());
+                    let temp_tip = {
+                        let h = &cells.last().unwrap().header;
+                        IndexerTip {
+                            block_number: h.inner.number,
+                            block_hash: h.hash.clone(),
+                        }
+                    };
 
-                    if !self.process_fn.submit_cells(cells).await {
+                    if !self
+                        .process_fn
+                        .submit_cells_at(cells, self.position())
+                        .await
+                    {
                         self.stop = true;
                         return;
                     }
+                    self.scan_tip.update(temp_tip);
+                    total_size = 0;
                 }
 
-                if tx_len == 32 {
-                    cursor = Some(txs.last_cursor);
-                } else {
-                    break;
+                // submit what a page of transactions brought, as one batch
+                if stream.page_done() && !submits.is_empty() {
+                    let mut cells = submits.drain().map(|(_, v)| v).collect::<Vec<Submit>>();
+                    cells.sort_unstable_by_key(|v| v.header.inner.number.value());
+
+                    if !self
+                        .process_fn
+                        .submit_cells_at(cells, self.position())
+                        .await
+                    {
+                        self.stop = true;
+                        return;
+                    }
+                    total_size = 0;
                 }
             }
             self.scan_tip.update(new_tip);
+            self.process_fn.scanned(last);
         } else {
             interval.tick().await;
         }
     }
 }
+
+/// Add the cells of one indexer transaction to the submit of its block,
+/// returns the estimated encoded size of what was added
+fn collect_cells(
+    submits: &mut HashMap<H256, Submit>,
+    tx_with_cells: TxWithCells,
+    tx: TransactionView,
+    header: HeaderView,
+) -> usize {
+    let mut total_size = 0;
+    let submit_entry = submits.entry(header.hash.clone()).or_insert(Submit {
+        header,
+        inputs: Default::default(),
+        outputs: Default::default(),
+    });
+    for (ty, idx) in tx_with_cells.cells {
+        let index = idx.value() as usize;
+        // header size
+        total_size += 8;
+        match ty {
+            CellType::Input => {
+                total_size += OUTPOINT_SIZE;
+                let outpoint = tx.inner.inputs[index].previous_output.clone();
+                submit_entry.inputs.push(outpoint)
+            }
+            CellType::Output => {
+                total_size += OUTPOINT_SIZE;
+                let cell_info = {
+                    let data = tx.inner.outputs_data.get(index).cloned();
+                    total_size += data
+                        .as_ref()
+                        .map(|a| a.as_bytes().len())
+                        .unwrap_or_default();
+                    let output = tx.inner.outputs[index].clone();
+                    total_size += packed::CellOutput::from(output.clone()).total_size();
+                    CellInfo {
+                        output,
+                        data: data.map(|d| CellData {
+                            hash: packed::CellOutput::calc_data_hash(d.as_bytes()).unpack(),
+                            content: d,
+                        }),
+                    }
+                };
+                let outpoint = OutPoint {
+                    tx_hash: tx_with_cells.tx_hash.clone(),
+                    index: idx,
+                };
+                submit_entry.outputs.push((outpoint, cell_info));
+            }
+        }
+    }
+    total_size
+}
+
+/// All cell changes of `key` in blocks `[from, to)` ordered by block number,
+/// collected the same way `CellProcess` does. Rpc errors are returned instead
+/// of retried.
+pub async fn fetch_submits<R: Rpc>(
+    client: &R,
+    key: RpcSearchKey,
+    from: BlockNumber,
+    to: BlockNumber,
+) -> Result<Vec<Submit>, io::Error> {
+    let mut stream = TxStream::new(key.search_keys(Some([from, to])));
+    let mut submits = HashMap::new();
+    while let Some(tx_with_cells) = stream.next(client).await? {
+        let tx = client
+            .get_transaction(&tx_with_cells.tx_hash)
+            .await?
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("transaction {:#x} not found", tx_with_cells.tx_hash),
+                )
+            })?;
+        let header = client
+            .get_header_by_number(tx_with_cells.block_number)
+            .await?;
+        collect_cells(&mut submits, tx_with_cells, tx, header);
+    }
+    let mut submits = submits.into_values().collect::<Vec<_>>();
+    submits.sort_unstable_by_key(|v| v.header.inner.number.value());
+    Ok(submits)
+}
+
+/// Transactions of several search keys over one block range, merged in chain
+/// order. A transaction found by more than one key comes once, with the union
+/// of the cells each key matched.
+struct TxStream {
+    sources: Vec<TxSource>,
+    /// Set when the last transaction returned emptied the page it came from
+    page_done: bool,
+}
+
+struct TxSource {
+    key: SearchKey,
+    cursor: Option<JsonBytes>,
+    buffer: VecDeque<TxWithCells>,
+    /// No more pages after the buffered one
+    last_page: bool,
+}
+
+impl TxStream {
+    fn new(keys: Vec<SearchKey>) -> Self {
+        TxStream {
+            sources: keys
+                .into_iter()
+                .map(|key| TxSource {
+                    key,
+                    cursor: None,
+                    buffer: VecDeque::new(),
+                    last_page: false,
+                })
+                .collect(),
+            page_done: false,
+        }
+    }
+
+    fn page_done(&self) -> bool {
+        self.page_done
+    }
+
+    /// The next transaction, or none once every key is exhausted. After an
+    /// error the call can be repeated, nothing is lost.
+    async fn next<R: Rpc>(&mut self, client: &R) -> Result<Option<TxWithCells>, io::Error> {
+        for source in self.sources.iter_mut() {
+            if !source.buffer.is_empty() || source.last_page {
+                continue;
+            }
+            let txs = client
+                .get_transactions(
+                    source.key.clone(),
+                    Order::Asc,
+                    PAGE_SIZE.into(),
+                    source.cursor.clone(),
+                )
+                .await?;
+            source.last_page = txs.objects.len() < PAGE_SIZE as usize;
+            source.cursor = Some(txs.last_cursor);
+            for tx in txs.objects {
+                match tx {
+                    Tx::Grouped(tx_with_cells) => source.buffer.push_back(tx_with_cells),
+                    Tx::Ungrouped(_) => unreachable!(),
+                }
+            }
+        }
+
+        let first = match self
+            .sources
+            .iter()
+            .filter_map(|s| s.buffer.front())
+            .map(|tx| (tx.block_number.value(), tx.tx_index.value()))
+            .min()
+        {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+
+        let mut merged: Option<TxWithCells> = None;
+        self.page_done = false;
+        for source in self.sources.iter_mut() {
+            let is_first = source
+                .buffer
+                .front()
+                .map(|tx| (tx.block_number.value(), tx.tx_index.value()) == first)
+                .unwrap_or(false);
+            if !is_first {
+                continue;
+            }
+            let tx = source.buffer.pop_front().unwrap();
+            self.page_done |= source.buffer.is_empty();
+            match merged {
+                Some(ref mut merged) => {
+                    for cell in tx.cells {
+                        if !merged.cells.contains(&cell) {
+                            merged.cells.push(cell);
+                        }
+                    }
+                }
+                None => merged = Some(tx),
+            }
+        }
+        Ok(merged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{GroupScript, Pagination, ScriptType};
+    use ckb_jsonrpc_types::{BlockView, Script, ScriptHashType, Uint32};
+    use std::sync::Mutex;
+
+    /// An indexer holding the transactions of each lock script, by args
+    #[derive(Default)]
+    struct MockRpc {
+        txs: HashMap<JsonBytes, Vec<TxWithCells>>,
+        /// `get_transactions` calls, by args
+        calls: Mutex<HashMap<JsonBytes, usize>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Rpc for MockRpc {
+        async fn get_transactions(
+            &self,
+            search_key: SearchKey,
+            _order: Order,
+            limit: Uint32,
+            after: Option<JsonBytes>,
+        ) -> Result<Pagination<Tx>, io::Error> {
+            let args = search_key.script.args;
+            *self.calls.lock().unwrap().entry(args.clone()).or_default() += 1;
+            let start = after.map_or(0, |cursor| cursor.as_bytes()[0] as usize);
+            let txs = self.txs.get(&args).cloned().unwrap_or_default();
+            let objects = txs
+                .into_iter()
+                .skip(start)
+                .take(limit.value() as usize)
+                .collect::<Vec<_>>();
+            let end = start + objects.len();
+            Ok(Pagination {
+                objects: objects.into_iter().map(Tx::Grouped).collect(),
+                last_cursor: JsonBytes::from_vec(vec![end as u8]),
+            })
+        }
+
+        async fn get_transaction(&self, _: &H256) -> Result<Option<TransactionView>, io::Error> {
+            Ok(None)
+        }
+
+        async fn get_header_by_number(&self, _: BlockNumber) -> Result<HeaderView, io::Error> {
+            Err(not_mocked("get_header_by_number"))
+        }
+
+        async fn get_indexer_tip(&self) -> Result<IndexerTip, io::Error> {
+            Err(not_mocked("get_indexer_tip"))
+        }
+
+        async fn get_block_by_number(&self, _: BlockNumber) -> Result<BlockView, io::Error> {
+            Err(not_mocked("get_block_by_number"))
+        }
+    }
+
+    /// Only transactions are served, the tests never ask for anything else
+    fn not_mocked(method: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} is not mocked", method),
+        )
+    }
+
+    fn args(n: u8) -> JsonBytes {
+        JsonBytes::from_vec(vec![n])
+    }
+
+    fn lock(n: u8) -> Script {
+        Script {
+            code_hash: H256::default(),
+            hash_type: ScriptHashType::Type,
+            args: args(n),
+        }
+    }
+
+    fn key(locks: &[u8]) -> RpcSearchKey {
+        RpcSearchKey {
+            script: lock(locks[0]),
+            script_type: ScriptType::Lock,
+            script_search_mode: None,
+            filter: None,
+            group: locks[1..]
+                .iter()
+                .map(|n| GroupScript {
+                    script: lock(*n),
+                    script_type: ScriptType::Lock,
+                })
+                .collect(),
+        }
+    }
+
+    fn tx(block: u64, index: u32, cells: &[(CellType, u32)]) -> TxWithCells {
+        TxWithCells {
+            tx_hash: H256::from_slice(&[block as u8, index as u8].repeat(16)).unwrap(),
+            block_number: block.into(),
+            tx_index: index.into(),
+            cells: cells
+                .iter()
+                .map(|(ty, i)| (ty.clone(), (*i).into()))
+                .collect(),
+        }
+    }
+
+    async fn collect(stream: &mut TxStream, client: &MockRpc) -> Vec<TxWithCells> {
+        let mut txs = Vec::new();
+        while let Some(tx) = stream.next(client).await.unwrap() {
+            txs.push(tx);
+        }
+        txs
+    }
+
+    fn positions(txs: &[TxWithCells]) -> Vec<(u64, u32)> {
+        txs.iter()
+            .map(|tx| (tx.block_number.value(), tx.tx_index.value()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn merges_keys_in_chain_order() {
+        let mut client = MockRpc::default();
+        client.txs.insert(
+            args(1),
+            vec![
+                tx(10, 0, &[(CellType::Output, 0)]),
+                tx(12, 3, &[(CellType::Input, 0)]),
+            ],
+        );
+        client.txs.insert(
+            args(2),
+            vec![
+                tx(10, 1, &[(CellType::Output, 1)]),
+                tx(11, 0, &[(CellType::Output, 0)]),
+                tx(12, 2, &[(CellType::Output, 0)]),
+            ],
+        );
+
+        let mut stream = TxStream::new(key(&[1, 2]).search_keys(None));
+        let txs = collect(&mut stream, &client).await;
+        assert_eq!(
+            positions(&txs),
+            vec![(10, 0), (10, 1), (11, 0), (12, 2), (12, 3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn shared_transactions_come_once_with_all_cells() {
+        let mut client = MockRpc::default();
+        client.txs.insert(
+            args(1),
+            vec![tx(10, 0, &[(CellType::Input, 0), (CellType::Output, 0)])],
+        );
+        client.txs.insert(
+            args(2),
+            vec![tx(10, 0, &[(CellType::Output, 0), (CellType::Output, 1)])],
+        );
+        client.txs.insert(
+            args(3),
+            vec![
+                tx(10, 0, &[(CellType::Input, 1)]),
+                tx(11, 0, &[(CellType::Output, 0)]),
+            ],
+        );
+
+        let mut stream = TxStream::new(key(&[1, 2, 3]).search_keys(None));
+        let txs = collect(&mut stream, &client).await;
+        assert_eq!(positions(&txs), vec![(10, 0), (11, 0)]);
+        assert_eq!(
+            txs[0].cells,
+            vec![
+                (CellType::Input, 0.into()),
+                (CellType::Output, 0.into()),
+                (CellType::Output, 1.into()),
+                (CellType::Input, 1.into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn follows_the_pages_of_each_key() {
+        let mut client = MockRpc::default();
+        let many = (0..PAGE_SIZE + 5)
+            .map(|i| tx(10 + i as u64, 0, &[(CellType::Output, 0)]))
+            .collect::<Vec<_>>();
+        client.txs.insert(args(1), many.clone());
+        client
+            .txs
+            .insert(args(2), vec![tx(20, 1, &[(CellType::Output, 0)])]);
+
+        let mut stream = TxStream::new(key(&[1, 2]).search_keys(None));
+        let mut txs = Vec::new();
+        let mut pages_done = 0;
+        while let Some(tx) = stream.next(&client).await.unwrap() {
+            pages_done += stream.page_done() as usize;
+            txs.push(tx);
+        }
+
+        let mut expected = positions(&many);
+        expected.push((20, 1));
+        expected.sort_unstable();
+        assert_eq!(positions(&txs), expected);
+        // two pages of key 1, one of key 2
+        assert_eq!(pages_done, 3);
+        let calls = client.calls.lock().unwrap();
+        assert_eq!(calls[&args(1)], 2);
+        assert_eq!(calls[&args(2)], 1);
+    }
+
+    #[tokio::test]
+    async fn empty_keys_end_the_stream() {
+        let client = MockRpc::default();
+        let mut stream = TxStream::new(key(&[1, 2]).search_keys(None));
+        assert!(stream.next(&client).await.unwrap().is_none());
+        // exhausted keys are not asked again
+        assert!(stream.next(&client).await.unwrap().is_none());
+        assert_eq!(client.calls.lock().unwrap()[&args(1)], 1);
+    }
+}
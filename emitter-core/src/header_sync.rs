@@ -1,4 +1,4 @@
-use crate::{types::IndexerTip, Rpc, SubmitProcess, TipState};
+use crate::{types::IndexerTip, Rpc, SubmitProcess, TipState, CONFIRMATIONS};
 
 pub struct HeaderSyncProcess<T, P, R> {
     scan_tip: T,
@@ -36,12 +36,20 @@ where
         let indexer_tip = rpc_get!(self.client.get_indexer_tip());
         let old_tip = self.scan_tip.load().clone();
 
-        if indexer_tip.block_number.value().saturating_sub(24) > old_tip.block_number.value() {
+        if indexer_tip
+            .block_number
+            .value()
+            .saturating_sub(CONFIRMATIONS)
+            > old_tip.block_number.value()
+        {
             let new_tip = {
                 let new = rpc_get!(self.client.get_header_by_number(
                     // 256 headers as a step
                     std::cmp::min(
-                        indexer_tip.block_number.value().saturating_sub(24),
+                        indexer_tip
+                            .block_number
+                            .value()
+                            .saturating_sub(CONFIRMATIONS),
                         old_tip.block_number.value() + 256,
                     )
                     .into(),
@@ -62,7 +70,8 @@ where
             }
 
             if !self.process_fn.submit_headers(headers).await {
-                self.stop = true
+                self.stop = true;
+                return;
             }
 
             self.scan_tip.update(new_tip);
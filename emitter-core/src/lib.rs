@@ -15,10 +15,12 @@ macro_rules! rpc_get {
     }};
 }
 
+pub mod address;
 pub mod cell_process;
 pub mod header_sync;
 #[cfg(feature = "client")]
 pub mod rpc_client;
+pub mod schema;
 pub mod types;
 
 use async_trait::async_trait;
@@ -26,14 +28,21 @@ use ckb_jsonrpc_types::{
     BlockNumber, BlockView, CellInfo, HeaderView, JsonBytes, OutPoint, TransactionView, Uint32,
 };
 use ckb_types::H256;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use types::{HeaderViewWithExtension, IndexerTip, Order, Pagination, SearchKey, Tx};
 
+/// The scan processes stay this many blocks behind the indexer tip
+pub const CONFIRMATIONS: u64 = 24;
+
 // Cell changes on a single block
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Submit {
+    #[schemars(with = "schema::HeaderView")]
     pub header: HeaderView,
+    #[schemars(with = "Vec<schema::OutPoint>")]
     pub inputs: Vec<OutPoint>,
+    #[schemars(with = "Vec<(schema::OutPoint, schema::CellInfo)>")]
     pub outputs: Vec<(OutPoint, CellInfo)>,
 }
 
@@ -43,11 +52,19 @@ pub trait TipState {
 }
 
 #[async_trait]
-pub trait SubmitProcess {
+pub trait SubmitProcess: Send {
     fn is_closed(&self) -> bool;
     // if false return, it means this cell process should be shutdown
     async fn submit_cells(&mut self, cells: Vec<Submit>) -> bool;
+    /// `submit_cells` with the number of transactions of the last block in
+    /// `cells` submitted so far, earlier batches included
+    async fn submit_cells_at(&mut self, cells: Vec<Submit>, _position: u32) -> bool {
+        self.submit_cells(cells).await
+    }
     async fn submit_headers(&mut self, headers: Vec<HeaderViewWithExtension>) -> bool;
+    /// Every block up to `last` has been scanned and what it had submitted,
+    /// the scan tip moves to the block after it
+    fn scanned(&mut self, _last: IndexerTip) {}
 }
 
 #[async_trait]
@@ -1,12 +1,9 @@
 #![allow(dead_code)]
 
 use async_trait::async_trait;
-use ckb_jsonrpc_types::{
-    BlockNumber, BlockView, HeaderView, JsonBytes, TransactionView, TxStatus, Uint32,
-};
+use ckb_jsonrpc_types::{BlockNumber, BlockView, HeaderView, JsonBytes, TransactionView, Uint32};
 use ckb_types::H256;
 use reqwest::{Client, Url};
-use serde::{Deserialize, Serialize};
 
 use std::{
     future::Future,
@@ -18,7 +15,10 @@ use std::{
 };
 
 use crate::{
-    types::{Cell, CellsCapacity, IndexerTip, Order, Pagination, SearchKey, Tx},
+    types::{
+        BlockchainInfo, Cell, CellsCapacity, IndexerTip, Order, Pagination, SearchKey,
+        TransactionWithStatus, Tx,
+    },
     Rpc,
 };
 
@@ -90,20 +90,20 @@ impl RpcClient {
         &self,
         hash: &H256,
     ) -> impl Future<Output = Result<Option<TransactionView>, io::Error>> {
-        #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
-        struct TransactionWithStatusResponse {
-            /// The transaction.
-            pub transaction: Option<TransactionView>,
-            /// The Transaction status.
-            pub tx_status: TxStatus,
-        }
-        let task = jsonrpc!("get_transaction", self, TransactionWithStatusResponse, hash);
+        let task = self.get_transaction_with_status(hash);
         async {
             let res = task.await?;
             Ok(res.transaction)
         }
     }
 
+    pub fn get_transaction_with_status(
+        &self,
+        hash: &H256,
+    ) -> impl Future<Output = Result<TransactionWithStatus, io::Error>> {
+        jsonrpc!("get_transaction", self, TransactionWithStatus, hash)
+    }
+
     pub fn get_header_by_number(
         &self,
         number: BlockNumber,
@@ -129,6 +129,10 @@ impl RpcClient {
         jsonrpc!("get_indexer_tip", self, IndexerTip)
     }
 
+    pub fn get_blockchain_info(&self) -> impl Future<Output = Result<BlockchainInfo, io::Error>> {
+        jsonrpc!("get_blockchain_info", self, BlockchainInfo)
+    }
+
     pub fn get_transactions(
         &self,
         search_key: SearchKey,
@@ -0,0 +1,159 @@
+//! Json schemas of the ckb rpc types the emitter api uses, for
+//! `#[schemars(with = "...")]` on fields of those types
+
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{ArrayValidation, InstanceType, Metadata, Schema, SchemaObject, StringValidation},
+    JsonSchema,
+};
+
+/// Marks a `[lower, upper]` array whose upper bound must not be below its
+/// lower bound
+pub const ASCENDING: &str = "x-ascending";
+
+fn hex_string(name: &str, description: &str, pattern: &str) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        format: Some(name.to_string()),
+        metadata: Some(Box::new(Metadata {
+            description: Some(description.to_string()),
+            ..Default::default()
+        })),
+        string: Some(Box::new(StringValidation {
+            pattern: Some(pattern.to_string()),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
+macro_rules! hex_schema {
+    ($name:ident, $format:literal, $description:literal, $pattern:literal) => {
+        pub struct $name;
+
+        impl JsonSchema for $name {
+            fn schema_name() -> String {
+                stringify!($name).to_string()
+            }
+
+            fn json_schema(_: &mut SchemaGenerator) -> Schema {
+                hex_string($format, $description, $pattern)
+            }
+        }
+    };
+}
+
+hex_schema!(
+    Uint32,
+    "uint32",
+    "0x-prefixed hex u32 without leading zeros",
+    "^0x(0|[1-9a-fA-F][0-9a-fA-F]{0,7})$"
+);
+hex_schema!(
+    Uint64,
+    "uint64",
+    "0x-prefixed hex u64 without leading zeros",
+    "^0x(0|[1-9a-fA-F][0-9a-fA-F]{0,15})$"
+);
+hex_schema!(
+    Uint128,
+    "uint128",
+    "0x-prefixed hex u128 without leading zeros",
+    "^0x(0|[1-9a-fA-F][0-9a-fA-F]{0,31})$"
+);
+hex_schema!(
+    H256,
+    "h256",
+    "0x-prefixed 32 byte hex string",
+    "^0x[0-9a-fA-F]{64}$"
+);
+hex_schema!(
+    JsonBytes,
+    "bytes",
+    "0x-prefixed hex string of whole bytes",
+    "^0x([0-9a-fA-F]{2})*$"
+);
+
+/// `[lower, upper]`, inclusive lower and exclusive upper bound
+pub struct Range;
+
+impl JsonSchema for Range {
+    fn schema_name() -> String {
+        "Range".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let mut schema = SchemaObject {
+            instance_type: Some(InstanceType::Array.into()),
+            array: Some(Box::new(ArrayValidation {
+                items: Some(gen.subschema_for::<Uint64>().into()),
+                min_items: Some(2),
+                max_items: Some(2),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        schema
+            .extensions
+            .insert(ASCENDING.to_string(), serde_json::Value::Bool(true));
+        schema.into()
+    }
+}
+
+#[derive(JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptHashType {
+    Data,
+    Type,
+    Data1,
+}
+
+#[derive(JsonSchema)]
+pub struct Script {
+    pub code_hash: H256,
+    pub hash_type: ScriptHashType,
+    pub args: JsonBytes,
+}
+
+#[derive(JsonSchema)]
+pub struct OutPoint {
+    pub tx_hash: H256,
+    pub index: Uint32,
+}
+
+#[derive(JsonSchema)]
+pub struct CellOutput {
+    pub capacity: Uint64,
+    pub lock: Script,
+    #[serde(rename = "type")]
+    pub type_: Option<Script>,
+}
+
+#[derive(JsonSchema)]
+pub struct CellData {
+    pub content: JsonBytes,
+    pub hash: H256,
+}
+
+#[derive(JsonSchema)]
+pub struct CellInfo {
+    pub output: CellOutput,
+    pub data: Option<CellData>,
+}
+
+#[derive(JsonSchema)]
+pub struct HeaderView {
+    pub version: Uint32,
+    pub compact_target: Uint32,
+    pub timestamp: Uint64,
+    pub number: Uint64,
+    pub epoch: Uint64,
+    pub parent_hash: H256,
+    pub transactions_root: H256,
+    pub proposals_hash: H256,
+    pub extra_hash: H256,
+    pub dao: H256,
+    pub nonce: Uint128,
+    pub hash: H256,
+}
@@ -2,11 +2,16 @@ use ckb_jsonrpc_types::{
     BlockNumber, Capacity, CellOutput, JsonBytes, OutPoint, Script, Uint32, Uint64,
 };
 use ckb_types::H256;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+use crate::schema;
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct IndexerTip {
+    #[schemars(with = "schema::H256")]
     pub block_hash: H256,
+    #[schemars(with = "schema::Uint64")]
     pub block_number: BlockNumber,
 }
 
@@ -23,7 +28,7 @@ pub struct Pagination<T> {
     pub last_cursor: JsonBytes,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum CellType {
     Input,
@@ -63,7 +68,7 @@ impl Tx {
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum IndexerScriptSearchMode {
     /// Mode `prefix` search script with prefix
@@ -72,6 +77,7 @@ pub enum IndexerScriptSearchMode {
     Exact,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for IndexerScriptSearchMode {
     fn default() -> Self {
         Self::Prefix
@@ -97,7 +103,7 @@ pub struct SearchKeyFilter {
     pub block_range: Option<[BlockNumber; 2]>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ScriptType {
     Lock,
@@ -120,36 +126,83 @@ pub struct Cell {
     pub tx_index: Uint32,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq, JsonSchema)]
 pub struct RpcSearchKey {
+    #[schemars(with = "schema::Script")]
     pub script: Script,
     pub script_type: ScriptType,
     pub script_search_mode: Option<IndexerScriptSearchMode>,
     pub filter: Option<RpcSearchKeyFilter>,
+    /// More scripts scanned together with `script` as one registration, with
+    /// the same search mode and filter
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub group: Vec<GroupScript>,
+}
+
+/// A script of a group registration besides the first one
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq, JsonSchema)]
+pub struct GroupScript {
+    #[schemars(with = "schema::Script")]
+    pub script: Script,
+    pub script_type: ScriptType,
 }
 
 impl RpcSearchKey {
-    pub fn into_key(self, block_range: Option<[Uint64; 2]>) -> SearchKey {
-        SearchKey {
-            script: self.script,
-            script_type: self.script_type,
-            filter: if self.filter.is_some() {
-                self.filter.map(|f| f.into_filter(block_range))
-            } else {
-                Some(RpcSearchKeyFilter::default().into_filter(block_range))
-            },
-            script_search_mode: self.script_search_mode,
-            with_data: None,
-            group_by_transaction: Some(true),
-        }
+    /// One indexer search key for each script of the registration
+    pub fn search_keys(&self, block_range: Option<[Uint64; 2]>) -> Vec<SearchKey> {
+        std::iter::once((&self.script, &self.script_type))
+            .chain(self.group.iter().map(|g| (&g.script, &g.script_type)))
+            .map(|(script, script_type)| SearchKey {
+                script: script.clone(),
+                script_type: script_type.clone(),
+                filter: Some(
+                    self.filter
+                        .clone()
+                        .unwrap_or_default()
+                        .into_filter(block_range),
+                ),
+                script_search_mode: self.script_search_mode.clone(),
+                with_data: None,
+                group_by_transaction: Some(true),
+            })
+            .collect()
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default, Hash, PartialEq, Eq)]
+/// A script given as script JSON or, for lock scripts, as a ckb address
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(untagged)]
+pub enum ScriptOrAddress {
+    Script(#[schemars(with = "schema::Script")] Script),
+    Address(String),
+}
+
+/// `RpcSearchKey` as accepted from callers, see `address::resolve_search_key`
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct RpcSearchKeyParam {
+    pub script: ScriptOrAddress,
+    pub script_type: ScriptType,
+    pub script_search_mode: Option<IndexerScriptSearchMode>,
+    pub filter: Option<RpcSearchKeyFilter>,
+    #[serde(default)]
+    pub group: Vec<GroupScriptParam>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct GroupScriptParam {
+    pub script: ScriptOrAddress,
+    pub script_type: ScriptType,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Hash, PartialEq, Eq, JsonSchema)]
 pub struct RpcSearchKeyFilter {
+    #[schemars(with = "Option<schema::Script>")]
     pub script: Option<Script>,
+    #[schemars(with = "Option<schema::Range>")]
     pub script_len_range: Option<[Uint64; 2]>,
+    #[schemars(with = "Option<schema::Range>")]
     pub output_data_len_range: Option<[Uint64; 2]>,
+    #[schemars(with = "Option<schema::Range>")]
     pub output_capacity_range: Option<[Uint64; 2]>,
 }
 
@@ -165,10 +218,27 @@ impl RpcSearchKeyFilter {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default, Hash, PartialEq, Eq)]
+/// The part of ckb rpc `get_blockchain_info` the emitter needs
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlockchainInfo {
+    pub chain: String,
+}
+
+/// ckb rpc `get_transaction` result
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+pub struct TransactionWithStatus {
+    /// The transaction.
+    pub transaction: Option<ckb_jsonrpc_types::TransactionView>,
+    /// The Transaction status.
+    pub tx_status: ckb_jsonrpc_types::TxStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Hash, PartialEq, Eq, JsonSchema)]
 pub struct HeaderViewWithExtension {
+    #[schemars(with = "schema::HeaderView")]
     pub inner: ckb_jsonrpc_types::HeaderView,
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::JsonBytes>")]
     pub extension: Option<ckb_jsonrpc_types::JsonBytes>,
 }
 
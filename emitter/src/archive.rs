@@ -0,0 +1,410 @@
+use anyhow::{anyhow, Context, Result};
+use ckb_jsonrpc_types::{BlockNumber, Uint64};
+use emitter_core::{
+    schema,
+    types::{HeaderViewWithExtension, RpcSearchKey},
+    Submit,
+};
+use ethers::types::TxHash;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, read_dir, remove_file, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::emit_data::{
+    eth_tx::{send_eth_tx, CKB_LIGHT_CLIENT_ADDRESS, IMAGE_CELL_ADDRESS},
+    tx_data::{convert_blocks, convert_headers},
+};
+
+const ARCHIVE_DIR: &str = "archive";
+/// Each archive file holds the data of this many blocks
+const BUCKET_SIZE: u64 = 10_000;
+
+/// Cell changes emitted for one registration
+#[derive(Serialize, Deserialize)]
+pub struct CellBatch {
+    pub search_key: RpcSearchKey,
+    pub submits: Vec<Submit>,
+    /// The axon transaction that carried the batch, none if sending failed
+    /// or the batch was archived by an older emitter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx_hash: Option<TxHash>,
+}
+
+#[derive(Serialize)]
+struct CellBatchRef<'a> {
+    search_key: &'a RpcSearchKey,
+    submits: Vec<&'a Submit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx_hash: Option<TxHash>,
+}
+
+/// One block of cell changes as it was emitted for a registration
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct EmittedBlock {
+    pub submit: Submit,
+    /// The axon transaction that carried the block, null if sending failed
+    #[schemars(with = "Option<schema::H256>")]
+    pub axon_tx_hash: Option<TxHash>,
+}
+
+/// Page of emitted blocks ordered by block number
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct HistoryPage {
+    pub objects: Vec<EmittedBlock>,
+    #[schemars(with = "Option<schema::Uint64>")]
+    pub last_cursor: Option<BlockNumber>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+pub struct ReplayReport {
+    #[schemars(with = "schema::Uint64")]
+    pub header_batches: Uint64,
+    #[schemars(with = "schema::Uint64")]
+    pub cell_batches: Uint64,
+}
+
+/// Append-only record of every batch sent to axon, stored as json lines in
+/// `<store_path>/archive/{cells,headers}/<bucket>.jsonl`, where a bucket is
+/// `block_number / 10000`. A batch that spans several buckets is split, so a
+/// block range can be read back from the buckets it covers only.
+#[derive(Clone)]
+pub struct Archive {
+    dir: PathBuf,
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl Archive {
+    pub fn new<P: AsRef<Path>>(store_path: P) -> Self {
+        Archive {
+            dir: store_path.as_ref().join(ARCHIVE_DIR),
+            write_lock: Default::default(),
+        }
+    }
+
+    pub fn save_cells(
+        &self,
+        search_key: &RpcSearchKey,
+        submits: &[Submit],
+        tx_hash: Option<TxHash>,
+    ) {
+        let mut buckets: BTreeMap<u64, Vec<&Submit>> = BTreeMap::new();
+        for submit in submits {
+            buckets
+                .entry(submit.header.inner.number.value() / BUCKET_SIZE)
+                .or_default()
+                .push(submit);
+        }
+        for (bucket, submits) in buckets {
+            let batch = CellBatchRef {
+                search_key,
+                submits,
+                tx_hash,
+            };
+            if let Err(e) = self.append("cells", bucket, &batch) {
+                log::error!("Failed to archive cells, error: {:#}", e);
+            }
+        }
+    }
+
+    pub fn save_headers(&self, headers: &[HeaderViewWithExtension]) {
+        let mut buckets: BTreeMap<u64, Vec<&HeaderViewWithExtension>> = BTreeMap::new();
+        for header in headers {
+            buckets
+                .entry(header.inner.inner.number.value() / BUCKET_SIZE)
+                .or_default()
+                .push(header);
+        }
+        for (bucket, headers) in buckets {
+            if let Err(e) = self.append("headers", bucket, &headers) {
+                log::error!("Failed to archive headers, error: {:#}", e);
+            }
+        }
+    }
+
+    /// Cell batches with at least one block in `[from, to]`, only the blocks
+    /// in range are kept
+    pub fn cells(&self, from: u64, to: u64) -> Result<Vec<CellBatch>> {
+        let mut batches = Vec::new();
+        for line in self.lines("cells", from, to)? {
+            let mut batch: CellBatch = serde_json::from_str(&line)?;
+            batch.submits.retain(|s| {
+                let number = s.header.inner.number.value();
+                number >= from && number <= to
+            });
+            if !batch.submits.is_empty() {
+                batches.push(batch);
+            }
+        }
+        Ok(batches)
+    }
+
+    /// Blocks emitted for `search_key` in `[from, to]` after block `after`.
+    /// A page ends on a block boundary, so it can hold more than `limit`
+    /// objects when a block was sent in several batches.
+    pub fn history(
+        &self,
+        search_key: &RpcSearchKey,
+        from: u64,
+        to: u64,
+        limit: usize,
+        after: Option<u64>,
+    ) -> Result<HistoryPage> {
+        let from = match after {
+            Some(after) => from.max(after.saturating_add(1)),
+            None => from,
+        };
+        if from > to {
+            return Ok(HistoryPage {
+                objects: Vec::new(),
+                last_cursor: None,
+            });
+        }
+
+        let mut blocks = Vec::new();
+        for batch in self.cells(from, to)? {
+            if &batch.search_key != search_key {
+                continue;
+            }
+            let tx_hash = batch.tx_hash;
+            blocks.extend(batch.submits.into_iter().map(|submit| EmittedBlock {
+                submit,
+                axon_tx_hash: tx_hash,
+            }));
+        }
+        // stable, blocks sent more than once stay in the order they were sent
+        blocks.sort_by_key(|b| b.submit.header.inner.number.value());
+
+        let mut objects: Vec<EmittedBlock> = Vec::new();
+        for block in blocks {
+            let number = block.submit.header.inner.number;
+            if objects.len() >= limit
+                && objects.last().map(|b| b.submit.header.inner.number) != Some(number)
+            {
+                break;
+            }
+            objects.push(block);
+        }
+        let last_cursor = if objects.len() >= limit {
+            objects.last().map(|b| b.submit.header.inner.number)
+        } else {
+            None
+        };
+        Ok(HistoryPage {
+            objects,
+            last_cursor,
+        })
+    }
+
+    /// Header batches with at least one header in `[from, to]`
+    pub fn headers(&self, from: u64, to: u64) -> Result<Vec<Vec<HeaderViewWithExtension>>> {
+        let mut batches = Vec::new();
+        for line in self.lines("headers", from, to)? {
+            let mut batch: Vec<HeaderViewWithExtension> = serde_json::from_str(&line)?;
+            batch.retain(|h| {
+                let number = h.inner.inner.number.value();
+                number >= from && number <= to
+            });
+            if !batch.is_empty() {
+                batches.push(batch);
+            }
+        }
+        Ok(batches)
+    }
+
+    /// Whether the cell buckets of blocks `[from, to)` are all on disk, none
+    /// are if nothing was archived or they were pruned
+    pub fn covers_cells(&self, from: u64, to: u64) -> Result<bool> {
+        self.covers("cells", from, to)
+    }
+
+    /// Whether the header buckets of blocks `[from, to)` are all on disk
+    pub fn covers_headers(&self, from: u64, to: u64) -> Result<bool> {
+        self.covers("headers", from, to)
+    }
+
+    /// Drop every bucket that only holds blocks lower than `below`
+    pub fn prune(&self, below: u64) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        for kind in ["cells", "headers"] {
+            for (bucket, path) in self.buckets(kind)? {
+                if (bucket + 1) * BUCKET_SIZE <= below {
+                    remove_file(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the cells archived for `search_key` from block `from` on, they
+    /// are archived again when the registration is scanned after a rewind
+    pub fn truncate_cells(&self, search_key: &RpcSearchKey, from: u64) -> Result<()> {
+        self.rewrite("cells", from, |line| {
+            let mut batch: CellBatch = serde_json::from_str(line)?;
+            if &batch.search_key != search_key {
+                return Ok(Some(line.to_string()));
+            }
+            batch
+                .submits
+                .retain(|s| s.header.inner.number.value() < from);
+            if batch.submits.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(serde_json::to_string(&batch)?))
+            }
+        })
+    }
+
+    /// Forget every cell batch archived for `search_key`, once its
+    /// registration is deleted
+    pub fn remove_cells(&self, search_key: &RpcSearchKey) -> Result<()> {
+        self.rewrite("cells", 0, |line| {
+            let batch: CellBatch = serde_json::from_str(line)?;
+            if &batch.search_key == search_key {
+                Ok(None)
+            } else {
+                Ok(Some(line.to_string()))
+            }
+        })
+    }
+
+    /// Forget the headers archived from block `from` on
+    pub fn truncate_headers(&self, from: u64) -> Result<()> {
+        self.rewrite("headers", from, |line| {
+            let mut batch: Vec<HeaderViewWithExtension> = serde_json::from_str(line)?;
+            batch.retain(|h| h.inner.inner.number.value() < from);
+            if batch.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(serde_json::to_string(&batch)?))
+            }
+        })
+    }
+
+    /// Pass every line of the buckets holding blocks from `from` on through
+    /// `f`, dropping the lines it returns none for
+    fn rewrite<F>(&self, kind: &str, from: u64, f: F) -> Result<()>
+    where
+        F: Fn(&str) -> Result<Option<String>>,
+    {
+        let _guard = self.write_lock.lock().unwrap();
+        for (bucket, path) in self.buckets(kind)? {
+            if bucket < from / BUCKET_SIZE {
+                continue;
+            }
+            let mut content = String::new();
+            File::open(&path)
+                .and_then(|mut f| f.read_to_string(&mut content))
+                .with_context(|| format!("failed to read {:?}", path))?;
+            let mut out = String::with_capacity(content.len());
+            for line in content.lines().filter(|l| !l.is_empty()) {
+                if let Some(line) = f(line)? {
+                    out.push_str(&line);
+                    out.push('\n');
+                }
+            }
+            if out.is_empty() {
+                remove_file(&path)?;
+            } else {
+                let tmp = path.with_extension("jsonl.tmp");
+                std::fs::write(&tmp, out)?;
+                std::fs::rename(tmp, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append<T: Serialize>(&self, kind: &str, bucket: u64, batch: &T) -> Result<()> {
+        let mut line = serde_json::to_vec(batch)?;
+        line.push(b'\n');
+
+        let _guard = self.write_lock.lock().unwrap();
+        let dir = self.dir.join(kind);
+        create_dir_all(&dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(format!("{}.jsonl", bucket)))?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    fn buckets(&self, kind: &str) -> Result<Vec<(u64, PathBuf)>> {
+        let dir = self.dir.join(kind);
+        let mut buckets = match read_dir(&dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let name = entry.file_name().into_string().ok()?;
+                    let bucket = name.strip_suffix(".jsonl")?.parse().ok()?;
+                    Some((bucket, entry.path()))
+                })
+                .collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+        buckets.sort_unstable_by_key(|(bucket, _)| *bucket);
+        Ok(buckets)
+    }
+
+    fn covers(&self, kind: &str, from: u64, to: u64) -> Result<bool> {
+        if from >= to {
+            return Ok(true);
+        }
+        let buckets = self.buckets(kind)?;
+        Ok((from / BUCKET_SIZE..=(to - 1) / BUCKET_SIZE)
+            .all(|bucket| buckets.iter().any(|(b, _)| *b == bucket)))
+    }
+
+    fn lines(&self, kind: &str, from: u64, to: u64) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        for (bucket, path) in self.buckets(kind)? {
+            if bucket < from / BUCKET_SIZE || bucket > to / BUCKET_SIZE {
+                continue;
+            }
+            let mut content = String::new();
+            File::open(&path)
+                .and_then(|mut f| f.read_to_string(&mut content))
+                .with_context(|| format!("failed to read {:?}", path))?;
+            // every batch is written with a trailing newline in one go, anything
+            // after the last newline is still being written by a running emitter
+            if let Some(end) = content.rfind('\n') {
+                lines.extend(content[..end].split('\n').map(ToString::to_string));
+            }
+        }
+        Ok(lines)
+    }
+}
+
+/// Push the archived headers, then the archived cells of `[from, to]` to
+/// axon, the ckb node is not involved
+pub async fn replay(archive: &Archive, from: u64, to: u64, axon_url: &str) -> Result<ReplayReport> {
+    if from > to {
+        return Err(anyhow!(
+            "invalid block range, from {} is above to {}",
+            from,
+            to
+        ));
+    }
+    let (mut header_batches, mut cell_batches) = (0u64, 0u64);
+    for headers in archive.headers(from, to)? {
+        send_eth_tx(axon_url, convert_headers(headers), CKB_LIGHT_CLIENT_ADDRESS)
+            .await
+            .context("failed to replay headers")?;
+        header_batches += 1;
+    }
+    for batch in archive.cells(from, to)? {
+        send_eth_tx(axon_url, convert_blocks(&batch.submits), IMAGE_CELL_ADDRESS)
+            .await
+            .context("failed to replay cells")?;
+        cell_batches += 1;
+    }
+    Ok(ReplayReport {
+        header_batches: header_batches.into(),
+        cell_batches: cell_batches.into(),
+    })
+}
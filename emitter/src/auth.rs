@@ -0,0 +1,716 @@
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use hyper::{body::HttpBody, header, Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::OpenOptions,
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+};
+use tokio::{io::copy_bidirectional, net::TcpStream};
+use tower::{Layer, Service};
+
+use crate::status::now_ms;
+
+const AUDIT_FILE: &str = "audit.jsonl";
+/// Same as the default request limit of the rpc server
+const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+/// How far the timestamp of a signed request may be off, in seconds
+const SIGNATURE_WINDOW: u64 = 300;
+
+/// No or unknown credentials
+pub const UNAUTHORIZED: i32 = -32010;
+/// Known credentials without the scope the call needs
+pub const FORBIDDEN: i32 = -32011;
+
+/// Methods that do not change anything, callable with the read scope. Every
+/// other method needs the write scope. `emitter_ack` only moves the queue of
+/// the caller's own subscription.
+const READ_ONLY_METHODS: &[&str] = &[
+    "info",
+    "export_state",
+    "get_live_cells",
+    "get_live_cells_capacity",
+    "get_live_cell",
+    "status",
+    "preview",
+    "history",
+    "header_sync_status",
+    "rpc.discover",
+    "emitter_subscription",
+    "emitter_unsubscribe",
+    "emitter_ack",
+    "subscription_status",
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Read,
+    /// Includes read
+    Write,
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::Write => write!(f, "write"),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+enum Secret {
+    /// Sent as is in `Authorization: Bearer <token>`
+    Token(String),
+    /// Signs the request body, see `Auth::check_signature`
+    HmacKey(String),
+}
+
+#[derive(Deserialize, Clone)]
+struct Credential {
+    name: String,
+    scope: Scope,
+    #[serde(flatten)]
+    secret: Secret,
+}
+
+#[derive(Deserialize)]
+struct AuthFile {
+    credentials: Vec<Credential>,
+}
+
+/// A rejected call, appended to `<store_path>/audit.jsonl`
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    at: u64,
+    /// The credential the caller presented, if it is a known one
+    credential: Option<&'a str>,
+    methods: &'a [String],
+    scope: Scope,
+    reason: &'a str,
+    /// The rpc server does not pass the peer address on, these are the
+    /// `X-Forwarded-For` and `User-Agent` headers
+    forwarded_for: Option<&'a str>,
+    user_agent: Option<&'a str>,
+}
+
+/// Credentials of the rpc server, loaded from a json file:
+///
+/// ```json
+/// { "credentials": [
+///     { "name": "ops", "scope": "write", "token": "..." },
+///     { "name": "dashboard", "scope": "read", "hmac_key": "..." }
+/// ] }
+/// ```
+pub struct Auth {
+    credentials: Vec<Credential>,
+    audit: Option<PathBuf>,
+    /// Signatures seen within the signature window, a signed request is
+    /// accepted once
+    seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl Auth {
+    /// `store_path` is where rejected calls are recorded, they are only
+    /// logged without it
+    pub fn load<P: AsRef<Path>>(path: P, store_path: Option<&str>) -> Result<Auth> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read auth file {:?}", path))?;
+        let file: AuthFile = serde_json::from_str(&content)
+            .with_context(|| format!("failed to parse auth file {:?}", path))?;
+
+        let mut names = HashSet::new();
+        let mut secrets = HashSet::new();
+        for c in &file.credentials {
+            let secret = match &c.secret {
+                Secret::Token(s) | Secret::HmacKey(s) => s,
+            };
+            if c.name.is_empty() || c.name.contains(':') {
+                return Err(anyhow!("invalid credential name {:?}", c.name));
+            }
+            if secret.is_empty() {
+                return Err(anyhow!("credential {} has an empty secret", c.name));
+            }
+            if !names.insert(&c.name) {
+                return Err(anyhow!("duplicate credential name {}", c.name));
+            }
+            if !secrets.insert(secret) {
+                return Err(anyhow!("credential {} reuses a secret", c.name));
+            }
+        }
+        if file.credentials.is_empty() {
+            return Err(anyhow!("auth file {:?} has no credentials", path));
+        }
+
+        Ok(Auth {
+            credentials: file.credentials,
+            audit: store_path.map(|p| Path::new(p).join(AUDIT_FILE)),
+            seen: Default::default(),
+        })
+    }
+
+    /// The credential a request with `headers` and `body` is made with
+    fn authenticate(
+        &self,
+        headers: &header::HeaderMap,
+        body: &[u8],
+    ) -> Result<&Credential, String> {
+        let value = headers
+            .get(header::AUTHORIZATION)
+            .ok_or("missing authorization header")?
+            .to_str()
+            .map_err(|_| "invalid authorization header")?;
+        match value.split_once(' ') {
+            Some(("Bearer", token)) => self
+                .credentials
+                .iter()
+                .find(|c| matches!(&c.secret, Secret::Token(t) if constant_time_eq(t.as_bytes(), token.as_bytes())))
+                .ok_or_else(|| "unknown token".to_string()),
+            Some(("HMAC", signature)) => self.check_signature(signature, body),
+            _ => Err("unsupported authorization scheme".to_string()),
+        }
+    }
+
+    /// `<name>:<timestamp>:<signature>`, where the signature is the hex
+    /// encoded HMAC-SHA256 of `<timestamp>.<body>` and the timestamp is in
+    /// unix seconds
+    fn check_signature(&self, value: &str, body: &[u8]) -> Result<&Credential, String> {
+        let mut parts = value.splitn(3, ':');
+        let (name, timestamp, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(n), Some(t), Some(s)) => (n, t, s),
+            _ => return Err("malformed signature".to_string()),
+        };
+        let credential = self
+            .credentials
+            .iter()
+            .find(|c| c.name == name && matches!(c.secret, Secret::HmacKey(_)))
+            .ok_or("unknown hmac key")?;
+        let key = match &credential.secret {
+            Secret::HmacKey(key) => key,
+            Secret::Token(_) => unreachable!(),
+        };
+
+        let ts: u64 = timestamp.parse().map_err(|_| "malformed timestamp")?;
+        let now = now_ms() / 1000;
+        if now.abs_diff(ts) > SIGNATURE_WINDOW {
+            return Err(format!("timestamp is more than {}s off", SIGNATURE_WINDOW));
+        }
+        let signature = hex::decode(signature).map_err(|_| "malformed signature")?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("any key size");
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        mac.verify_slice(&signature)
+            .map_err(|_| "signature mismatch".to_string())?;
+
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, t| now.abs_diff(*t) <= SIGNATURE_WINDOW);
+        if seen.insert(signature, ts).is_some() {
+            return Err("signature already used".to_string());
+        }
+        Ok(credential)
+    }
+
+    /// Check a request calling `methods`, returns the scope of its
+    /// credential, or the error code and message to reject it with. Opening
+    /// a websocket needs the read scope, see `AuthLayer` for what it can call.
+    fn check(
+        &self,
+        headers: &header::HeaderMap,
+        body: &[u8],
+        methods: &[String],
+    ) -> Result<Scope, (i32, String)> {
+        let scope = if is_websocket_upgrade(headers) {
+            Scope::Read
+        } else {
+            required_scope(methods)
+        };
+        let (credential, rejection) = match self.authenticate(headers, body) {
+            Ok(c) if c.scope >= scope => return Ok(c.scope),
+            Ok(c) => (
+                Some(c.name.as_str()),
+                (
+                    FORBIDDEN,
+                    format!("credential {} does not have the {} scope", c.name, scope),
+                ),
+            ),
+            Err(reason) => (None, (UNAUTHORIZED, reason)),
+        };
+
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        let record = AuditRecord {
+            at: now_ms(),
+            credential,
+            methods,
+            scope,
+            reason: &rejection.1,
+            forwarded_for: header("x-forwarded-for"),
+            user_agent: header(header::USER_AGENT.as_str()),
+        };
+        self.audit(&record);
+        Err(rejection)
+    }
+
+    fn audit(&self, record: &AuditRecord) {
+        let mut line = serde_json::to_string(record).unwrap();
+        log::warn!(target: "audit", "Rejected rpc call {}", line);
+        if let Some(ref path) = self.audit {
+            line.push('\n');
+            if let Err(e) = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut f| f.write_all(line.as_bytes()))
+            {
+                log::error!("Failed to write audit log, error: {:#}", e);
+            }
+        }
+    }
+}
+
+fn is_websocket_upgrade(headers: &header::HeaderMap) -> bool {
+    headers
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// Whether `method` is callable with the read scope
+pub fn is_read_only(method: &str) -> bool {
+    READ_ONLY_METHODS.contains(&method)
+}
+
+/// The scope needed to call every method of a request. Requests that are not
+/// json-rpc need the read scope.
+fn required_scope(methods: &[String]) -> Scope {
+    if methods.iter().all(|m| is_read_only(m)) {
+        Scope::Read
+    } else {
+        Scope::Write
+    }
+}
+
+/// Methods called by a single or batch json-rpc request
+fn request_methods(body: &[u8]) -> Vec<String> {
+    #[derive(Deserialize)]
+    struct Call {
+        method: String,
+    }
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Calls {
+        One(Call),
+        Batch(Vec<Call>),
+    }
+    match serde_json::from_slice(body) {
+        Ok(Calls::One(c)) => vec![c.method],
+        Ok(Calls::Batch(calls)) => calls.into_iter().map(|c| c.method).collect(),
+        // not json-rpc, the rpc server rejects it
+        Err(_) => Vec::new(),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn rejection(status: StatusCode, code: i32, message: String) -> Response<Body> {
+    let body = serde_json::json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": null,
+    });
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Rpc server middleware checking every request against `Auth`, everything
+/// is let through without it.
+///
+/// The methods called over a websocket are not seen once it is open. A
+/// websocket opened with the read scope is handed to the read only server
+/// if there is one, a server serving only read methods needs none.
+#[derive(Clone)]
+pub struct AuthLayer {
+    auth: Option<Arc<Auth>>,
+    read_only: Option<SocketAddr>,
+}
+
+impl AuthLayer {
+    pub fn new(auth: Option<Arc<Auth>>) -> Self {
+        AuthLayer {
+            auth,
+            read_only: None,
+        }
+    }
+
+    /// Hand websockets opened with the read scope to the websocket server at
+    /// `addr`, which only serves methods callable with it
+    pub fn with_read_only(mut self, addr: SocketAddr) -> Self {
+        self.read_only = Some(addr);
+        self
+    }
+}
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = AuthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthService {
+            auth: self.auth.clone(),
+            read_only: self.read_only,
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthService<S> {
+    auth: Option<Arc<Auth>>,
+    read_only: Option<SocketAddr>,
+    inner: S,
+}
+
+/// Pass a websocket upgrade on to the server at `addr` and relay the
+/// connection both ways once it is upgraded
+async fn forward_websocket(
+    mut request: Request<Body>,
+    addr: SocketAddr,
+) -> Result<Response<Body>, BoxError> {
+    let stream = TcpStream::connect(addr).await?;
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::debug!("read only websocket connection error: {}", e);
+        }
+    });
+
+    let mut forwarded = Request::builder()
+        .method(request.method())
+        .uri(request.uri().clone())
+        .body(Body::empty())?;
+    *forwarded.headers_mut() = request.headers().clone();
+    let mut response = sender.send_request(forwarded).await?;
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(response);
+    }
+
+    let client = hyper::upgrade::on(&mut request);
+    let server = hyper::upgrade::on(&mut response);
+    tokio::spawn(async move {
+        let relayed = match (client.await, server.await) {
+            (Ok(mut client), Ok(mut server)) => copy_bidirectional(&mut client, &mut server)
+                .await
+                .map(|_| ()),
+            (Err(e), _) | (_, Err(e)) => Err(std::io::Error::other(e)),
+        };
+        if let Err(e) = relayed {
+            log::debug!("read only websocket relay error: {}", e);
+        }
+    });
+    Ok(response)
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+impl<S> Service<Request<Body>> for AuthService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = BoxError> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let auth = match self.auth {
+            Some(ref auth) => auth.clone(),
+            None => return Box::pin(self.inner.call(request)),
+        };
+        let read_only = self.read_only;
+        // the clone may not be ready, keep the one that is
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let (parts, mut body) = request.into_parts();
+            let mut bytes = Vec::new();
+            while let Some(chunk) = body.data().await {
+                bytes.extend_from_slice(&chunk?);
+                if bytes.len() > MAX_BODY_SIZE {
+                    return Ok(rejection(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        -32600,
+                        "request is too large".to_string(),
+                    ));
+                }
+            }
+
+            let methods = request_methods(&bytes);
+            let scope = match auth.check(&parts.headers, &bytes, &methods) {
+                Ok(scope) => scope,
+                Err((code, message)) => {
+                    let status = if code == FORBIDDEN {
+                        StatusCode::FORBIDDEN
+                    } else {
+                        StatusCode::UNAUTHORIZED
+                    };
+                    return Ok(rejection(status, code, message));
+                }
+            };
+            let request = Request::from_parts(parts, Body::from(bytes));
+            match read_only {
+                Some(addr) if scope < Scope::Write && is_websocket_upgrade(request.headers()) => {
+                    forward_websocket(request, addr).await
+                }
+                _ => inner.call(request).await,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "dashboard key";
+    const BODY: &[u8] = br#"{"jsonrpc":"2.0","method":"status","params":[],"id":1}"#;
+
+    fn auth() -> Auth {
+        Auth {
+            credentials: vec![
+                Credential {
+                    name: "ops".to_string(),
+                    scope: Scope::Write,
+                    secret: Secret::Token("ops token".to_string()),
+                },
+                Credential {
+                    name: "dashboard".to_string(),
+                    scope: Scope::Read,
+                    secret: Secret::HmacKey(KEY.to_string()),
+                },
+            ],
+            audit: None,
+            seen: Default::default(),
+        }
+    }
+
+    fn now() -> u64 {
+        now_ms() / 1000
+    }
+
+    fn sign(key: &str, timestamp: u64, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn signed(name: &str, key: &str, timestamp: u64, body: &[u8]) -> String {
+        format!("{}:{}:{}", name, timestamp, sign(key, timestamp, body))
+    }
+
+    fn name(result: Result<&Credential, String>) -> String {
+        result.unwrap().name.clone()
+    }
+
+    fn reason(result: Result<&Credential, String>) -> String {
+        result.map(|c| c.name.clone()).unwrap_err()
+    }
+
+    #[test]
+    fn valid_signature() {
+        let auth = auth();
+        let value = signed("dashboard", KEY, now(), BODY);
+        assert_eq!(name(auth.check_signature(&value, BODY)), "dashboard");
+    }
+
+    #[test]
+    fn signature_covers_body_and_timestamp() {
+        let auth = auth();
+        let ts = now();
+        let value = signed("dashboard", KEY, ts, BODY);
+        assert_eq!(
+            reason(auth.check_signature(&value, b"{}")),
+            "signature mismatch"
+        );
+
+        let value = format!("dashboard:{}:{}", ts - 1, sign(KEY, ts, BODY));
+        assert_eq!(
+            reason(auth.check_signature(&value, BODY)),
+            "signature mismatch"
+        );
+
+        let value = signed("dashboard", "another key", ts, BODY);
+        assert_eq!(
+            reason(auth.check_signature(&value, BODY)),
+            "signature mismatch"
+        );
+    }
+
+    #[test]
+    fn only_hmac_credentials_sign() {
+        let auth = auth();
+        let value = signed("ops", "ops token", now(), BODY);
+        assert_eq!(
+            reason(auth.check_signature(&value, BODY)),
+            "unknown hmac key"
+        );
+        let value = signed("nobody", KEY, now(), BODY);
+        assert_eq!(
+            reason(auth.check_signature(&value, BODY)),
+            "unknown hmac key"
+        );
+    }
+
+    #[test]
+    fn malformed_signature() {
+        let auth = auth();
+        for (value, expected) in [
+            ("dashboard", "malformed signature"),
+            ("dashboard:1", "malformed signature"),
+            ("dashboard:soon:00", "malformed timestamp"),
+        ] {
+            assert_eq!(reason(auth.check_signature(value, BODY)), expected);
+        }
+        let value = format!("dashboard:{}:not hex", now());
+        assert_eq!(
+            reason(auth.check_signature(&value, BODY)),
+            "malformed signature"
+        );
+    }
+
+    #[test]
+    fn timestamp_window() {
+        let auth = auth();
+        for ts in [now() - SIGNATURE_WINDOW - 10, now() + SIGNATURE_WINDOW + 10] {
+            let value = signed("dashboard", KEY, ts, BODY);
+            assert_eq!(
+                reason(auth.check_signature(&value, BODY)),
+                "timestamp is more than 300s off"
+            );
+        }
+        let value = signed("dashboard", KEY, now() - SIGNATURE_WINDOW + 10, BODY);
+        assert!(auth.check_signature(&value, BODY).is_ok());
+    }
+
+    #[test]
+    fn replay_is_rejected() {
+        let auth = auth();
+        let ts = now();
+        let value = signed("dashboard", KEY, ts, BODY);
+        assert!(auth.check_signature(&value, BODY).is_ok());
+        assert_eq!(
+            reason(auth.check_signature(&value, BODY)),
+            "signature already used"
+        );
+
+        // the same body signed again at another time is a new request
+        let value = signed("dashboard", KEY, ts - 1, BODY);
+        assert!(auth.check_signature(&value, BODY).is_ok());
+        assert_eq!(auth.seen.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn seen_signatures_expire_with_the_window() {
+        let auth = auth();
+        auth.seen
+            .lock()
+            .unwrap()
+            .insert(vec![0; 32], now() - SIGNATURE_WINDOW - 10);
+        let value = signed("dashboard", KEY, now(), BODY);
+        assert!(auth.check_signature(&value, BODY).is_ok());
+        assert_eq!(auth.seen.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn authorization_schemes() {
+        let auth = auth();
+        let headers = |value: &str| {
+            let mut headers = header::HeaderMap::new();
+            headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+            headers
+        };
+
+        assert_eq!(
+            name(auth.authenticate(&headers("Bearer ops token"), BODY)),
+            "ops"
+        );
+        assert_eq!(
+            reason(auth.authenticate(&headers("Bearer ops"), BODY)),
+            "unknown token"
+        );
+        let value = format!("HMAC {}", signed("dashboard", KEY, now(), BODY));
+        assert_eq!(name(auth.authenticate(&headers(&value), BODY)), "dashboard");
+        assert_eq!(
+            reason(auth.authenticate(&headers("Basic b3Bz"), BODY)),
+            "unsupported authorization scheme"
+        );
+        assert_eq!(
+            reason(auth.authenticate(&header::HeaderMap::new(), BODY)),
+            "missing authorization header"
+        );
+    }
+
+    #[test]
+    fn scopes() {
+        let auth = auth();
+        let body = br#"[{"method":"status"},{"method":"register"}]"#;
+        let methods = request_methods(body);
+        assert_eq!(methods, vec!["status", "register"]);
+
+        let mut headers = header::HeaderMap::new();
+        let value = format!("HMAC {}", signed("dashboard", KEY, now(), body));
+        headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+        assert_eq!(
+            auth.check(&headers, body, &methods).unwrap_err(),
+            (
+                FORBIDDEN,
+                "credential dashboard does not have the write scope".to_string()
+            )
+        );
+
+        let value = format!("HMAC {}", signed("dashboard", KEY, now(), BODY));
+        headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+        assert!(auth.check(&headers, BODY, &request_methods(BODY)).is_ok());
+
+        headers.insert(header::AUTHORIZATION, "Bearer ops token".parse().unwrap());
+        assert!(auth.check(&headers, body, &methods).is_ok());
+    }
+
+    #[test]
+    fn websockets_open_with_the_read_scope() {
+        let auth = auth();
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::UPGRADE, "websocket".parse().unwrap());
+        let value = format!("HMAC {}", signed("dashboard", KEY, now(), b""));
+        headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+        assert_eq!(auth.check(&headers, b"", &[]), Ok(Scope::Read));
+
+        headers.insert(header::AUTHORIZATION, "Bearer ops token".parse().unwrap());
+        assert_eq!(auth.check(&headers, b"", &[]), Ok(Scope::Write));
+
+        headers.remove(header::AUTHORIZATION);
+        assert_eq!(auth.check(&headers, b"", &[]).unwrap_err().0, UNAUTHORIZED);
+
+        assert!(is_read_only("emitter_subscription") && is_read_only("emitter_ack"));
+        assert!(!is_read_only("register"));
+    }
+}
@@ -0,0 +1,260 @@
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fs::{copy, create_dir_all, read_dir, remove_file},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::global_state::{move_file, read_state};
+
+const BACKUP_DIR: &str = "backups";
+const BACKUP_PREFIX: &str = "scan_state.";
+
+/// A timestamped copy of `scan_state` stored under `<store_path>/backups`,
+/// named `scan_state.<unix secs>.<millis>`. Backups of older emitters are
+/// named `scan_state.<unix secs>`.
+pub struct Backup {
+    pub name: String,
+    pub path: PathBuf,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl Backup {
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.timestamp)
+    }
+}
+
+fn backup_name(timestamp: u64) -> String {
+    format!(
+        "{}{}.{:03}",
+        BACKUP_PREFIX,
+        timestamp / 1000,
+        timestamp % 1000
+    )
+}
+
+fn parse_timestamp(name: &str) -> Option<u64> {
+    let rest = name.strip_prefix(BACKUP_PREFIX)?;
+    let (secs, millis) = match rest.split_once('.') {
+        Some((secs, millis)) if millis.len() == 3 => (secs, millis.parse::<u64>().ok()?),
+        Some(_) => return None,
+        None => (rest, 0),
+    };
+    secs.parse::<u64>()
+        .ok()?
+        .checked_mul(1000)?
+        .checked_add(millis)
+}
+
+/// List all backups of the store, newest first
+pub fn list_backups<P: AsRef<Path>>(store_path: P) -> Vec<Backup> {
+    let dir = store_path.as_ref().join(BACKUP_DIR);
+    let mut backups = match read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let timestamp = parse_timestamp(&name)?;
+                Some(Backup {
+                    name,
+                    path: entry.path(),
+                    timestamp,
+                })
+            })
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    backups.sort_unstable_by_key(|b| std::cmp::Reverse(b.timestamp));
+    backups
+}
+
+/// Copy a known good state file into the backup directory unless the newest
+/// backup is less than `interval` old, and drop the oldest backups so that at
+/// most `keep` remain. Returns whether a backup was taken.
+pub fn save_backup(
+    store_path: &Path,
+    state_file: &Path,
+    keep: usize,
+    interval: Duration,
+) -> Result<bool, std::io::Error> {
+    if keep == 0 {
+        return Ok(false);
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let latest = list_backups(store_path).first().map(|b| b.timestamp);
+    if latest
+        .and_then(|latest| now.checked_sub(latest))
+        .is_some_and(|age| age < interval.as_millis() as u64)
+    {
+        return Ok(false);
+    }
+    // never reuse the name of the newest backup, even within a millisecond
+    // or after the clock went back
+    let timestamp = latest.map_or(now, |latest| now.max(latest + 1));
+    let dir = store_path.join(BACKUP_DIR);
+    create_dir_all(&dir)?;
+    copy(state_file, dir.join(backup_name(timestamp)))?;
+
+    for old in list_backups(store_path).into_iter().skip(keep) {
+        remove_file(old.path)?;
+    }
+    Ok(true)
+}
+
+/// Replace `scan_state` with the named backup.
+///
+/// The backup is validated before anything is touched, and the current state
+/// file is moved aside rather than deleted.
+pub fn restore_backup<P: AsRef<Path>>(store_path: P, name: &str) -> Result<()> {
+    let store_path = store_path.as_ref();
+    let backup = list_backups(store_path)
+        .into_iter()
+        .find(|b| b.name == name)
+        .ok_or_else(|| anyhow!("backup {} not found in {:?}", name, store_path))?;
+    read_state(&backup.path).with_context(|| format!("backup {} is not usable", name))?;
+
+    let db_path = store_path.join("scan_state");
+    if db_path.exists() {
+        let aside = set_aside(store_path, "replaced")?;
+        log::info!("previous state moved to {:?}", aside);
+    }
+    let tmp = store_path.join("tmp");
+    create_dir_all(&tmp)?;
+    copy(&backup.path, tmp.join("scan_state"))?;
+    move_file(tmp.join("scan_state"), db_path)?;
+    Ok(())
+}
+
+/// Move `scan_state` out of the way as `scan_state.<reason>.<timestamp>`, so
+/// it can still be inspected by hand
+pub fn set_aside<P: AsRef<Path>>(store_path: P, reason: &str) -> Result<PathBuf> {
+    let dst = aside_path(store_path.as_ref(), reason);
+    move_file(store_path.as_ref().join("scan_state"), dst.clone())?;
+    Ok(dst)
+}
+
+/// Same as `set_aside`, but leaves `scan_state` in place
+pub fn keep_copy<P: AsRef<Path>>(store_path: P, reason: &str) -> Result<PathBuf> {
+    let dst = aside_path(store_path.as_ref(), reason);
+    copy(store_path.as_ref().join("scan_state"), &dst)?;
+    Ok(dst)
+}
+
+fn aside_path(store_path: &Path, reason: &str) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    store_path.join(format!("scan_state.{}.{}", reason, now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global_state::{write_state, State};
+    use emitter_core::TipState;
+    use serde_json::json;
+    use std::fs::{read_dir, write};
+
+    /// Write a state db whose header tip is at `header`
+    fn write_header_state(store_path: &Path, header: u64) {
+        let state: State = serde_json::from_value(json!({
+            "cell_states": [],
+            "registrations": [],
+            "header_state": {
+                "block_hash": format!("0x{:064x}", header),
+                "block_number": format!("{:#x}", header),
+            },
+            "header_paused": false,
+        }))
+        .unwrap();
+        write_state(store_path, &state).unwrap();
+    }
+
+    fn header_of(path: &Path) -> u64 {
+        let (state, _) = read_state(path).unwrap();
+        let tip = state.header_state.tip();
+        tip.load().block_number.value()
+    }
+
+    #[test]
+    fn keeps_the_newest_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("scan_state");
+        write_header_state(dir.path(), 1);
+        // left by an older emitter
+        create_dir_all(dir.path().join(BACKUP_DIR)).unwrap();
+        copy(
+            &state_file,
+            dir.path().join(BACKUP_DIR).join("scan_state.1697000000"),
+        )
+        .unwrap();
+        assert_eq!(list_backups(dir.path())[0].timestamp, 1_697_000_000_000);
+
+        // several dumps within the same second each get their own backup
+        for _ in 0..5 {
+            assert!(save_backup(dir.path(), &state_file, 3, Duration::ZERO).unwrap());
+        }
+        let backups = list_backups(dir.path());
+        assert_eq!(backups.len(), 3);
+        assert!(backups.windows(2).all(|b| b[0].timestamp > b[1].timestamp));
+        assert!(backups.iter().all(|b| b.name != "scan_state.1697000000"));
+        assert_eq!(read_dir(dir.path().join(BACKUP_DIR)).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn backs_up_once_per_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("scan_state");
+        write_header_state(dir.path(), 1);
+        let hour = Duration::from_secs(3600);
+
+        assert!(save_backup(dir.path(), &state_file, 10, hour).unwrap());
+        assert!(!save_backup(dir.path(), &state_file, 10, hour).unwrap());
+        assert_eq!(list_backups(dir.path()).len(), 1);
+        // no backups at all
+        assert!(!save_backup(dir.path(), &state_file, 0, Duration::ZERO).unwrap());
+        assert_eq!(list_backups(dir.path()).len(), 1);
+    }
+
+    #[test]
+    fn restores_a_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("scan_state");
+        write_header_state(dir.path(), 10);
+        save_backup(dir.path(), &state_file, 10, Duration::ZERO).unwrap();
+        let name = list_backups(dir.path())[0].name.clone();
+        write_header_state(dir.path(), 20);
+
+        restore_backup(dir.path(), &name).unwrap();
+        assert_eq!(header_of(&state_file), 10);
+        // the replaced state is kept aside
+        let aside = read_dir(dir.path())
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .find(|e| {
+                e.file_name()
+                    .to_string_lossy()
+                    .starts_with("scan_state.replaced.")
+            })
+            .unwrap();
+        assert_eq!(header_of(&aside.path()), 20);
+    }
+
+    #[test]
+    fn refuses_an_unusable_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        write_header_state(dir.path(), 10);
+        create_dir_all(dir.path().join(BACKUP_DIR)).unwrap();
+        write(dir.path().join(BACKUP_DIR).join("scan_state.1.000"), "{").unwrap();
+
+        assert!(restore_backup(dir.path(), "scan_state.1.000").is_err());
+        assert!(restore_backup(dir.path(), "scan_state.2.000").is_err());
+        assert_eq!(header_of(&dir.path().join("scan_state")), 10);
+    }
+}
@@ -0,0 +1,527 @@
+use emitter_core::rpc_client::RpcClient;
+use jsonrpsee::server::ServerBuilder;
+
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    archive::{self, Archive},
+    auth::{self, Auth, AuthLayer},
+    backup,
+    emit_data::eth_tx::wallet,
+    global_state::GlobalState,
+    migrate, openrpc,
+    rpc_server::{EmitterRpc, EmitterServer},
+    snapshot,
+    store_lock::StoreLock,
+    ws_queue::{QueueConfig, Queues, SlowConsumer},
+    ws_subscription,
+};
+
+/// Parse the command line and run the emitter or one of its subcommands
+pub async fn run() {
+    env_logger::init();
+
+    let cmd = clap::Command::new("emitter")
+    .version(clap::crate_version!()).arg(
+        clap::Arg::new("ckb_uri")
+            .short('c')
+            .default_value("http://127.0.0.1:8114")
+            .help(
+                "CKB rpc service uri, supports http and tcp, for example: `http://127.0.0.1:8114`",
+            )
+            .action(clap::ArgAction::Set),
+    ).arg(
+        clap::Arg::new("listen_uri")
+        .short('l')
+        .default_value("127.0.0.1:8120")
+        .help("Emitter rpc http service listen address, default 127.0.0.1:8120")
+        .action(clap::ArgAction::Set),
+    ).arg(
+        clap::Arg::new("store_path")
+        .short('s')
+        .help("Sets the indexer store path to use")
+        .required_unless_present("ws")
+        .action(clap::ArgAction::Set),
+    ).arg(
+        clap::Arg::new("axon_uri")
+        .long("i")
+        .default_value("http://127.0.0.1:8080")
+        .help("The Axon listening address, default http://127.0.0.1:8080")
+        .action(clap::ArgAction::Set)
+    )
+    .arg(
+        clap::Arg::new("private_path")
+        .short('p')
+        .help("Sets the private key path to use")
+        .help("The Axon trasaction signer key, use to construct transaction, default is axon demo wallet")
+        .action(clap::ArgAction::Set),
+    )
+    .arg(
+        clap::Arg::new("ws")
+        .long("ws")
+        .help("Serve the websocket subscriptions, on the same address as the http rpc if a store path is set, alone otherwise")
+        .action(clap::ArgAction::SetTrue)
+    )
+    .arg(
+        clap::Arg::new("ws_queue")
+        .long("ws-queue")
+        .default_value("64")
+        .value_parser(clap::value_parser!(u64).range(1..))
+        .help("Notifications a `cell_filter` or `header_sync` subscriber may leave unacknowledged with `emitter_ack`, default 64")
+        .action(clap::ArgAction::Set)
+    )
+    .arg(
+        clap::Arg::new("ws_slow_consumer")
+        .long("ws-slow-consumer")
+        .default_value("block")
+        .value_parser(["block", "drop", "coalesce"])
+        .help("What a subscription with a full queue does: `block` pauses its scan, `drop` closes it, `coalesce` merges the next batches into one")
+        .action(clap::ArgAction::Set)
+    )
+    .arg(
+        clap::Arg::new("recover")
+        .long("recover")
+        .help("If the state db is corrupt, move it aside and load the latest good backup instead of refusing to start")
+        .action(clap::ArgAction::SetTrue)
+    )
+    .arg(
+        clap::Arg::new("backups")
+        .long("backups")
+        .default_value("10")
+        .value_parser(clap::value_parser!(usize))
+        .help("Number of timestamped state snapshots kept in `<store_path>/backups`, default 10")
+        .action(clap::ArgAction::Set)
+    )
+    .arg(
+        clap::Arg::new("backup_interval")
+        .long("backup-interval")
+        .default_value("3600")
+        .value_parser(clap::value_parser!(u64))
+        .help("Minimum number of seconds between two state snapshots, default 3600")
+        .action(clap::ArgAction::Set)
+    )
+    .arg(
+        clap::Arg::new("ignore_lock")
+        .long("ignore-lock")
+        .help("Start even if the store path is locked by another emitter, only use it when that emitter is known to be gone")
+        .action(clap::ArgAction::SetTrue)
+    )
+    .arg(
+        clap::Arg::new("no_header_sync")
+        .long("no-header-sync")
+        .help("Do not sync headers to axon, for when another relayer keeps its light client up to date")
+        .action(clap::ArgAction::SetTrue)
+    )
+    .arg(
+        clap::Arg::new("archive_retention")
+        .long("archive-retention")
+        .value_parser(clap::value_parser!(u64))
+        .help("Number of blocks below the highest tip kept in `<store_path>/archive`, keep everything if not set")
+        .action(clap::ArgAction::Set)
+    )
+    .arg(
+        clap::Arg::new("auth_file")
+        .long("auth-file")
+        .help("Json file of the bearer tokens and hmac keys allowed to call the rpc, with read or write scope, anyone can call everything if not set")
+        .action(clap::ArgAction::Set)
+    )
+    .subcommand_negates_reqs(true)
+    .args_conflicts_with_subcommands(true)
+    .subcommand(
+        clap::Command::new("restore")
+        .about("List state backups, or replace the state db with one of them")
+        .arg(
+            clap::Arg::new("store_path")
+            .short('s')
+            .help("Sets the indexer store path to use")
+            .required(true)
+            .action(clap::ArgAction::Set),
+        )
+        .arg(
+            clap::Arg::new("backup")
+            .help("Backup name to restore, list all backups if not set")
+            .action(clap::ArgAction::Set),
+        )
+    )
+    .subcommand(
+        clap::Command::new("migrate")
+        .about("Upgrade the state db to the layout used by this emitter, the original file is kept")
+        .arg(
+            clap::Arg::new("store_path")
+            .short('s')
+            .help("Sets the indexer store path to use")
+            .required(true)
+            .action(clap::ArgAction::Set),
+        )
+    )
+    .subcommand(
+        clap::Command::new("export")
+        .about("Write a checksummed snapshot of the state db, can be used while the emitter is running")
+        .arg(
+            clap::Arg::new("store_path")
+            .short('s')
+            .help("Sets the indexer store path to use")
+            .required(true)
+            .action(clap::ArgAction::Set),
+        )
+        .arg(
+            clap::Arg::new("output")
+            .short('o')
+            .help("Snapshot file to write")
+            .required(true)
+            .action(clap::ArgAction::Set),
+        )
+    )
+    .subcommand(
+        clap::Command::new("import")
+        .about("Import a snapshot into the state db of a stopped emitter, tips are checked against the ckb node first")
+        .arg(
+            clap::Arg::new("store_path")
+            .short('s')
+            .help("Sets the indexer store path to use")
+            .required(true)
+            .action(clap::ArgAction::Set),
+        )
+        .arg(
+            clap::Arg::new("input")
+            .short('i')
+            .help("Snapshot file to read")
+            .required(true)
+            .action(clap::ArgAction::Set),
+        )
+        .arg(
+            clap::Arg::new("ckb_uri")
+            .short('c')
+            .default_value("http://127.0.0.1:8114")
+            .help("CKB rpc service uri used to check the snapshot")
+            .action(clap::ArgAction::Set),
+        )
+        .arg(
+            clap::Arg::new("mode")
+            .long("mode")
+            .default_value("merge")
+            .value_parser(["merge", "replace"])
+            .help("`merge` only adds registrations that do not exist yet, `replace` drops the current state")
+            .action(clap::ArgAction::Set),
+        )
+    )
+    .subcommand(
+        clap::Command::new("replay")
+        .about("Push the archived headers and cells of a block range to axon again, the ckb node is not needed")
+        .arg(
+            clap::Arg::new("store_path")
+            .short('s')
+            .help("Sets the indexer store path to use")
+            .required(true)
+            .action(clap::ArgAction::Set),
+        )
+        .arg(
+            clap::Arg::new("from")
+            .long("from")
+            .value_parser(clap::value_parser!(u64))
+            .help("First block to replay")
+            .required(true)
+            .action(clap::ArgAction::Set),
+        )
+        .arg(
+            clap::Arg::new("to")
+            .long("to")
+            .value_parser(clap::value_parser!(u64))
+            .help("Last block to replay")
+            .required(true)
+            .action(clap::ArgAction::Set),
+        )
+        .arg(
+            clap::Arg::new("axon_uri")
+            .long("i")
+            .default_value("http://127.0.0.1:8080")
+            .help("The Axon listening address, default http://127.0.0.1:8080")
+            .action(clap::ArgAction::Set)
+        )
+        .arg(
+            clap::Arg::new("private_path")
+            .short('p')
+            .help("The Axon trasaction signer key, use to construct transaction, default is axon demo wallet")
+            .action(clap::ArgAction::Set),
+        )
+    );
+
+    let matches = cmd.get_matches();
+
+    match matches.subcommand() {
+        Some(("restore", sub)) => {
+            restore(
+                sub.get_one::<String>("store_path").unwrap(),
+                sub.get_one::<String>("backup"),
+            );
+            return;
+        }
+        Some(("migrate", sub)) => {
+            migrate_store(sub.get_one::<String>("store_path").unwrap());
+            return;
+        }
+        Some(("export", sub)) => {
+            let store_path = sub.get_one::<String>("store_path").unwrap();
+            let output = sub.get_one::<String>("output").unwrap();
+            if let Err(e) = snapshot::export_store(store_path, output) {
+                eprintln!("export failed: {:#}", e);
+                std::process::exit(1);
+            }
+            println!("state exported to {}", output);
+            return;
+        }
+        Some(("import", sub)) => {
+            import_store(sub).await;
+            return;
+        }
+        Some(("replay", sub)) => {
+            replay(sub).await;
+            return;
+        }
+        _ => (),
+    }
+
+    let client = RpcClient::new(matches.get_one::<String>("ckb_uri").unwrap());
+
+    let listen_url = matches.get_one::<String>("listen_uri").unwrap();
+    if let Some(priv_path) = matches.get_one::<String>("private_path") {
+        load_privkey_from_file(priv_path);
+    }
+    let auth = match matches.get_one::<String>("auth_file") {
+        Some(path) => match Auth::load(
+            path,
+            matches.get_one::<String>("store_path").map(|s| s.as_str()),
+        ) {
+            Ok(auth) => Some(Arc::new(auth)),
+            Err(e) => {
+                log::error!("{:#}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let queues = Queues::new(QueueConfig {
+        capacity: *matches.get_one::<u64>("ws_queue").unwrap() as usize,
+        policy: match matches
+            .get_one::<String>("ws_slow_consumer")
+            .unwrap()
+            .as_str()
+        {
+            "drop" => SlowConsumer::Drop,
+            "coalesce" => SlowConsumer::Coalesce,
+            _ => SlowConsumer::Block,
+        },
+    });
+    if let Some(store_path) = matches.get_one::<String>("store_path") {
+        let _lock = match StoreLock::acquire(store_path, matches.get_flag("ignore_lock")) {
+            Ok(lock) => lock,
+            Err(e) => {
+                log::error!("{:#}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let genesis = client.get_header_by_number(0.into()).await.unwrap();
+
+        let mut global = match GlobalState::new(
+            store_path.into(),
+            genesis,
+            matches.get_one::<String>("axon_uri").unwrap().into(),
+            matches.get_flag("recover"),
+            *matches.get_one::<usize>("backups").unwrap(),
+            Duration::from_secs(*matches.get_one::<u64>("backup_interval").unwrap()),
+            matches.get_one::<u64>("archive_retention").copied(),
+        ) {
+            Ok(global) => global,
+            Err(e) => {
+                log::error!("{:#}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let state = global.state.clone();
+        let ctx = global.ctx.clone();
+
+        let header_handle =
+            global.spawn_header_sync(client.clone(), !matches.get_flag("no_header_sync"));
+        let save = global.save_trigger();
+
+        let cell_handles = global.spawn_cells(client.clone());
+
+        let _global_handle = tokio::spawn(async move { global.run().await });
+
+        let ws = matches.get_flag("ws");
+        let events = ctx.events.clone();
+        let emitter_rpc = EmitterRpc {
+            state,
+            cell_handles,
+            header_handle,
+            save,
+            client: client.clone(),
+            ctx,
+        };
+        let mut rpc = openrpc::validated_module(emitter_rpc.clone().into_rpc());
+
+        let mut layer = AuthLayer::new(auth.clone());
+        let mut _read_only_handle = None;
+        if ws {
+            let subscriptions = || {
+                ws_subscription::ws_subscription_module(
+                    client.clone(),
+                    Some(events.clone()),
+                    queues.clone(),
+                )
+            };
+            rpc.merge(subscriptions().await).unwrap();
+            // every rpc method is callable over a websocket served next to
+            // the http rpc, those opened with the read scope get a server
+            // of their own with only the methods it allows
+            if auth.is_some() {
+                let mut read_only =
+                    openrpc::validated_subset(emitter_rpc.into_rpc(), auth::is_read_only);
+                read_only.merge(subscriptions().await).unwrap();
+                let server = ServerBuilder::new()
+                    .ws_only()
+                    .build("127.0.0.1:0")
+                    .await
+                    .unwrap();
+                layer = layer.with_read_only(server.local_addr().unwrap());
+                _read_only_handle = Some(server.start(read_only).unwrap());
+            }
+        }
+        let mut builder =
+            ServerBuilder::new().set_middleware(tower::ServiceBuilder::new().layer(layer));
+        if !ws {
+            builder = builder.http_only();
+        }
+        let handle = builder.build(listen_url).await.unwrap().start(rpc).unwrap();
+
+        if ws {
+            log::info!("listen on {}, with websocket", listen_url);
+        } else {
+            log::info!("listen on {}", listen_url);
+        }
+        handle.stopped().await;
+    } else {
+        let rpc = ws_subscription::ws_subscription_module(client, None, queues).await;
+        let handle = ServerBuilder::new()
+            .set_middleware(tower::ServiceBuilder::new().layer(AuthLayer::new(auth)))
+            .ws_only()
+            .build(listen_url)
+            .await
+            .unwrap()
+            .start(rpc)
+            .unwrap();
+        log::info!("websocket listen on {}", listen_url);
+        handle.stopped().await;
+    }
+}
+
+fn restore(store_path: &str, name: Option<&String>) {
+    match name {
+        Some(name) => match StoreLock::acquire(store_path, false)
+            .and_then(|_lock| backup::restore_backup(store_path, name))
+        {
+            Ok(()) => println!("restored state db from backup {}", name),
+            Err(e) => {
+                eprintln!("restore failed: {:#}", e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            for b in backup::list_backups(store_path) {
+                println!(
+                    "{}\t{}",
+                    b.name,
+                    humantime::format_rfc3339_seconds(b.time())
+                );
+            }
+        }
+    }
+}
+
+fn migrate_store(store_path: &str) {
+    match StoreLock::acquire(store_path, false).and_then(|_lock| migrate::migrate_store(store_path))
+    {
+        Ok(Some((version, original))) => println!(
+            "migrated state db from version {} to {}, the original is kept at {:?}",
+            version,
+            migrate::STATE_VERSION,
+            original
+        ),
+        Ok(None) => println!("state db is already at version {}", migrate::STATE_VERSION),
+        Err(e) => {
+            eprintln!("migrate failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn import_store(matches: &clap::ArgMatches) {
+    let store_path = matches.get_one::<String>("store_path").unwrap();
+    let input = matches.get_one::<String>("input").unwrap();
+    let client = RpcClient::new(matches.get_one::<String>("ckb_uri").unwrap());
+    let mode = match matches.get_one::<String>("mode").unwrap().as_str() {
+        "replace" => snapshot::ImportMode::Replace,
+        _ => snapshot::ImportMode::Merge,
+    };
+
+    let res = match StoreLock::acquire(store_path, false) {
+        Ok(_lock) => snapshot::import_store(store_path, input, mode, &client).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(report) => println!(
+            "imported {}, added {}, skipped {}, removed {}",
+            input,
+            report.added.len(),
+            report.skipped.len(),
+            report.removed.len()
+        ),
+        Err(e) => {
+            eprintln!("import failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn replay(matches: &clap::ArgMatches) {
+    let store_path = matches.get_one::<String>("store_path").unwrap();
+    let from = *matches.get_one::<u64>("from").unwrap();
+    let to = *matches.get_one::<u64>("to").unwrap();
+    if let Some(priv_path) = matches.get_one::<String>("private_path") {
+        load_privkey_from_file(priv_path);
+    }
+
+    match archive::replay(
+        &Archive::new(store_path),
+        from,
+        to,
+        matches.get_one::<String>("axon_uri").unwrap(),
+    )
+    .await
+    {
+        Ok(report) => println!(
+            "replayed blocks {} to {}, {} header batches, {} cell batches",
+            from,
+            to,
+            report.header_batches.value(),
+            report.cell_batches.value()
+        ),
+        Err(e) => {
+            eprintln!("replay failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn load_privkey_from_file(privkey_path: &str) {
+    use std::io::Read;
+    let privkey = std::fs::File::open(privkey_path)
+        .and_then(|mut f| {
+            let mut buffer = Vec::new();
+            f.read_to_end(&mut buffer).map(|_| buffer)
+        })
+        .expect("failed to parse private key file");
+    wallet(Some(&privkey));
+}
@@ -0,0 +1,280 @@
+//! Typed client of the emitter rpc, the methods are those of `EmitterClient`
+//! on an http or websocket client. The `cell_filter` and `header_sync`
+//! subscriptions reconnect on their own and go on right after the last item
+//! received, and acknowledge each batch once the next one is asked for.
+
+use futures::{stream, Stream};
+use jsonrpsee::{
+    core::{
+        client::{ClientT, Subscription, SubscriptionClientT, SubscriptionKind},
+        params::ArrayParams,
+        Error,
+    },
+    http_client::{HttpClient, HttpClientBuilder},
+    rpc_params,
+    types::error::CallError,
+    ws_client::{HeaderMap, WsClient, WsClientBuilder},
+};
+use serde::de::DeserializeOwned;
+use std::{pin::Pin, time::Duration};
+
+pub use crate::{
+    archive::{EmittedBlock, HistoryPage, ReplayReport},
+    global_state::{FilterVersion, HeaderState, JobRecord, Registration, State},
+    live_cells::{LiveCell, LiveCellLookup, LiveCellsCapacity, LiveCellsPage},
+    rewind::RewindReport,
+    rpc_server::{
+        BatchItem, BatchReport, EmitterClient, InfoFilter, Preview, RegisterItem, RegisterMeta,
+        UpdateReport,
+    },
+    snapshot::{ImportMode, ImportReport, Snapshot},
+    status::{HeaderSyncState, HeaderSyncStatus, LastBatch, StatusReport, TaskStatus},
+    submit::ScanTip,
+    ws_queue::{QueueStatus, SlowConsumer, SubscriptionReport},
+    ws_subscription::{Notification, ResumeToken, Start, CKB_UNAVAILABLE_CODE, UNSUPPORTED_CODE},
+};
+pub use ckb_jsonrpc_types::BlockNumber;
+pub use emitter_core::{
+    types::{
+        HeaderViewWithExtension, IndexerScriptSearchMode, IndexerTip, RpcSearchKey,
+        RpcSearchKeyFilter, RpcSearchKeyParam,
+    },
+    Submit, TipState,
+};
+
+/// Client of the emitter rpc over http, `headers` carry the credentials of
+/// `--auth-file` if the emitter runs with one
+pub fn http_client(url: &str, headers: HeaderMap) -> Result<HttpClient, Error> {
+    HttpClientBuilder::default().set_headers(headers).build(url)
+}
+
+/// Client of the emitter rpc over a websocket, for an emitter serving both
+/// on the same address
+pub async fn ws_client(url: &str, headers: HeaderMap) -> Result<WsClient, Error> {
+    WsClientBuilder::default()
+        .set_headers(headers)
+        .build(url)
+        .await
+}
+
+/// How a subscription reconnects after its connection is lost
+#[derive(Clone, Debug)]
+pub struct Reconnect {
+    /// Wait before the first attempt, doubled after each failed one
+    pub delay: Duration,
+    pub max_delay: Duration,
+    /// Failed attempts in a row after which the subscription ends with the
+    /// last error, none to try forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Reconnect {
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SubscribeOptions {
+    /// Headers of the websocket upgrade, such as the `Authorization` header
+    pub headers: HeaderMap,
+    pub reconnect: Reconnect,
+}
+
+/// Notifications of a subscription. An error the emitter answers the
+/// subscription with ends it, a lost connection does not, and neither does a
+/// reorg notice although nothing follows it.
+pub type Batches<T> = Pin<Box<dyn Stream<Item = Result<Notification<T>, Error>> + Send>>;
+
+/// Cells of `search_key` created or consumed from block `start` on, or after
+/// a resume token
+pub fn subscribe_cells(
+    url: impl Into<String>,
+    search_key: RpcSearchKeyParam,
+    start: impl Into<Start>,
+    options: SubscribeOptions,
+) -> Batches<Submit> {
+    resumable(url.into(), options, start.into(), move |start| {
+        rpc_params!["cell_filter", &search_key, start, true]
+    })
+}
+
+/// Headers from block `start` on, or after a resume token
+pub fn subscribe_headers(
+    url: impl Into<String>,
+    start: impl Into<Start>,
+    options: SubscribeOptions,
+) -> Batches<HeaderViewWithExtension> {
+    resumable(url.into(), options, start.into(), |start| {
+        rpc_params!["header_sync", start, true]
+    })
+}
+
+fn resumable<T, P>(url: String, options: SubscribeOptions, start: Start, params: P) -> Batches<T>
+where
+    T: DeserializeOwned + Send + Sync + 'static,
+    P: Fn(&Start) -> ArrayParams + Send + Sync + 'static,
+{
+    let resume = Resume {
+        url,
+        options,
+        params,
+        start,
+        connection: None,
+        received: None,
+        failures: 0,
+        ended: false,
+    };
+    Box::pin(stream::unfold(resume, |mut resume| async move {
+        let notification = resume.next().await?;
+        Some((notification, resume))
+    }))
+}
+
+struct Resume<T, P> {
+    url: String,
+    options: SubscribeOptions,
+    /// Parameters of the subscription from where it starts
+    params: P,
+    /// The first block, then the token of the last batch
+    start: Start,
+    /// The subscription needs its client kept alive
+    connection: Option<(WsClient, Subscription<Notification<T>>)>,
+    /// Token of the batch handed out last, acknowledged when the next one is
+    /// asked for
+    received: Option<ResumeToken>,
+    /// Failed attempts in a row
+    failures: u32,
+    ended: bool,
+}
+
+impl<T, P> Resume<T, P>
+where
+    T: DeserializeOwned + Send + Sync + 'static,
+    P: Fn(&Start) -> ArrayParams + Send + Sync + 'static,
+{
+    async fn next(&mut self) -> Option<Result<Notification<T>, Error>> {
+        self.ack().await;
+        while !self.ended {
+            let subscription = match self.connection {
+                Some((_, ref mut subscription)) => subscription,
+                None => {
+                    match self.connect().await {
+                        Ok(connection) => self.connection = Some(connection),
+                        Err(e) if is_final(&e) => return self.end(e),
+                        Err(e) => {
+                            if let Err(e) = self.wait(e).await {
+                                return self.end(e);
+                            }
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            match subscription.next().await {
+                Some(Ok(notification)) => {
+                    self.failures = 0;
+                    match notification {
+                        Notification::Batch { ref resume, .. } => {
+                            self.start = Start::Resume(resume.clone());
+                            self.received = Some(resume.clone());
+                        }
+                        // resuming again would only be told the same
+                        Notification::Reorg { .. } => {
+                            self.ended = true;
+                            self.connection = None;
+                        }
+                    }
+                    return Some(Ok(notification));
+                }
+                // a notification that is not a batch, the connection is fine
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    self.connection = None;
+                    self.received = None;
+                    let closed = Error::Custom("subscription closed".to_string());
+                    if let Err(e) = self.wait(closed).await {
+                        return self.end(e);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Let the emitter know the last batch is taken care of, a lost
+    /// acknowledgement is not worth an error since the next one covers it
+    async fn ack(&mut self) {
+        let (token, (client, subscription)) = match (self.received.take(), &self.connection) {
+            (Some(token), Some(connection)) => (token, connection),
+            _ => return,
+        };
+        if let SubscriptionKind::Subscription(id) = subscription.kind() {
+            let acked: Result<usize, _> =
+                client.request("emitter_ack", rpc_params![id, token]).await;
+            if let Err(e) = acked {
+                log::debug!("Failed to acknowledge emitter notification, error: {}", e);
+            }
+        }
+    }
+
+    async fn connect(&self) -> Result<(WsClient, Subscription<Notification<T>>), Error> {
+        let client = ws_client(&self.url, self.options.headers.clone()).await?;
+        let subscription = client
+            .subscribe(
+                "emitter_subscription",
+                (self.params)(&self.start),
+                "emitter_unsubscribe",
+            )
+            .await?;
+        Ok((client, subscription))
+    }
+
+    /// Wait before the next attempt, `error` is handed back once there are
+    /// no attempts left
+    async fn wait(&mut self, error: Error) -> Result<(), Error> {
+        self.failures += 1;
+        let reconnect = &self.options.reconnect;
+        if reconnect
+            .max_attempts
+            .is_some_and(|max| self.failures > max)
+        {
+            return Err(error);
+        }
+        let from = match self.start {
+            Start::Block(number) => number,
+            Start::Resume(ref token) => token.block_number,
+        };
+        log::warn!(
+            "Emitter subscription lost, resuming from block {}, error: {}",
+            from.value(),
+            error
+        );
+        let delay = reconnect
+            .delay
+            .saturating_mul(2u32.saturating_pow(self.failures - 1))
+            .min(reconnect.max_delay);
+        tokio::time::sleep(delay).await;
+        Ok(())
+    }
+
+    fn end(&mut self, error: Error) -> Option<Result<Notification<T>, Error>> {
+        self.ended = true;
+        Some(Err(error))
+    }
+}
+
+/// Rejected by the emitter, trying again does not help unless it could not
+/// reach its ckb node
+fn is_final(e: &Error) -> bool {
+    match e {
+        Error::Call(CallError::Custom(e)) => e.code() != CKB_UNAVAILABLE_CODE,
+        Error::Call(_) => true,
+        _ => false,
+    }
+}
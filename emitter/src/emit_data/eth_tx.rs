@@ -10,7 +10,9 @@ use ethers_signers::coins_bip39::English;
 pub const IMAGE_CELL_ADDRESS: Address = system_contract_address(0x3);
 pub const CKB_LIGHT_CLIENT_ADDRESS: Address = system_contract_address(0x2);
 
-pub async fn send_eth_tx(axon_url: &str, data: Vec<u8>, to: Address) -> Result<()> {
+/// Send `data` to the system contract `to` and wait for the receipt, returns
+/// the hash of the transaction
+pub async fn send_eth_tx(axon_url: &str, data: Vec<u8>, to: Address) -> Result<TxHash> {
     let provider = Provider::<Http>::try_from(axon_url)?;
     let wallet = wallet(None);
 
@@ -30,13 +32,13 @@ pub async fn send_eth_tx(axon_url: &str, data: Vec<u8>, to: Address) -> Result<(
     let tx = Legacy(transaction_request);
     let signature: Signature = wallet.sign_transaction(&tx).await?;
 
-    provider
+    let receipt = provider
         .send_raw_transaction(tx.rlp_signed(&signature))
         .await?
         .await?
         .expect("failed to send eth tx");
 
-    Ok(())
+    Ok(receipt.transaction_hash)
 }
 
 pub fn wallet(private_key: Option<&[u8]>) -> &'static Wallet<SigningKey> {
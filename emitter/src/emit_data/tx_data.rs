@@ -1,4 +1,5 @@
 use ckb_jsonrpc_types::{CellInfo, OutPoint, Script, ScriptHashType};
+use ckb_types::H256;
 use ethers::abi::AbiEncode;
 use ethers::core::types::Bytes;
 
@@ -7,7 +8,7 @@ use emitter_core::types::HeaderViewWithExtension;
 use crate::emit_data::{ckb_light_client_abi, image_cell_abi};
 use crate::Submit;
 
-pub fn convert_blocks(data: Vec<Submit>) -> Vec<u8> {
+pub fn convert_blocks(data: &[Submit]) -> Vec<u8> {
     let mut blocks = Vec::new();
     for block in data {
         blocks.push(image_cell_abi::BlockUpdate {
@@ -47,6 +48,30 @@ pub fn convert_headers(headers: Vec<HeaderViewWithExtension>) -> Vec<u8> {
     .encode()
 }
 
+/// Undo the given blocks, newest first
+pub fn convert_rollback_blocks(data: &[Submit]) -> Vec<u8> {
+    let mut blocks = Vec::new();
+    for block in data.iter().rev() {
+        blocks.push(image_cell_abi::BlockRollBlack {
+            tx_inputs: convert_inputs(&block.inputs),
+            tx_outputs: block
+                .outputs
+                .iter()
+                .map(|o| convert_outpoint(&o.0))
+                .collect(),
+        });
+    }
+
+    image_cell_abi::RollbackCall { blocks }.encode()
+}
+
+pub fn convert_rollback_headers(block_hashes: Vec<H256>) -> Vec<u8> {
+    ckb_light_client_abi::RollbackCall {
+        block_hashes: block_hashes.into_iter().map(|h| h.0).collect(),
+    }
+    .encode()
+}
+
 fn convert_inputs(inputs: &Vec<OutPoint>) -> Vec<image_cell_abi::OutPoint> {
     let mut res = Vec::new();
     for out_point in inputs {
@@ -1,24 +1,258 @@
-use ckb_jsonrpc_types::HeaderView;
+use anyhow::{anyhow, Context};
+use ckb_jsonrpc_types::{BlockNumber, Capacity, HeaderView, Timestamp, Uint32, Uint64};
 use emitter_core::{
     cell_process::CellProcess,
     header_sync::HeaderSyncProcess,
     rpc_client::RpcClient,
-    types::{IndexerTip, RpcSearchKey},
+    schema,
+    types::{IndexerScriptSearchMode, IndexerTip, RpcSearchKey, RpcSearchKeyFilter},
+    TipState,
 };
+use futures::FutureExt;
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
 use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
+    borrow::Cow,
     fs::{copy, create_dir_all, remove_file, rename, File, OpenOptions},
-    io::Write,
+    future::Future,
+    io::{BufReader, ErrorKind, Write},
+    panic::AssertUnwindSafe,
     path::{Path, PathBuf},
-    sync::{atomic::AtomicPtr, Arc},
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
 };
 
-use crate::{RpcSubmit, ScanTip, ScanTipInner};
+use crate::{
+    archive::Archive,
+    backup,
+    live_cells::LiveCells,
+    migrate::{self, NewerVersion, STATE_VERSION},
+    status::{now_ms, Stats, TaskStats},
+    ws_subscription::relay_events,
+    ScanTip, ScanTipInner, SubmitContext,
+};
+
+/// The header sync task and what keeps it from running, besides a pause
+#[derive(Default)]
+pub(crate) struct HeaderTask {
+    /// None while header sync is paused, stopped or disabled
+    pub task: Option<ScanTask>,
+    /// Stopped by `header_sync_stop` until it is started again, unlike a
+    /// pause this is not kept in the state
+    pub stopped: bool,
+    /// Disabled by `--no-header-sync`
+    pub disabled: bool,
+}
+
+pub(crate) type HeaderHandle = Arc<Mutex<HeaderTask>>;
+
+/// How long a scan task may take to finish its current round when asked to stop
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A spawned cell or header scan task
+pub(crate) struct ScanTask {
+    handle: tokio::task::JoinHandle<()>,
+    /// Shared with the task's submitter, see `RpcSubmit::closed`
+    closed: Arc<AtomicBool>,
+}
+
+impl ScanTask {
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Let the task finish without submitting anything more.
+    ///
+    /// The task exits at its next submit or scan round, so its tip matches
+    /// what was sent to axon. It is aborted if that takes too long.
+    pub async fn stop(mut self) {
+        self.closed.store(true, Ordering::Release);
+        if tokio::time::timeout(STOP_TIMEOUT, &mut self.handle)
+            .await
+            .is_err()
+        {
+            log::warn!("Scan task did not stop in {:?}, abort it", STOP_TIMEOUT);
+            self.handle.abort();
+            // wait until it is really gone, it may be running on another worker
+            let _ = self.handle.await;
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct State {
     pub cell_states: Arc<dashmap::DashMap<RpcSearchKey, ScanTip>>,
-    pub header_state: ScanTip,
+    pub registrations: Arc<dashmap::DashMap<RpcSearchKey, Registration>>,
+    pub header_state: HeaderState,
+    pub header_paused: Arc<AtomicBool>,
+}
+
+/// The scan tip of header sync. Moving it backwards installs a fresh
+/// `ScanTip` rather than swapping the tip under readers of the old one.
+#[derive(Clone)]
+pub struct HeaderState(Arc<RwLock<ScanTip>>);
+
+impl HeaderState {
+    pub fn new(tip: ScanTip) -> Self {
+        HeaderState(Arc::new(RwLock::new(tip)))
+    }
+
+    /// The current tip, which stays valid for as long as it is held
+    pub fn tip(&self) -> ScanTip {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Replace the tip unconditionally, even with a lower block. The header
+    /// sync task must be stopped, it keeps updating the tip it was given.
+    pub fn reset(&self, tip: IndexerTip) {
+        *self.0.write().unwrap() = ScanTip::from(tip);
+    }
+}
+
+impl Serialize for HeaderState {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.tip().serialize(serializer)
+    }
+}
+
+/// What is known about a registered search key besides its scan tip
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+pub struct Registration {
+    /// Free-form label, e.g. the product line the key belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Who asked for the registration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    /// Milliseconds since unix epoch, unknown for keys registered before metadata existed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::Uint64>")]
+    pub created_at: Option<Timestamp>,
+    /// The block the registration originally started from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::Uint64>")]
+    pub start_block: Option<BlockNumber>,
+    /// Paused registrations keep their tip but have no scan task
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub paused: bool,
+    /// Last block of a bounded job, the registration follows the chain if
+    /// absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<schema::Uint64>")]
+    pub end_block: Option<BlockNumber>,
+    /// Set once a bounded job has submitted its end block, it is not scanned
+    /// anymore but kept as a record
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed: Option<JobRecord>,
+    /// Every filter and search mode the key had, oldest first, empty until
+    /// the first update
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<FilterVersion>,
+}
+
+/// Final stats of a bounded job
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct JobRecord {
+    /// Milliseconds since unix epoch
+    #[schemars(with = "schema::Uint64")]
+    pub completed_at: Timestamp,
+    /// Blocks scanned, from the start block to the end block
+    #[schemars(with = "schema::Uint64")]
+    pub blocks: Uint64,
+    /// Live cells of the key right after the end block
+    #[schemars(with = "schema::Uint32")]
+    pub live_cells: Uint32,
+    #[schemars(with = "schema::Uint64")]
+    pub capacity: Capacity,
+    /// The last submit error of the final run, if any
+    pub last_error: Option<String>,
+}
+
+/// The filter and search mode a registration had from some block on
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct FilterVersion {
+    pub script_search_mode: Option<IndexerScriptSearchMode>,
+    pub filter: Option<RpcSearchKeyFilter>,
+    /// The first block scanned with this version, unknown for the original
+    /// version of keys registered before metadata existed
+    #[schemars(with = "Option<schema::Uint64>")]
+    pub from_block: Option<BlockNumber>,
+    /// Milliseconds since unix epoch
+    #[schemars(with = "Option<schema::Uint64>")]
+    pub set_at: Option<Timestamp>,
+}
+
+impl Registration {
+    /// Record that `key` is scanned from `from_block` on, keeping the version
+    /// of `previous` as the first entry if there is no history yet
+    pub fn push_version(
+        &mut self,
+        previous: &RpcSearchKey,
+        key: &RpcSearchKey,
+        from_block: BlockNumber,
+    ) {
+        if self.history.is_empty() {
+            self.history.push(FilterVersion {
+                script_search_mode: previous.script_search_mode.clone(),
+                filter: previous.filter.clone(),
+                from_block: self.start_block,
+                set_at: self.created_at,
+            });
+        }
+        self.history.push(FilterVersion {
+            script_search_mode: key.script_search_mode.clone(),
+            filter: key.filter.clone(),
+            from_block: Some(from_block),
+            set_at: Some(now_ms().into()),
+        });
+    }
+}
+
+impl State {
+    /// Paused keys and finished jobs get no scan task
+    pub fn is_idle(&self, key: &RpcSearchKey) -> bool {
+        self.registrations
+            .get(key)
+            .map(|r| r.paused || r.completed.is_some())
+            .unwrap_or(false)
+    }
+
+    pub fn end_block(&self, key: &RpcSearchKey) -> Option<BlockNumber> {
+        self.registrations.get(key).and_then(|r| r.end_block)
+    }
+
+    /// A copy of the state that only contains the registrations accepted by `f`
+    pub fn filter<F>(&self, f: F) -> State
+    where
+        F: Fn(&RpcSearchKey, &Registration) -> bool,
+    {
+        let registrations = self
+            .registrations
+            .iter()
+            .filter(|kv| f(kv.key(), kv.value()))
+            .map(|kv| (kv.key().clone(), kv.value().clone()))
+            .collect::<dashmap::DashMap<_, _>>();
+        let cell_states = self
+            .cell_states
+            .iter()
+            .filter(|kv| registrations.contains_key(kv.key()))
+            .map(|kv| (kv.key().clone(), kv.value().clone()))
+            .collect();
+        State {
+            cell_states: Arc::new(cell_states),
+            registrations: Arc::new(registrations),
+            header_state: self.header_state.clone(),
+            header_paused: self.header_paused.clone(),
+        }
+    }
 }
 
 impl Serialize for State {
@@ -26,7 +260,7 @@ impl Serialize for State {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("State", 2)?;
+        let mut state = serializer.serialize_struct("State", 4)?;
         state.serialize_field(
             "cell_states",
             &self
@@ -35,26 +269,62 @@ impl Serialize for State {
                 .map(|kv| (kv.key().clone(), kv.value().clone()))
                 .collect::<Vec<_>>(),
         )?;
+        state.serialize_field(
+            "registrations",
+            &self
+                .registrations
+                .iter()
+                .map(|kv| (kv.key().clone(), kv.value().clone()))
+                .collect::<Vec<_>>(),
+        )?;
         state.serialize_field("header_state", &self.header_state)?;
+        state.serialize_field("header_paused", &self.header_paused.load(Ordering::Acquire))?;
         state.end()
     }
 }
 
+/// Registrations with the tips of their scans, and the tip of header sync
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[schemars(rename = "State")]
+struct StateVisitor {
+    cell_states: Vec<(RpcSearchKey, ScanTip)>,
+    registrations: Vec<(RpcSearchKey, Registration)>,
+    header_state: ScanTip,
+    #[serde(default)]
+    header_paused: bool,
+}
+
+impl JsonSchema for State {
+    fn schema_name() -> String {
+        StateVisitor::schema_name()
+    }
+
+    fn schema_id() -> Cow<'static, str> {
+        StateVisitor::schema_id()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        StateVisitor::json_schema(gen)
+    }
+}
+
 impl<'a> Deserialize<'a> for State {
     fn deserialize<D>(deserializer: D) -> Result<State, D::Error>
     where
         D: Deserializer<'a>,
     {
-        #[derive(Deserialize, Serialize)]
-        struct StateVisitor {
-            cell_states: Vec<(RpcSearchKey, ScanTip)>,
-            header_state: ScanTip,
-        }
-
         let v: StateVisitor = Deserialize::deserialize(deserializer)?;
+        let registrations: dashmap::DashMap<_, _> = v.registrations.into_iter().collect();
+        for (key, _) in v.cell_states.iter() {
+            if !registrations.contains_key(key) {
+                registrations.insert(key.clone(), Registration::default());
+            }
+        }
         Ok(State {
             cell_states: Arc::new(v.cell_states.into_iter().collect()),
-            header_state: v.header_state,
+            registrations: Arc::new(registrations),
+            header_state: HeaderState::new(v.header_state),
+            header_paused: Arc::new(AtomicBool::new(v.header_paused)),
         })
     }
 }
@@ -62,18 +332,33 @@ impl<'a> Deserialize<'a> for State {
 pub(crate) struct GlobalState {
     pub state: State,
     path: PathBuf,
-    cell_handles: Arc<dashmap::DashMap<RpcSearchKey, tokio::task::JoinHandle<()>>>,
-    axon_url: String,
+    pub ctx: SubmitContext,
+    cell_handles: Arc<dashmap::DashMap<RpcSearchKey, ScanTask>>,
+    header_handle: HeaderHandle,
+    save: Arc<tokio::sync::Notify>,
+    keep_backups: usize,
+    backup_interval: Duration,
+    archive_retention: Option<u64>,
 }
 
 impl Drop for GlobalState {
     fn drop(&mut self) {
-        self.dump_to_dir(self.path.clone())
+        if let Err(e) = self.dump_to_dir(self.path.clone()) {
+            log::error!("Failed to save state db on exit, error: {:#}", e);
+        }
     }
 }
 
 impl GlobalState {
-    pub fn new(path: PathBuf, default_header: HeaderView, axon_url: String) -> Self {
+    pub fn new(
+        path: PathBuf,
+        default_header: HeaderView,
+        axon_url: String,
+        recover: bool,
+        keep_backups: usize,
+        backup_interval: Duration,
+        archive_retention: Option<u64>,
+    ) -> anyhow::Result<Self> {
         let default_scan_tip = {
             let tip = IndexerTip {
                 block_hash: default_header.hash,
@@ -83,14 +368,29 @@ impl GlobalState {
                 Box::new(tip),
             )))))
         };
-        let state = Self::load_from_dir(path.clone(), default_scan_tip);
+        let state = Self::load_from_dir(&path, default_scan_tip, recover)?;
+        let live_cells = LiveCells::new(&path);
+        for kv in state.cell_states.iter() {
+            live_cells.get_or_load(kv.key());
+        }
 
-        Self {
+        Ok(Self {
             cell_handles: Arc::new(dashmap::DashMap::with_capacity(state.cell_states.len())),
+            header_handle: Default::default(),
+            save: Default::default(),
             state,
+            ctx: SubmitContext {
+                axon_url,
+                live_cells,
+                archive: Archive::new(&path),
+                stats: Stats::default(),
+                events: relay_events(),
+            },
             path,
-            axon_url,
-        }
+            keep_backups,
+            backup_interval,
+            archive_retention,
+        })
     }
 
     pub async fn run(&mut self) {
@@ -98,7 +398,10 @@ impl GlobalState {
         interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
         loop {
-            interval.tick().await;
+            tokio::select! {
+                _ = interval.tick() => (),
+                _ = self.save.notified() => (),
+            }
 
             // clean shutdown task
             let mut shutdown_task = Vec::new();
@@ -110,100 +413,302 @@ impl GlobalState {
                     true
                 }
             });
+            // a bounded job that got past its end block finished. Any other
+            // task died, e.g. panicked, its registration stays until deleted
+            // and is reported as not running with its last error.
             shutdown_task.into_iter().for_each(|k| {
-                self.state.cell_states.remove(&k);
+                if let Some(record) = self.job_record(&k) {
+                    if let Some(mut registration) = self.state.registrations.get_mut(&k) {
+                        registration.completed = Some(record);
+                    }
+                    self.ctx.stats.remove(&k);
+                }
             });
 
-            self.dump_to_dir(self.path.clone());
+            // keep running on a full or failing disk, the next round retries
+            if let Err(e) = self.dump_to_dir(self.path.clone()) {
+                log::error!("Failed to save state db, error: {:#}", e);
+            }
+            self.prune_archive();
         }
     }
 
-    pub fn spawn_cells(
-        &self,
-        client: RpcClient,
-    ) -> Arc<dashmap::DashMap<RpcSearchKey, tokio::task::JoinHandle<()>>> {
+    /// The record of `key` if it is a bounded job past its end block
+    fn job_record(&self, key: &RpcSearchKey) -> Option<JobRecord> {
+        let (start, end) = self
+            .state
+            .registrations
+            .get(key)
+            .and_then(|r| Some((r.start_block, r.end_block?)))?;
+        let scan_tip = self.state.cell_states.get(key)?.value().clone();
+        if scan_tip.load().block_number.value() <= end.value() {
+            return None;
+        }
+        let (live_cells, capacity) = match self.ctx.live_cells.capacity(key) {
+            Some(c) => (c.count, c.capacity),
+            None => (0.into(), 0.into()),
+        };
+        let last_error = self
+            .ctx
+            .stats
+            .cells(key)
+            .and_then(|s| s.lock().unwrap().last_error());
+        Some(JobRecord {
+            completed_at: now_ms().into(),
+            blocks: (end.value() + 1 - start.map(|b| b.value()).unwrap_or_default()).into(),
+            live_cells,
+            capacity,
+            last_error,
+        })
+    }
+
+    /// Notified to save the state now instead of at the next tick
+    pub fn save_trigger(&self) -> Arc<tokio::sync::Notify> {
+        self.save.clone()
+    }
+
+    pub fn spawn_cells(&self, client: RpcClient) -> Arc<dashmap::DashMap<RpcSearchKey, ScanTask>> {
         if !self.state.cell_states.is_empty() {
             for kv in self.state.cell_states.iter() {
-                let mut cell_process = CellProcess::new(
+                if self.state.is_idle(kv.key()) {
+                    continue;
+                }
+                let handle = spawn_cell_process(
                     kv.key().clone(),
                     kv.value().clone(),
+                    self.state.end_block(kv.key()),
                     client.clone(),
-                    RpcSubmit {
-                        axon_url: self.axon_url.clone(),
-                    },
+                    &self.ctx,
+                    self.save.clone(),
                 );
-
-                let handle = tokio::spawn(async move {
-                    cell_process.run().await;
-                });
                 self.cell_handles.insert(kv.key().clone(), handle);
             }
         }
         self.cell_handles.clone()
     }
 
-    pub fn spawn_header_sync(&self, client: RpcClient) {
-        let state = self.state.header_state.clone();
-
-        let mut header_sync = HeaderSyncProcess::new(
-            state,
-            client,
-            RpcSubmit {
-                axon_url: self.axon_url.clone(),
-            },
-        );
+    /// Spawn header sync unless it is paused, or not `enabled` at all
+    pub fn spawn_header_sync(&self, client: RpcClient, enabled: bool) -> HeaderHandle {
+        let mut header = self.header_handle.lock().unwrap();
+        header.disabled = !enabled;
+        if enabled && !self.state.header_paused.load(Ordering::Acquire) {
+            header.task = Some(spawn_header_sync_process(
+                self.state.header_state.tip(),
+                client,
+                &self.ctx,
+            ));
+        }
+        drop(header);
+        self.header_handle.clone()
+    }
 
-        tokio::spawn(async move {
-            header_sync.run().await;
-        });
+    /// Drop archived batches older than the retention window below the
+    /// highest tip
+    fn prune_archive(&self) {
+        if let Some(retention) = self.archive_retention {
+            let highest = self
+                .state
+                .cell_states
+                .iter()
+                .map(|kv| kv.value().load().block_number.value())
+                .chain(std::iter::once(
+                    self.state.header_state.tip().load().block_number.value(),
+                ))
+                .max()
+                .unwrap_or_default();
+            if let Err(e) = self.ctx.archive.prune(highest.saturating_sub(retention)) {
+                log::warn!("Failed to prune archive, error: {:#}", e);
+            }
+        }
     }
 
-    fn load_from_dir(path: PathBuf, default_scan_tip: ScanTip) -> State {
+    fn load_from_dir(
+        path: &Path,
+        default_scan_tip: ScanTip,
+        recover: bool,
+    ) -> anyhow::Result<State> {
         let db_path = path.join("scan_state");
+        let default_state = || State {
+            cell_states: Default::default(),
+            registrations: Default::default(),
+            header_state: HeaderState::new(default_scan_tip),
+            header_paused: Default::default(),
+        };
 
-        match File::open(&db_path) {
-            Ok(f) => serde_json::from_reader(f).unwrap_or(State {
-                cell_states: Default::default(),
-                header_state: default_scan_tip,
-            }),
-            Err(e) => {
-                log::warn!(
-                    "Failed to open state db, file: {:?}, error: {:?}",
+        match read_state(&db_path) {
+            Ok((state, STATE_VERSION)) => Ok(state),
+            Ok((_, version)) => Err(anyhow!(
+                "state db {:?} has version {}, this emitter uses version {}\n\
+                 upgrade it with `emitter migrate -s {}` first",
+                db_path,
+                version,
+                STATE_VERSION,
+                path.display()
+            )),
+            Err(e) if e.is::<NewerVersion>() => Err(e),
+            Err(e)
+                if e.downcast_ref::<std::io::Error>()
+                    .map(|e| e.kind() == ErrorKind::NotFound)
+                    .unwrap_or_default() =>
+            {
+                log::warn!("State db {:?} not found, start with empty state", db_path);
+                Ok(default_state())
+            }
+            Err(e) if recover => {
+                let aside = backup::set_aside(path, "corrupt")?;
+                log::error!(
+                    "State db {:?} is corrupt, moved to {:?}, error: {:#}",
                     db_path,
+                    aside,
                     e
                 );
-                State {
-                    cell_states: Default::default(),
-                    header_state: default_scan_tip,
+                for b in backup::list_backups(path) {
+                    match read_state(&b.path) {
+                        Ok((state, _)) => {
+                            log::warn!("Recovered state from backup {}", b.name);
+                            return Ok(state);
+                        }
+                        Err(e) => log::warn!("Skip unusable backup {}, error: {:#}", b.name, e),
+                    }
                 }
+                log::warn!("No usable backup found, start with empty state");
+                Ok(default_state())
             }
+            Err(e) => Err(anyhow!(
+                "state db {:?} is corrupt: {:#}\n\
+                 restart with `--recover` to fall back to the latest good backup, \
+                 or pick one with `emitter restore -s {}`",
+                db_path,
+                e,
+                path.display()
+            )),
+        }
+    }
+
+    fn dump_to_dir<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        // encode the tips before dumping live cells, so the live cells on
+        // disk are never behind the tips on disk
+        let content = encode_state(&self.state).context("failed to encode state")?;
+        self.ctx
+            .live_cells
+            .dump()
+            .context("failed to write live cells")?;
+        write_state_str(&path, &content).context("failed to write state db")?;
+        let scan_state = path.as_ref().join("scan_state");
+        if let Err(e) = backup::save_backup(
+            path.as_ref(),
+            &scan_state,
+            self.keep_backups,
+            self.backup_interval,
+        ) {
+            log::warn!("Failed to backup state db, error: {:?}", e);
         }
+        Ok(())
     }
+}
+
+/// Spawn the scan of `key`, `done` is notified when a bounded job has
+/// submitted its end block
+pub(crate) fn spawn_cell_process(
+    key: RpcSearchKey,
+    tip: ScanTip,
+    end: Option<BlockNumber>,
+    client: RpcClient,
+    ctx: &SubmitContext,
+    done: Arc<tokio::sync::Notify>,
+) -> ScanTask {
+    let submit = ctx.cells(&key, tip.load());
+    let (stats, closed) = (submit.stats(), submit.closed());
+    let mut cell_process = CellProcess::new(key, tip, client, submit).with_end(end);
+
+    spawn_task(
+        async move {
+            cell_process.run().await;
+            if cell_process.is_done() {
+                done.notify_one();
+            }
+        },
+        stats,
+        closed,
+    )
+}
+
+pub(crate) fn spawn_header_sync_process(
+    tip: ScanTip,
+    client: RpcClient,
+    ctx: &SubmitContext,
+) -> ScanTask {
+    let submit = ctx.headers(tip.load());
+    let (stats, closed) = (submit.stats(), submit.closed());
+    let mut header_sync = HeaderSyncProcess::new(tip, client, submit);
+
+    spawn_task(async move { header_sync.run().await }, stats, closed)
+}
+
+/// Spawn a scan task, a panic ends the task and is kept in its stats
+fn spawn_task<F>(task: F, stats: Arc<Mutex<TaskStats>>, closed: Arc<AtomicBool>) -> ScanTask
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let handle = tokio::spawn(async move {
+        if let Err(payload) = AssertUnwindSafe(task).catch_unwind().await {
+            stats.lock().unwrap().panicked(payload);
+        }
+    });
+    ScanTask { handle, closed }
+}
+
+/// Read and parse a state file, migrating it in memory if it was written with
+/// an older layout. Returns the state and the version found on disk.
+pub(crate) fn read_state<P: AsRef<Path>>(path: P) -> anyhow::Result<(State, u64)> {
+    let f = File::open(path.as_ref())?;
+    let doc: serde_json::Value = serde_json::from_reader(BufReader::new(f))
+        .with_context(|| format!("failed to parse {:?}", path.as_ref()))?;
+    let version = migrate::version_of(&doc);
+    let mut doc = migrate::migrate(doc)?;
+    let state = serde_json::from_value(doc["state"].take())
+        .with_context(|| format!("failed to parse {:?}", path.as_ref()))?;
+    Ok((state, version))
+}
+
+/// Atomically replace `<path>/scan_state` with the current version of `state`
+pub(crate) fn write_state<P: AsRef<Path>>(path: P, state: &State) -> anyhow::Result<()> {
+    write_state_str(path, &encode_state(state)?)
+}
+
+fn encode_state(state: &State) -> serde_json::Result<String> {
+    #[derive(Serialize)]
+    struct Envelope<'a> {
+        version: u64,
+        state: &'a State,
+    }
+
+    serde_json::to_string(&Envelope {
+        version: STATE_VERSION,
+        state,
+    })
+}
+
+fn write_state_str<P: AsRef<Path>>(path: P, json_string: &str) -> anyhow::Result<()> {
+    // create dir
+    create_dir_all(&path)?;
+    // dump file to a temporary sub-directory
+    let tmp_dir = path.as_ref().join("tmp");
+    create_dir_all(&tmp_dir)?;
+    let tmp_scan_state = tmp_dir.join("scan_state");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_scan_state)?;
+    // empty file and dump the json string to it
+    file.write_all(json_string.as_bytes())?;
+    file.sync_all()?;
+    move_file(tmp_scan_state, path.as_ref().join("scan_state"))?;
+    Ok(())
+}
 
-    fn dump_to_dir<P: AsRef<Path>>(&self, path: P) {
-        // create dir
-        create_dir_all(&path).unwrap();
-        // dump file to a temporary sub-directory
-        let tmp_dir = path.as_ref().join("tmp");
-        create_dir_all(&tmp_dir).unwrap();
-        let tmp_scan_state = tmp_dir.join("scan_state");
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(false)
-            .open(&tmp_scan_state)
-            .unwrap();
-        // empty file and dump the json string to it
-        file.set_len(0)
-            .and_then(|_| serde_json::to_string(&self.state).map_err(Into::into))
-            .and_then(|json_string| file.write_all(json_string.as_bytes()))
-            .and_then(|_| file.sync_all())
-            .unwrap();
-        move_file(tmp_scan_state, path.as_ref().join("scan_state")).unwrap();
-    }
-}
-
-fn move_file<P: AsRef<Path>>(src: P, dst: P) -> Result<(), std::io::Error> {
+pub(crate) fn move_file<P: AsRef<Path>>(src: P, dst: P) -> Result<(), std::io::Error> {
     if rename(&src, &dst).is_err() {
         copy(&src, &dst)?;
         remove_file(&src)?;
@@ -0,0 +1,28 @@
+//! The emitter binary is a thin wrapper around `run`. With the `client`
+//! feature the library also carries `client`, the typed client of the
+//! emitter rpc, which is all it exports besides `run`.
+
+mod archive;
+mod auth;
+mod backup;
+mod cli;
+#[cfg(feature = "client")]
+pub mod client;
+mod emit_data;
+mod global_state;
+mod live_cells;
+mod migrate;
+mod openrpc;
+mod rewind;
+mod rpc_server;
+mod snapshot;
+mod status;
+mod store_lock;
+mod submit;
+mod ws_queue;
+mod ws_subscription;
+
+use emitter_core::Submit;
+
+pub use crate::cli::run;
+use crate::submit::{ScanTip, ScanTipInner, SubmitContext};
@@ -0,0 +1,537 @@
+use anyhow::{Context, Result};
+use ckb_jsonrpc_types::{BlockNumber, CellOutput, JsonBytes, OutPoint, Uint32};
+use ckb_types::H256;
+use emitter_core::{
+    schema,
+    types::{IndexerTip, RpcSearchKey},
+    Submit,
+};
+use ethers::utils::keccak256;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, remove_file, File},
+    io::{BufReader, BufWriter, Write},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::global_state::move_file;
+
+const LIVE_CELLS_DIR: &str = "live_cells";
+
+/// A live cell of a registration, in the same shape as ckb indexer `get_cells`
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct LiveCell {
+    #[schemars(with = "schema::OutPoint")]
+    pub out_point: OutPoint,
+    #[schemars(with = "schema::CellOutput")]
+    pub output: CellOutput,
+    #[schemars(with = "Option<schema::JsonBytes>")]
+    pub output_data: Option<JsonBytes>,
+    #[schemars(with = "schema::Uint64")]
+    pub block_number: BlockNumber,
+}
+
+/// The live cells of one registration, built from the submits sent to axon.
+///
+/// Only the blocks from the registration's start block on are seen. Cells
+/// created before it are missing even while they are live, and spending them
+/// changes nothing.
+#[derive(Default)]
+pub struct LiveCellSet {
+    /// The last block whose cell changes are all in `index`
+    applied: Option<IndexerTip>,
+    index: BTreeMap<(H256, u32), LiveCell>,
+    /// Cell changes of the blocks after `applied` submitted so far, the last
+    /// block may only be partly submitted
+    pending: Vec<PendingBlock>,
+}
+
+/// Cell changes of one block, not applied to the index yet
+#[derive(Serialize, Deserialize)]
+struct PendingBlock {
+    number: BlockNumber,
+    created: Vec<LiveCell>,
+    spent: Vec<OutPoint>,
+}
+
+/// On disk layout of a `LiveCellSet`
+#[derive(Serialize, Deserialize)]
+struct LiveCellFile<C, P> {
+    /// Older emitters kept the last block submitted here as `tip`, which may
+    /// not have been complete, it is not read
+    #[serde(default)]
+    applied: Option<IndexerTip>,
+    cells: Vec<C>,
+    #[serde(default = "Vec::new")]
+    pending: Vec<P>,
+}
+
+impl LiveCellSet {
+    /// Take in submitted blocks. The blocks before the last one are complete
+    /// then, the last one is kept aside until it is known to be.
+    pub fn apply(&mut self, submits: &[Submit]) {
+        for submit in submits {
+            let number = submit.header.inner.number;
+            if self.pending.last().map(|b| b.number) != Some(number) {
+                self.pending.push(PendingBlock {
+                    number,
+                    created: Vec::new(),
+                    spent: Vec::new(),
+                });
+            }
+            let block = self.pending.last_mut().unwrap();
+            block.spent.extend(submit.inputs.iter().cloned());
+            block
+                .created
+                .extend(submit.outputs.iter().map(|(out_point, info)| LiveCell {
+                    out_point: out_point.clone(),
+                    output: info.output.clone(),
+                    output_data: info.data.as_ref().map(|d| d.content.clone()),
+                    block_number: submit.header.inner.number,
+                }));
+        }
+        if let Some(last) = submits.last() {
+            let number = last.header.inner.number.value();
+            if number > 0 {
+                self.advance(IndexerTip {
+                    block_hash: last.header.inner.parent_hash.clone(),
+                    block_number: (number - 1).into(),
+                });
+            }
+        }
+    }
+
+    /// Apply the pending changes of the blocks up to `last`, which are all
+    /// submitted
+    pub fn advance(&mut self, last: IndexerTip) {
+        if self
+            .applied
+            .as_ref()
+            .is_some_and(|a| a.block_number.value() >= last.block_number.value())
+        {
+            return;
+        }
+        let number = last.block_number.value();
+        let split = self.pending.partition_point(|b| b.number.value() <= number);
+        for block in self.pending.drain(..split) {
+            // a cell may be spent in the block that created it
+            for cell in block.created {
+                self.index.insert(
+                    (cell.out_point.tx_hash.clone(), cell.out_point.index.value()),
+                    cell,
+                );
+            }
+            for out_point in block.spent {
+                self.index
+                    .remove(&(out_point.tx_hash, out_point.index.value()));
+            }
+        }
+        self.applied = Some(last);
+    }
+
+    /// Undo `submits`, the blocks after `applied`. `spent` are the cells
+    /// those blocks consumed that were created before them.
+    pub fn revert(
+        &mut self,
+        submits: &[Submit],
+        spent: Vec<LiveCell>,
+        applied: Option<IndexerTip>,
+    ) {
+        // pending changes are all in undone blocks
+        self.pending.clear();
+        for submit in submits {
+            for (out_point, _) in submit.outputs.iter() {
+                self.index
+                    .remove(&(out_point.tx_hash.clone(), out_point.index.value()));
+            }
+        }
+        for cell in spent {
+            self.index.insert(
+                (cell.out_point.tx_hash.clone(), cell.out_point.index.value()),
+                cell,
+            );
+        }
+        self.applied = applied;
+    }
+}
+
+/// Page of live cells ordered by out point
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct LiveCellsPage {
+    /// The cells are exactly the live cells after this block, none until a
+    /// block of the registration was scanned
+    pub tip: Option<IndexerTip>,
+    pub objects: Vec<LiveCell>,
+    #[schemars(with = "Option<schema::OutPoint>")]
+    pub last_cursor: Option<OutPoint>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct LiveCellsCapacity {
+    pub tip: Option<IndexerTip>,
+    #[schemars(with = "schema::Uint32")]
+    pub count: Uint32,
+    #[schemars(with = "schema::Uint64")]
+    pub capacity: ckb_jsonrpc_types::Capacity,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct LiveCellLookup {
+    pub tip: Option<IndexerTip>,
+    pub cell: Option<LiveCell>,
+}
+
+/// Live cell sets of all registrations, persisted as
+/// `<store_path>/live_cells/<key id>.json`
+#[derive(Clone)]
+pub struct LiveCells {
+    dir: PathBuf,
+    sets: Arc<dashmap::DashMap<RpcSearchKey, Arc<Mutex<LiveCellSet>>>>,
+}
+
+impl LiveCells {
+    pub fn new<P: AsRef<Path>>(store_path: P) -> Self {
+        LiveCells {
+            dir: store_path.as_ref().join(LIVE_CELLS_DIR),
+            sets: Default::default(),
+        }
+    }
+
+    /// The set of `key`, loaded from disk the first time it is asked for
+    pub fn get_or_load(&self, key: &RpcSearchKey) -> Arc<Mutex<LiveCellSet>> {
+        self.sets
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let path = self.dir.join(format!("{}.json", key_id(key)));
+                let set = match read_set(&path) {
+                    Ok(set) => set,
+                    Err(e) => {
+                        if path.exists() {
+                            log::warn!("Failed to load live cells {:?}, error: {:#}", path, e);
+                        }
+                        LiveCellSet::default()
+                    }
+                };
+                Arc::new(Mutex::new(set))
+            })
+            .clone()
+    }
+
+    pub fn get(&self, key: &RpcSearchKey) -> Option<Arc<Mutex<LiveCellSet>>> {
+        self.sets.get(key).map(|s| s.value().clone())
+    }
+
+    /// Forget the set of a deleted registration
+    pub fn remove(&self, key: &RpcSearchKey) {
+        self.sets.remove(key);
+        let _ = remove_file(self.dir.join(format!("{}.json", key_id(key))));
+    }
+
+    /// Replace the set of `from` with an empty one for `to`, for a
+    /// registration whose filter or search mode changed. The old cells were
+    /// matched by the old version, the new one is only known to match what
+    /// it finds from its scan tip on, nothing before that.
+    pub fn reset(&self, from: &RpcSearchKey, to: &RpcSearchKey) {
+        let applied = self.get_or_load(from).lock().unwrap().applied.clone();
+        self.remove(from);
+        self.sets.insert(
+            to.clone(),
+            Arc::new(Mutex::new(LiveCellSet {
+                applied,
+                ..Default::default()
+            })),
+        );
+    }
+
+    pub fn dump(&self) -> Result<()> {
+        create_dir_all(&self.dir)?;
+        let tmp_dir = self.dir.join("tmp");
+        create_dir_all(&tmp_dir)?;
+        for kv in self.sets.iter() {
+            let name = format!("{}.json", key_id(kv.key()));
+            let tmp = tmp_dir.join(&name);
+            {
+                let set = kv.value().lock().unwrap();
+                let mut writer = BufWriter::new(File::create(&tmp)?);
+                serde_json::to_writer(
+                    &mut writer,
+                    &LiveCellFile {
+                        applied: set.applied.clone(),
+                        cells: set.index.values().collect(),
+                        pending: set.pending.iter().collect(),
+                    },
+                )?;
+                writer.flush()?;
+                writer.get_ref().sync_all()?;
+            }
+            move_file(tmp, self.dir.join(name))?;
+        }
+        Ok(())
+    }
+
+    pub fn page(
+        &self,
+        key: &RpcSearchKey,
+        limit: usize,
+        after: Option<OutPoint>,
+    ) -> Option<LiveCellsPage> {
+        let set = self.get(key)?;
+        let set = set.lock().unwrap();
+        let start = match after {
+            Some(o) => Bound::Excluded((o.tx_hash, o.index.value())),
+            None => Bound::Unbounded,
+        };
+        let objects = set
+            .index
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(_, cell)| cell.clone())
+            .collect::<Vec<_>>();
+        let last_cursor = if objects.len() == limit {
+            objects.last().map(|c| c.out_point.clone())
+        } else {
+            None
+        };
+        Some(LiveCellsPage {
+            tip: set.applied.clone(),
+            objects,
+            last_cursor,
+        })
+    }
+
+    pub fn capacity(&self, key: &RpcSearchKey) -> Option<LiveCellsCapacity> {
+        let set = self.get(key)?;
+        let set = set.lock().unwrap();
+        let capacity = set
+            .index
+            .values()
+            .map(|c| c.output.capacity.value())
+            .sum::<u64>();
+        Some(LiveCellsCapacity {
+            tip: set.applied.clone(),
+            count: (set.index.len() as u32).into(),
+            capacity: capacity.into(),
+        })
+    }
+
+    pub fn lookup(&self, key: &RpcSearchKey, out_point: OutPoint) -> Option<LiveCellLookup> {
+        let set = self.get(key)?;
+        let set = set.lock().unwrap();
+        Some(LiveCellLookup {
+            tip: set.applied.clone(),
+            cell: set
+                .index
+                .get(&(out_point.tx_hash, out_point.index.value()))
+                .cloned(),
+        })
+    }
+}
+
+fn read_set(path: &Path) -> Result<LiveCellSet> {
+    let f = File::open(path)?;
+    let file: LiveCellFile<LiveCell, PendingBlock> = serde_json::from_reader(BufReader::new(f))
+        .with_context(|| format!("failed to parse {:?}", path))?;
+    Ok(LiveCellSet {
+        applied: file.applied,
+        index: file
+            .cells
+            .into_iter()
+            .map(|c| ((c.out_point.tx_hash.clone(), c.out_point.index.value()), c))
+            .collect(),
+        pending: file.pending,
+    })
+}
+
+/// Stable file name friendly id of a search key
+pub fn key_id(key: &RpcSearchKey) -> String {
+    hex::encode(keccak256(serde_json::to_vec(key).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_jsonrpc_types::{CellInfo, ScriptHashType};
+    use ckb_types::{packed, prelude::*};
+    use emitter_core::types::ScriptType;
+
+    fn key() -> RpcSearchKey {
+        RpcSearchKey {
+            script: ckb_jsonrpc_types::Script {
+                code_hash: H256::default(),
+                hash_type: ScriptHashType::Type,
+                args: JsonBytes::from_vec(vec![1]),
+            },
+            script_type: ScriptType::Lock,
+            script_search_mode: None,
+            filter: None,
+            group: Vec::new(),
+        }
+    }
+
+    fn out_point(n: u8) -> OutPoint {
+        OutPoint {
+            tx_hash: H256([n; 32]),
+            index: 0.into(),
+        }
+    }
+
+    /// A submit of block `number` creating the cells `created` and spending
+    /// `spent`
+    fn submit(number: u64, created: &[u8], spent: &[u8]) -> Submit {
+        let parent = packed::Header::new_builder()
+            .raw(
+                packed::RawHeader::new_builder()
+                    .number(number.saturating_sub(1).pack())
+                    .build(),
+            )
+            .build()
+            .into_view();
+        let header = packed::Header::new_builder()
+            .raw(
+                packed::RawHeader::new_builder()
+                    .number(number.pack())
+                    .parent_hash(parent.hash())
+                    .build(),
+            )
+            .build()
+            .into_view();
+        Submit {
+            header: header.into(),
+            inputs: spent.iter().map(|&n| out_point(n)).collect(),
+            outputs: created
+                .iter()
+                .map(|&n| {
+                    (
+                        out_point(n),
+                        CellInfo {
+                            output: packed::CellOutput::new_builder()
+                                .capacity(100u64.pack())
+                                .build()
+                                .into(),
+                            data: None,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn tip(submit: &Submit) -> IndexerTip {
+        IndexerTip {
+            block_hash: submit.header.hash.clone(),
+            block_number: submit.header.inner.number,
+        }
+    }
+
+    fn live(cells: &LiveCells, n: u8) -> bool {
+        cells.lookup(&key(), out_point(n)).unwrap().cell.is_some()
+    }
+
+    #[test]
+    fn keeps_the_last_block_until_it_is_scanned() {
+        let dir = tempfile::tempdir().unwrap();
+        let cells = LiveCells::new(dir.path());
+        let set = cells.get_or_load(&key());
+        assert!(cells.capacity(&key()).unwrap().tip.is_none());
+
+        // block 11 may go on in the next submit
+        let first = [submit(10, &[1], &[]), submit(11, &[2], &[])];
+        set.lock().unwrap().apply(&first);
+        let capacity = cells.capacity(&key()).unwrap();
+        assert_eq!(capacity.tip.unwrap().block_number.value(), 10);
+        assert_eq!(capacity.count.value(), 1);
+        assert!(!live(&cells, 2));
+
+        // the rest of block 11 spends a cell it created
+        set.lock().unwrap().apply(&[submit(11, &[3], &[2])]);
+        assert!(!live(&cells, 2));
+        set.lock().unwrap().advance(tip(&first[1]));
+        let capacity = cells.capacity(&key()).unwrap();
+        assert_eq!(capacity.tip.unwrap().block_hash, first[1].header.hash);
+        assert_eq!(capacity.count.value(), 2);
+        assert!(live(&cells, 1) && !live(&cells, 2) && live(&cells, 3));
+
+        // an older tip changes nothing
+        set.lock().unwrap().advance(tip(&first[0]));
+        assert_eq!(
+            cells.capacity(&key()).unwrap().tip.unwrap().block_hash,
+            first[1].header.hash
+        );
+    }
+
+    #[test]
+    fn revert_drops_the_pending_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let cells = LiveCells::new(dir.path());
+        let set = cells.get_or_load(&key());
+        set.lock()
+            .unwrap()
+            .apply(&[submit(10, &[1], &[]), submit(11, &[2], &[1])]);
+
+        let undo = [submit(11, &[2], &[1])];
+        let spent = cells.lookup(&key(), out_point(1)).unwrap().cell;
+        set.lock().unwrap().revert(
+            &undo,
+            spent.into_iter().collect(),
+            Some(tip(&submit(10, &[], &[]))),
+        );
+        set.lock().unwrap().advance(tip(&undo[0]));
+        assert!(live(&cells, 1) && !live(&cells, 2));
+        assert_eq!(cells.capacity(&key()).unwrap().count.value(), 1);
+    }
+
+    #[test]
+    fn reset_starts_over_at_the_same_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let cells = LiveCells::new(dir.path());
+        let first = [submit(10, &[1], &[]), submit(11, &[2], &[])];
+        cells.get_or_load(&key()).lock().unwrap().apply(&first);
+        cells.dump().unwrap();
+
+        let mut new_key = key();
+        new_key.script.args = JsonBytes::from_vec(vec![2]);
+        cells.reset(&key(), &new_key);
+        assert!(cells.get(&key()).is_none());
+        let capacity = cells.capacity(&new_key).unwrap();
+        assert_eq!(capacity.count.value(), 0);
+        assert_eq!(capacity.tip.unwrap().block_number.value(), 10);
+
+        // neither the old cells nor the pending block come back
+        cells
+            .get_or_load(&new_key)
+            .lock()
+            .unwrap()
+            .advance(tip(&first[1]));
+        assert_eq!(cells.capacity(&new_key).unwrap().count.value(), 0);
+        assert!(LiveCells::new(dir.path())
+            .get_or_load(&key())
+            .lock()
+            .unwrap()
+            .index
+            .is_empty());
+    }
+
+    #[test]
+    fn reads_back_what_it_dumped() {
+        let dir = tempfile::tempdir().unwrap();
+        let cells = LiveCells::new(dir.path());
+        let last = submit(11, &[2], &[]);
+        cells
+            .get_or_load(&key())
+            .lock()
+            .unwrap()
+            .apply(&[submit(10, &[1], &[]), submit(11, &[2], &[])]);
+        cells.dump().unwrap();
+
+        let loaded = LiveCells::new(dir.path());
+        let set = loaded.get_or_load(&key());
+        assert_eq!(loaded.capacity(&key()).unwrap().count.value(), 1);
+        // the pending block is applied once it is scanned
+        set.lock().unwrap().advance(tip(&last));
+        assert!(live(&loaded, 1) && live(&loaded, 2));
+    }
+}
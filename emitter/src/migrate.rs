@@ -0,0 +1,217 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    backup,
+    global_state::{read_state, write_state},
+};
+
+/// Version of the state db layout written by this build
+pub const STATE_VERSION: u64 = 4;
+
+type Migration = fn(Value) -> Result<Value>;
+
+/// `MIGRATIONS[n]` upgrades a version `n` document to version `n + 1`
+const MIGRATIONS: [Migration; STATE_VERSION as usize] = [v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4];
+
+/// The state db was written by a newer emitter, it must not be touched
+#[derive(Debug)]
+pub struct NewerVersion(pub u64);
+
+impl fmt::Display for NewerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "state version {} is newer than the supported version {}, upgrade the emitter",
+            self.0, STATE_VERSION
+        )
+    }
+}
+
+impl std::error::Error for NewerVersion {}
+
+pub fn version_of(doc: &Value) -> u64 {
+    doc.get("version").and_then(Value::as_u64).unwrap_or(0)
+}
+
+/// Upgrade a raw state document to `STATE_VERSION`
+pub fn migrate(mut doc: Value) -> Result<Value> {
+    let from = version_of(&doc);
+    if from > STATE_VERSION {
+        return Err(NewerVersion(from).into());
+    }
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        doc = migration(doc).with_context(|| {
+            format!(
+                "failed to migrate state from version {} to {}",
+                version,
+                version + 1
+            )
+        })?;
+    }
+    Ok(doc)
+}
+
+/// Upgrade the state db of a store in place.
+///
+/// The original file is copied to `scan_state.v<version>.<timestamp>` first.
+/// Returns the version found on disk and the path of that copy, or `None` if
+/// the store is already up to date.
+pub fn migrate_store<P: AsRef<Path>>(store_path: P) -> Result<Option<(u64, PathBuf)>> {
+    let store_path = store_path.as_ref();
+    let (state, version) = read_state(store_path.join("scan_state"))?;
+    if version == STATE_VERSION {
+        return Ok(None);
+    }
+    let original = backup::keep_copy(store_path, &format!("v{}", version))?;
+    write_state(store_path, &state)?;
+    Ok(Some((version, original)))
+}
+
+/// Version 0 is the bare `State` without an envelope
+fn v0_to_v1(doc: Value) -> Result<Value> {
+    Ok(json!({ "version": 1, "state": doc }))
+}
+
+/// Version 2 adds a metadata record for each registration, nothing is known
+/// about keys registered before that
+fn v1_to_v2(mut doc: Value) -> Result<Value> {
+    let state = doc
+        .get_mut("state")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| anyhow!("missing state"))?;
+    let registrations = state
+        .get("cell_states")
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow!("missing cell_states"))?
+        .iter()
+        .map(|kv| json!([kv[0], {}]))
+        .collect::<Vec<_>>();
+    state.insert("registrations".to_string(), Value::Array(registrations));
+    doc["version"] = json!(2);
+    Ok(doc)
+}
+
+/// Version 3 can pause header sync and registrations, nothing was paused
+/// before that. Older emitters must not run a store that may hold paused
+/// registrations, hence the version bump.
+fn v2_to_v3(mut doc: Value) -> Result<Value> {
+    let state = doc
+        .get_mut("state")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| anyhow!("missing state"))?;
+    state.insert("header_paused".to_string(), json!(false));
+    doc["version"] = json!(3);
+    Ok(doc)
+}
+
+/// Version 4 registrations can have an end block, a completion record and a
+/// filter history, none had them before that. An older emitter would drop
+/// them on its next write and scan a finished job past its end again, hence
+/// the version bump.
+fn v3_to_v4(mut doc: Value) -> Result<Value> {
+    doc.get("state")
+        .and_then(Value::as_object)
+        .ok_or_else(|| anyhow!("missing state"))?;
+    doc["version"] = json!(4);
+    Ok(doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global_state::State;
+    use emitter_core::TipState;
+
+    fn tip(number: u64) -> Value {
+        json!({
+            "block_hash": format!("0x{:064x}", number),
+            "block_number": format!("{:#x}", number),
+        })
+    }
+
+    fn key(args: &str) -> Value {
+        json!({
+            "script": {
+                "code_hash": format!("0x{:064x}", 1),
+                "hash_type": "type",
+                "args": args,
+            },
+            "script_type": "lock",
+            "script_search_mode": null,
+            "filter": null,
+        })
+    }
+
+    /// A state db as written before it had a version
+    fn v0() -> Value {
+        json!({
+            "cell_states": [[key("0x01"), tip(100)], [key("0x02"), tip(200)]],
+            "header_state": tip(300),
+        })
+    }
+
+    #[test]
+    fn v0_is_wrapped_in_an_envelope() {
+        let doc = v0_to_v1(v0()).unwrap();
+        assert_eq!(version_of(&doc), 1);
+        assert_eq!(doc["state"], v0());
+    }
+
+    #[test]
+    fn v1_gets_an_empty_registration_per_key() {
+        let doc = v1_to_v2(v0_to_v1(v0()).unwrap()).unwrap();
+        assert_eq!(version_of(&doc), 2);
+        assert_eq!(
+            doc["state"]["registrations"],
+            json!([[key("0x01"), {}], [key("0x02"), {}]])
+        );
+    }
+
+    #[test]
+    fn v2_is_not_paused() {
+        let doc = migrate(v0()).unwrap();
+        assert_eq!(doc["state"]["header_paused"], json!(false));
+    }
+
+    #[test]
+    fn every_version_migrates_to_the_current_one() {
+        let mut doc = v0();
+        for (version, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(version_of(&doc), version as u64);
+            let migrated = migrate(doc.clone()).unwrap();
+            assert_eq!(version_of(&migrated), STATE_VERSION);
+
+            let state: State = serde_json::from_value(migrated["state"].clone()).unwrap();
+            assert_eq!(state.cell_states.len(), 2);
+            assert_eq!(state.registrations.len(), 2);
+            assert_eq!(state.header_state.tip().load().block_number.value(), 300);
+
+            doc = migration(doc).unwrap();
+        }
+        assert_eq!(version_of(&doc), STATE_VERSION);
+    }
+
+    #[test]
+    fn current_version_is_left_alone() {
+        let doc = migrate(v0()).unwrap();
+        assert_eq!(migrate(doc.clone()).unwrap(), doc);
+    }
+
+    #[test]
+    fn newer_version_is_refused() {
+        let doc = json!({ "version": STATE_VERSION + 1, "state": {} });
+        let err = migrate(doc).unwrap_err();
+        assert!(err.downcast_ref::<NewerVersion>().is_some());
+    }
+
+    #[test]
+    fn missing_state_fails() {
+        let err = migrate(json!({ "version": 1 })).unwrap_err();
+        assert!(format!("{:#}", err).contains("missing state"));
+    }
+}
@@ -0,0 +1,546 @@
+use emitter_core::{
+    schema,
+    types::{IndexerScriptSearchMode, RpcSearchKey, RpcSearchKeyFilter, RpcSearchKeyParam},
+};
+use jsonrpsee::{
+    core::{traits::ToRpcParams, Error},
+    types::error::{CallError, ErrorObject, INVALID_PARAMS_CODE},
+    RpcModule,
+};
+use regex::Regex;
+use schemars::gen::SchemaSettings;
+use serde_json::{
+    json,
+    value::{to_raw_value, RawValue},
+    Map, Value,
+};
+use std::collections::HashMap;
+
+use crate::{
+    archive::{HistoryPage, ReplayReport},
+    global_state::State,
+    live_cells::{LiveCellLookup, LiveCellsCapacity, LiveCellsPage},
+    rewind::RewindReport,
+    rpc_server::{
+        BatchReport, EmitterRpc, InfoFilter, Preview, RegisterItem, RegisterMeta, UpdateReport,
+    },
+    snapshot::{ImportMode, ImportReport, Snapshot},
+    status::{HeaderSyncStatus, StatusReport},
+};
+
+const DEFINITIONS: &str = "#/components/schemas/";
+
+struct Param {
+    name: &'static str,
+    schema: Value,
+}
+
+struct Method {
+    name: &'static str,
+    summary: &'static str,
+    params: Vec<Param>,
+    result: Value,
+}
+
+/// Mirrors the `Emitter` trait, with the ckb json types of the parameters
+/// and results replaced by their schemas
+macro_rules! methods {
+    ($($name:ident($summary:literal)($($param:ident: $ty:ty),*) -> $result:ty;)*) => {
+        fn methods(gen: &mut schemars::gen::SchemaGenerator) -> Vec<Method> {
+            vec![$(Method {
+                name: stringify!($name),
+                summary: $summary,
+                params: vec![$(Param {
+                    name: stringify!($param),
+                    schema: json!(gen.subschema_for::<$ty>()),
+                }),*],
+                result: json!(gen.subschema_for::<$result>()),
+            }),*]
+        }
+    };
+}
+
+methods! {
+    register("Register the cell you want to track")(
+        search_key: RpcSearchKeyParam,
+        start: schema::Uint64,
+        meta: Option<RegisterMeta>,
+        end: Option<schema::Uint64>
+    ) -> bool;
+    register_batch("Register many keys at once, nothing is registered if any item is invalid")(
+        items: Vec<RegisterItem>
+    ) -> BatchReport;
+    delete("Delete the registered cell")(search_key: RpcSearchKey) -> bool;
+    delete_batch("Delete many registrations at once")(search_keys: Vec<RpcSearchKey>) -> BatchReport;
+    update("Change the filter or search mode of a registered key")(
+        search_key: RpcSearchKey,
+        filter: Option<RpcSearchKeyFilter>,
+        script_search_mode: Option<IndexerScriptSearchMode>,
+        rescan_from: Option<schema::Uint64>
+    ) -> UpdateReport;
+    info("Returns the state of the cell being tracked")(filter: Option<InfoFilter>) -> State;
+    header_sync_start("Start a stopped header sync from its tip, or move it on to a later header first")(
+        number: Option<schema::Uint64>
+    ) -> bool;
+    export_state("Returns a snapshot of the whole state")() -> Snapshot;
+    import_state("Import a snapshot returned by export_state")(
+        snapshot: Snapshot,
+        mode: Option<ImportMode>
+    ) -> ImportReport;
+    get_live_cells("Page through the live cells of a registration")(
+        search_key: RpcSearchKey,
+        limit: schema::Uint32,
+        after: Option<schema::OutPoint>
+    ) -> LiveCellsPage;
+    get_live_cells_capacity("Total capacity and number of the live cells of a registration")(
+        search_key: RpcSearchKey
+    ) -> LiveCellsCapacity;
+    get_live_cell("Look up a single out point in the live cells of a registration")(
+        search_key: RpcSearchKey,
+        out_point: schema::OutPoint
+    ) -> LiveCellLookup;
+    pause("Stop the scan of a registration, keeping its tip")(
+        search_key: Option<RpcSearchKey>
+    ) -> Vec<RpcSearchKey>;
+    resume("Restart the scan of a paused registration from its tip")(
+        search_key: Option<RpcSearchKey>
+    ) -> Vec<RpcSearchKey>;
+    header_sync_pause("Stop header sync, keeping its tip")() -> bool;
+    header_sync_resume("Restart header sync from its tip")() -> bool;
+    rewind("Move a registration back to an earlier block and scan again from there")(
+        search_key: RpcSearchKey,
+        number: schema::Uint64,
+        rollback: Option<bool>
+    ) -> RewindReport;
+    header_sync_rewind("Move header sync back to an earlier block and sync again from there")(
+        number: schema::Uint64,
+        rollback: Option<bool>
+    ) -> RewindReport;
+    status("Progress and health of header sync and of every registration")(
+        search_key: Option<RpcSearchKey>
+    ) -> StatusReport;
+    replay("Push the archived headers and cells of a block range to Axon again")(
+        from: schema::Uint64,
+        to: schema::Uint64,
+        axon_url: Option<String>
+    ) -> ReplayReport;
+    preview("Scan a block range for a search key the way a registration would")(
+        search_key: RpcSearchKey,
+        from: schema::Uint64,
+        to: schema::Uint64,
+        calldata: Option<bool>
+    ) -> Preview;
+    history("Page through the blocks emitted for a search key")(
+        search_key: RpcSearchKey,
+        from: schema::Uint64,
+        to: schema::Uint64,
+        limit: schema::Uint32,
+        after: Option<schema::Uint64>
+    ) -> HistoryPage;
+    header_sync_stop("Stop header sync until it is started again")() -> bool;
+    header_sync_restart("Stop header sync and start it again from its tip")() -> bool;
+    header_sync_status("Whether header sync runs, its tip, lag and last batch")() -> HeaderSyncStatus;
+}
+
+/// The `OpenRPC` document of the emitter rpc and the validator of the
+/// parameters it describes
+pub struct OpenRpc {
+    document: Value,
+    methods: HashMap<&'static str, Vec<Param>>,
+    definitions: Map<String, Value>,
+    patterns: HashMap<String, Regex>,
+}
+
+impl OpenRpc {
+    fn new() -> Self {
+        let mut gen = SchemaSettings::draft07()
+            .with(|s| s.definitions_path = DEFINITIONS.to_string())
+            .into_generator();
+        let methods = methods(&mut gen);
+        let definitions: Map<String, Value> = gen
+            .take_definitions()
+            .into_iter()
+            .map(|(name, schema)| (name, json!(schema)))
+            .collect();
+
+        let mut patterns = HashMap::new();
+        for schema in definitions.values().chain(
+            methods
+                .iter()
+                .flat_map(|m| m.params.iter().map(|p| &p.schema)),
+        ) {
+            collect_patterns(schema, &mut patterns);
+        }
+
+        let mut openrpc = OpenRpc {
+            document: Value::Null,
+            methods: HashMap::new(),
+            definitions,
+            patterns,
+        };
+        let described: Vec<Value> = methods
+            .iter()
+            .map(|m| {
+                json!({
+                    "name": m.name,
+                    "summary": m.summary,
+                    "paramStructure": "by-position",
+                    "params": m.params.iter().map(|p| json!({
+                        "name": p.name,
+                        // optional parameters take null
+                        "required": openrpc.check(&p.schema, &Value::Null, p.name).is_err(),
+                        "schema": p.schema,
+                    })).collect::<Vec<_>>(),
+                    "result": { "name": "result", "schema": m.result },
+                })
+            })
+            .collect();
+        openrpc.document = json!({
+            "openrpc": "1.2.6",
+            "info": { "title": "emitter", "version": env!("CARGO_PKG_VERSION") },
+            "methods": described,
+            "components": { "schemas": openrpc.definitions },
+        });
+        openrpc.methods = methods.into_iter().map(|m| (m.name, m.params)).collect();
+        openrpc
+    }
+
+    /// Check the positional parameters of a call to `method` against its
+    /// schemas, the error names the offending field
+    pub fn validate(&self, method: &str, params: &Value) -> Result<(), String> {
+        let declared = match self.methods.get(method) {
+            Some(declared) => declared,
+            None => return Ok(()),
+        };
+        let given = match params {
+            Value::Array(given) => given.as_slice(),
+            Value::Null => &[],
+            // rejected by the rpc server
+            _ => return Ok(()),
+        };
+        for (i, param) in declared.iter().enumerate() {
+            self.check(
+                &param.schema,
+                given.get(i).unwrap_or(&Value::Null),
+                param.name,
+            )
+            .map_err(|e| match given.get(i) {
+                None => format!("{} is required", param.name),
+                Some(_) => e,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn check(&self, schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+        let schema = match schema {
+            Value::Object(schema) => schema,
+            Value::Bool(false) => return Err(format!("{} is not allowed", path)),
+            _ => return Ok(()),
+        };
+
+        if let Some(Value::String(reference)) = schema.get("$ref") {
+            return self.check(self.resolve(reference), value, path);
+        }
+        if let Some(Value::Array(all)) = schema.get("allOf") {
+            for s in all {
+                self.check(s, value, path)?;
+            }
+        }
+        if let Some(Value::Array(any)) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            if !any.iter().any(|s| self.check(s, value, path).is_ok()) {
+                // report against the one alternative of the same json type
+                let mut candidates = any.iter().filter(|s| self.type_matches(s, value));
+                return match (candidates.next(), candidates.next()) {
+                    (Some(s), None) => self.check(s, value, path),
+                    _ => Err(format!(
+                        "{} must be {}",
+                        path,
+                        any.iter()
+                            .map(|s| self.expected(s))
+                            .collect::<Vec<_>>()
+                            .join(" or ")
+                    )),
+                };
+            }
+        }
+
+        if !self.type_matches(&Value::Object(schema.clone()), value) {
+            return Err(format!("{} must be {}", path, self.expected_of(schema)));
+        }
+        if let Some(Value::Array(allowed)) = schema.get("enum") {
+            if !allowed.contains(value) {
+                return Err(format!(
+                    "{} must be one of {}",
+                    path,
+                    allowed
+                        .iter()
+                        .map(|v| v.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+        if let Some(constant) = schema.get("const") {
+            if constant != value {
+                return Err(format!("{} must be {}", path, constant));
+            }
+        }
+
+        match value {
+            Value::Object(fields) => self.check_object(schema, fields, path),
+            Value::Array(items) => self.check_array(schema, items, path),
+            Value::String(s) => match schema.get("pattern") {
+                Some(Value::String(pattern)) if !self.patterns[pattern].is_match(s) => {
+                    Err(format!("{} must be {}", path, self.expected_of(schema)))
+                }
+                _ => Ok(()),
+            },
+            Value::Number(n) => {
+                if let (Some(min), Some(n)) =
+                    (schema.get("minimum").and_then(Value::as_f64), n.as_f64())
+                {
+                    if n < min {
+                        return Err(format!("{} must be >= {}", path, min));
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_object(
+        &self,
+        schema: &Map<String, Value>,
+        fields: &Map<String, Value>,
+        path: &str,
+    ) -> Result<(), String> {
+        let empty = Map::new();
+        let properties = schema
+            .get("properties")
+            .and_then(Value::as_object)
+            .unwrap_or(&empty);
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !fields.contains_key(name) {
+                    return Err(format!("{}.{} is required", path, name));
+                }
+            }
+        }
+        for (name, value) in fields {
+            let field = format!("{}.{}", path, name);
+            match properties.get(name) {
+                Some(s) => self.check(s, value, &field)?,
+                None => {
+                    if let Some(additional) = schema.get("additionalProperties") {
+                        self.check(additional, value, &field)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_array(
+        &self,
+        schema: &Map<String, Value>,
+        items: &[Value],
+        path: &str,
+    ) -> Result<(), String> {
+        let min = schema.get("minItems").and_then(Value::as_u64);
+        let max = schema.get("maxItems").and_then(Value::as_u64);
+        match (min, max) {
+            (Some(min), Some(max)) if min == max && items.len() as u64 != min => {
+                return Err(format!("{} must have {} items", path, min));
+            }
+            (Some(min), _) if (items.len() as u64) < min => {
+                return Err(format!("{} must have at least {} items", path, min));
+            }
+            (_, Some(max)) if items.len() as u64 > max => {
+                return Err(format!("{} must have at most {} items", path, max));
+            }
+            _ => {}
+        }
+        for (i, item) in items.iter().enumerate() {
+            let item_schema = match schema.get("items") {
+                // a tuple
+                Some(Value::Array(tuple)) => tuple.get(i),
+                other => other,
+            };
+            if let Some(s) = item_schema {
+                self.check(s, item, &format!("{}[{}]", path, i))?;
+            }
+        }
+        if schema.get(schema::ASCENDING) == Some(&Value::Bool(true)) {
+            let bound = |v: &Value| {
+                v.as_str()
+                    .and_then(|s| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok())
+            };
+            if let (Some(lower), Some(upper)) = (bound(&items[0]), bound(&items[1])) {
+                if upper < lower {
+                    return Err(format!("{}[1] must be >= [0]", path));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve(&self, reference: &str) -> &Value {
+        reference
+            .strip_prefix(DEFINITIONS)
+            .and_then(|name| self.definitions.get(name))
+            .unwrap_or(&Value::Bool(true))
+    }
+
+    /// Whether `value` has one of the json types `schema` allows
+    fn type_matches(&self, schema: &Value, value: &Value) -> bool {
+        let schema = match schema {
+            Value::Object(schema) => schema,
+            _ => return true,
+        };
+        if let Some(Value::String(reference)) = schema.get("$ref") {
+            return self.type_matches(self.resolve(reference), value);
+        }
+        if let Some(Value::Array(any)) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            if !any.iter().any(|s| self.type_matches(s, value)) {
+                return false;
+            }
+        }
+        let allowed = match schema.get("type") {
+            Some(Value::String(t)) => vec![t.as_str()],
+            Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+            _ => return true,
+        };
+        allowed.into_iter().any(|t| match t {
+            "null" => value.is_null(),
+            "boolean" => value.is_boolean(),
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_u64() || value.is_i64(),
+            _ => true,
+        })
+    }
+
+    fn expected(&self, schema: &Value) -> String {
+        match schema {
+            Value::Object(schema) => match schema.get("$ref") {
+                Some(Value::String(reference)) => self.expected(self.resolve(reference)),
+                _ => self.expected_of(schema),
+            },
+            _ => "anything".to_string(),
+        }
+    }
+
+    /// What a value of `schema` looks like, for error messages
+    fn expected_of(&self, schema: &Map<String, Value>) -> String {
+        if let Some(Value::String(description)) = schema.get("description") {
+            if schema.contains_key("pattern") {
+                return format!("a {}", description);
+            }
+        }
+        let types: Vec<&str> = match schema.get("type") {
+            Some(Value::String(t)) => vec![t.as_str()],
+            Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if types.is_empty() {
+            return "a valid value".to_string();
+        }
+        types
+            .into_iter()
+            .map(|t| match t {
+                "null" => "null".to_string(),
+                "object" | "array" | "integer" => format!("an {}", t),
+                t => format!("a {}", t),
+            })
+            .collect::<Vec<_>>()
+            .join(" or ")
+    }
+}
+
+fn collect_patterns(schema: &Value, patterns: &mut HashMap<String, Regex>) {
+    match schema {
+        Value::Object(fields) => {
+            if let Some(Value::String(pattern)) = fields.get("pattern") {
+                patterns
+                    .entry(pattern.clone())
+                    .or_insert_with(|| Regex::new(pattern).unwrap());
+            }
+            fields.values().for_each(|v| collect_patterns(v, patterns));
+        }
+        Value::Array(items) => items.iter().for_each(|v| collect_patterns(v, patterns)),
+        _ => {}
+    }
+}
+
+struct RawParams(Option<Box<RawValue>>);
+
+impl ToRpcParams for RawParams {
+    fn to_rpc_params(self) -> Result<Option<Box<RawValue>>, Error> {
+        Ok(self.0)
+    }
+}
+
+pub struct Validated {
+    inner: RpcModule<EmitterRpc>,
+    openrpc: OpenRpc,
+}
+
+/// Serve the methods of `rpc` behind the validation of their parameters,
+/// along with `rpc.discover` returning the document they are checked against
+pub fn validated_module(rpc: RpcModule<EmitterRpc>) -> RpcModule<Validated> {
+    validated_subset(rpc, |_| true)
+}
+
+/// Same as `validated_module`, but only the methods `serve` accepts are
+/// callable
+pub fn validated_subset<F>(rpc: RpcModule<EmitterRpc>, serve: F) -> RpcModule<Validated>
+where
+    F: Fn(&str) -> bool,
+{
+    let openrpc = OpenRpc::new();
+    let names: Vec<&'static str> = rpc.method_names().collect();
+    for name in &names {
+        assert!(
+            openrpc.methods.contains_key(name),
+            "method {} is missing from the openrpc document",
+            name
+        );
+    }
+    assert_eq!(
+        names.len(),
+        openrpc.methods.len(),
+        "the openrpc document has methods the rpc does not serve"
+    );
+
+    let mut module = RpcModule::new(Validated {
+        inner: rpc,
+        openrpc,
+    });
+    module
+        .register_method("rpc.discover", |_, ctx| Ok(ctx.openrpc.document.clone()))
+        .unwrap();
+    for name in names.into_iter().filter(|name| serve(name)) {
+        module
+            .register_async_method(name, move |params, ctx| async move {
+                let value: Value = params.parse()?;
+                ctx.openrpc.validate(name, &value).map_err(|e| {
+                    Error::Call(CallError::Custom(ErrorObject::owned(
+                        INVALID_PARAMS_CODE,
+                        e,
+                        None::<()>,
+                    )))
+                })?;
+                let raw = match value {
+                    Value::Null => None,
+                    value => Some(to_raw_value(&value).map_err(Error::ParseError)?),
+                };
+                ctx.inner.call::<_, Value>(name, RawParams(raw)).await
+            })
+            .unwrap();
+    }
+    module
+}
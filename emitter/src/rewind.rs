@@ -0,0 +1,521 @@
+use anyhow::{anyhow, Context, Result};
+use ckb_jsonrpc_types::{OutPoint, Uint64};
+use ckb_types::H256;
+use emitter_core::{
+    cell_process::fetch_submits,
+    rpc_client::RpcClient,
+    schema,
+    types::{IndexerTip, RpcSearchKey},
+    Rpc, Submit,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use crate::{
+    archive::Archive,
+    emit_data::{
+        eth_tx::{send_eth_tx, CKB_LIGHT_CLIENT_ADDRESS, IMAGE_CELL_ADDRESS},
+        tx_data::{convert_rollback_blocks, convert_rollback_headers},
+    },
+    live_cells::LiveCell,
+};
+
+/// Blocks undone by one rollback transaction
+const ROLLBACK_BATCH: usize = 256;
+
+/// Result of moving a scan tip backwards
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct RewindReport {
+    /// The tip before the rewind
+    pub previous: IndexerTip,
+    pub tip: IndexerTip,
+    /// Rollback transactions sent to axon, zero if no rollback was asked for
+    #[schemars(with = "schema::Uint64")]
+    pub rollback_txs: Uint64,
+    /// Why the rollback stopped short of the requested block. `tip` is how
+    /// far it got, the same rewind called again goes on from there.
+    pub error: Option<String>,
+}
+
+/// Split the blocks to undo, given oldest first, into the batches of one
+/// rollback transaction each, newest batch first. Each batch comes with the
+/// block the tip moves to once it is rolled back, its oldest block, none for
+/// the last one, after which the tip is where the rewind goes.
+pub fn rollback_batches<T>(blocks: &[T], number: impl Fn(&T) -> u64) -> Vec<(&[T], Option<u64>)> {
+    let mut batches = Vec::new();
+    let mut end = blocks.len();
+    while end > 0 {
+        let mut start = end.saturating_sub(ROLLBACK_BATCH);
+        // a block sent under several hashes goes back in one batch, the
+        // tip never lies within it
+        while start > 0 && number(&blocks[start - 1]) == number(&blocks[start]) {
+            start -= 1;
+        }
+        let tip = (start > 0).then(|| number(&blocks[start]));
+        batches.push((&blocks[start..end], tip));
+        end = start;
+    }
+    batches
+}
+
+/// Roll the image cell contract back over the blocks of one batch
+pub async fn rollback_cells(axon_url: &str, submits: &[Submit]) -> Result<()> {
+    send_eth_tx(
+        axon_url,
+        convert_rollback_blocks(submits),
+        IMAGE_CELL_ADDRESS,
+    )
+    .await
+    .context("failed to roll back cells")?;
+    Ok(())
+}
+
+/// Roll the light client contract back over the headers of one batch, given
+/// oldest first
+pub async fn rollback_headers(axon_url: &str, headers: &[(u64, H256)]) -> Result<()> {
+    let hashes = headers.iter().rev().map(|(_, hash)| hash.clone()).collect();
+    send_eth_tx(
+        axon_url,
+        convert_rollback_headers(hashes),
+        CKB_LIGHT_CLIENT_ADDRESS,
+    )
+    .await
+    .context("failed to roll back headers")?;
+    Ok(())
+}
+
+/// The blocks of `key` to undo when its scan moves back from `to` to `from`,
+/// one submit per block ordered by block number.
+///
+/// These are the blocks that were sent, as the archive recorded them. After
+/// a reorg the chain holds other blocks at those heights, they were never
+/// sent and are scanned after the rewind. The chain is only used if the
+/// range was never archived or was pruned.
+pub async fn cells_to_undo<R: Rpc>(
+    archive: &Archive,
+    client: &R,
+    key: &RpcSearchKey,
+    from: u64,
+    to: u64,
+) -> Result<Vec<Submit>> {
+    if !archive.covers_cells(from, to)? {
+        log::warn!(
+            "Cells of blocks {} to {} are not archived, undo them as the chain has them",
+            from,
+            to
+        );
+        if from >= to {
+            return Ok(Vec::new());
+        }
+        return Ok(fetch_submits(client, key.clone(), from.into(), to.into()).await?);
+    }
+
+    // a block sent in several batches is undone once
+    let mut blocks: BTreeMap<(u64, H256), Submit> = BTreeMap::new();
+    for batch in archive.cells(from, u64::MAX)? {
+        if &batch.search_key != key {
+            continue;
+        }
+        for submit in batch.submits {
+            let block = blocks
+                .entry((
+                    submit.header.inner.number.value(),
+                    submit.header.hash.clone(),
+                ))
+                .or_insert_with(|| Submit {
+                    header: submit.header.clone(),
+                    inputs: Vec::new(),
+                    outputs: Vec::new(),
+                });
+            for input in submit.inputs {
+                if !block.inputs.contains(&input) {
+                    block.inputs.push(input);
+                }
+            }
+            for output in submit.outputs {
+                if !block.outputs.iter().any(|(o, _)| o == &output.0) {
+                    block.outputs.push(output);
+                }
+            }
+        }
+    }
+    Ok(blocks.into_values().collect())
+}
+
+/// The numbers and hashes of the headers to undo when header sync moves back
+/// from `to` to `from`, oldest first.
+///
+/// Read from the archive like `cells_to_undo`, which must hold a header for
+/// every block of `[from, to)`. The chain is only used on its own if the
+/// range was never archived or was pruned.
+pub async fn headers_to_undo(
+    archive: &Archive,
+    client: &RpcClient,
+    from: u64,
+    to: u64,
+) -> Result<Vec<(u64, H256)>> {
+    if !archive.covers_headers(from, to)? {
+        log::warn!(
+            "Headers of blocks {} to {} are not archived, undo them as the chain has them",
+            from,
+            to
+        );
+        // look every hash up before sending anything
+        let mut headers = Vec::with_capacity(to.saturating_sub(from) as usize);
+        for number in from..to {
+            headers.push((
+                number,
+                client.get_header_by_number(number.into()).await?.hash,
+            ));
+        }
+        return Ok(headers);
+    }
+
+    // a header sent again after a reorg is undone under both hashes
+    let mut headers = BTreeSet::new();
+    for batch in archive.headers(from, u64::MAX)? {
+        for header in batch {
+            headers.insert((header.inner.inner.number.value(), header.inner.hash));
+        }
+    }
+    let archived = headers.iter().map(|(n, _)| *n).collect::<HashSet<_>>();
+    let missing = (from..to)
+        .filter(|n| !archived.contains(n))
+        .collect::<Vec<_>>();
+    if let Some(first) = missing.first() {
+        return Err(anyhow!(
+            "archive and chain disagree on the headers to undo, {} blocks on the chain were not archived, the first is {}",
+            missing.len(),
+            first
+        ));
+    }
+    Ok(headers.into_iter().collect())
+}
+
+/// The cells consumed by `submits` that were created in block `since` or
+/// later but before `submits`, as they were when created
+pub async fn spent_cells(
+    client: &RpcClient,
+    submits: &[Submit],
+    since: u64,
+) -> Result<Vec<LiveCell>> {
+    let created = submits
+        .iter()
+        .flat_map(|s| s.outputs.iter().map(|(o, _)| o))
+        .collect::<HashSet<&OutPoint>>();
+
+    let mut cells = Vec::new();
+    for input in submits.iter().flat_map(|s| s.inputs.iter()) {
+        if created.contains(input) {
+            continue;
+        }
+        let tx = client.get_transaction_with_status(&input.tx_hash).await?;
+        let transaction = tx
+            .transaction
+            .ok_or_else(|| anyhow!("transaction {:#x} not found", input.tx_hash))?;
+        let block_hash = tx
+            .tx_status
+            .block_hash
+            .ok_or_else(|| anyhow!("transaction {:#x} is not committed", input.tx_hash))?;
+        let header = client
+            .get_header(block_hash.clone())
+            .await?
+            .ok_or_else(|| anyhow!("block {:#x} not found", block_hash))?;
+        if header.inner.number.value() < since {
+            continue;
+        }
+
+        let index = input.index.value() as usize;
+        let output = transaction
+            .inner
+            .outputs
+            .get(index)
+            .cloned()
+            .ok_or_else(|| anyhow!("out point {:#x}:{} not found", input.tx_hash, index))?;
+        cells.push(LiveCell {
+            out_point: input.clone(),
+            output,
+            output_data: transaction.inner.outputs_data.get(index).cloned(),
+            block_number: header.inner.number,
+        });
+    }
+    Ok(cells)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_jsonrpc_types::{
+        BlockNumber, BlockView, CellInfo, HeaderView, JsonBytes, ScriptHashType, TransactionView,
+        Uint32,
+    };
+    use ckb_types::{core, packed, prelude::*};
+    use emitter_core::types::{
+        CellType, Order, Pagination, ScriptType, SearchKey, Tx, TxWithCells,
+    };
+    use std::io;
+
+    use crate::live_cells::LiveCells;
+
+    /// A chain where every transaction creates one cell of the key
+    struct MockChain(Vec<(HeaderView, Vec<TransactionView>)>);
+
+    impl MockChain {
+        fn block(&self, number: u64) -> Option<&(HeaderView, Vec<TransactionView>)> {
+            self.0
+                .iter()
+                .find(|(header, _)| header.inner.number.value() == number)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Rpc for MockChain {
+        async fn get_transactions(
+            &self,
+            search_key: SearchKey,
+            _order: Order,
+            _limit: Uint32,
+            after: Option<JsonBytes>,
+        ) -> Result<Pagination<Tx>, io::Error> {
+            let [from, to] = search_key
+                .filter
+                .and_then(|f| f.block_range)
+                .map(|[from, to]| [from.value(), to.value()])
+                .unwrap_or([0, u64::MAX]);
+            let mut objects = Vec::new();
+            // everything fits on the first page
+            if after.is_none() {
+                for (header, txs) in self.0.iter() {
+                    let number = header.inner.number.value();
+                    if number < from || number >= to {
+                        continue;
+                    }
+                    for (index, tx) in txs.iter().enumerate() {
+                        objects.push(Tx::Grouped(TxWithCells {
+                            tx_hash: tx.hash.clone(),
+                            block_number: header.inner.number,
+                            tx_index: (index as u32).into(),
+                            cells: vec![(CellType::Output, 0.into())],
+                        }));
+                    }
+                }
+            }
+            Ok(Pagination {
+                objects,
+                last_cursor: JsonBytes::default(),
+            })
+        }
+
+        async fn get_transaction(&self, hash: &H256) -> Result<Option<TransactionView>, io::Error> {
+            Ok(self
+                .0
+                .iter()
+                .flat_map(|(_, txs)| txs.iter())
+                .find(|tx| &tx.hash == hash)
+                .cloned())
+        }
+
+        async fn get_header_by_number(&self, number: BlockNumber) -> Result<HeaderView, io::Error> {
+            self.block(number.value())
+                .map(|(header, _)| header.clone())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "block not found"))
+        }
+
+        async fn get_indexer_tip(&self) -> Result<IndexerTip, io::Error> {
+            let (header, _) = self
+                .0
+                .last()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "empty chain"))?;
+            Ok(IndexerTip {
+                block_hash: header.hash.clone(),
+                block_number: header.inner.number,
+            })
+        }
+
+        async fn get_block_by_number(&self, _: BlockNumber) -> Result<BlockView, io::Error> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "blocks are not mocked",
+            ))
+        }
+    }
+
+    fn key() -> RpcSearchKey {
+        RpcSearchKey {
+            script: ckb_jsonrpc_types::Script {
+                code_hash: H256::default(),
+                hash_type: ScriptHashType::Type,
+                args: JsonBytes::from_vec(vec![1]),
+            },
+            script_type: ScriptType::Lock,
+            script_search_mode: None,
+            filter: None,
+            group: Vec::new(),
+        }
+    }
+
+    /// Blocks `numbers` of a fork, the forks differ in every hash
+    fn chain(numbers: std::ops::Range<u64>, fork: u64) -> Vec<(HeaderView, Vec<TransactionView>)> {
+        numbers
+            .map(|number| {
+                let header = packed::Header::new_builder()
+                    .raw(
+                        packed::RawHeader::new_builder()
+                            .number(number.pack())
+                            .build(),
+                    )
+                    .nonce((fork as u128).pack())
+                    .build()
+                    .into_view();
+                let tx = core::TransactionBuilder::default()
+                    .output(
+                        packed::CellOutput::new_builder()
+                            .capacity((number * 100 + fork).pack())
+                            .build(),
+                    )
+                    .output_data(Default::default())
+                    .build();
+                (header.into(), vec![tx.into()])
+            })
+            .collect()
+    }
+
+    fn submits(blocks: &[(HeaderView, Vec<TransactionView>)]) -> Vec<Submit> {
+        blocks
+            .iter()
+            .map(|(header, txs)| Submit {
+                header: header.clone(),
+                inputs: Vec::new(),
+                outputs: txs
+                    .iter()
+                    .map(|tx| {
+                        (
+                            OutPoint {
+                                tx_hash: tx.hash.clone(),
+                                index: 0.into(),
+                            },
+                            CellInfo {
+                                output: tx.inner.outputs[0].clone(),
+                                data: None,
+                            },
+                        )
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    fn hashes(submits: &[Submit]) -> Vec<(u64, H256)> {
+        submits
+            .iter()
+            .map(|s| (s.header.inner.number.value(), s.header.hash.clone()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn undoes_what_was_sent_across_a_reorg() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = Archive::new(dir.path());
+
+        // blocks 10 to 14 were sent, then 12 to 14 were reorged away
+        let sent = chain(10..15, 0);
+        archive.save_cells(&key(), &submits(&sent), None);
+        let mut canonical = chain(10..12, 0);
+        canonical.extend(chain(12..16, 1));
+        let client = MockChain(canonical);
+
+        let undo = cells_to_undo(&archive, &client, &key(), 11, 15)
+            .await
+            .unwrap();
+        assert_eq!(hashes(&undo), hashes(&submits(&sent[1..])));
+
+        // the live cells drop what was sent from the rewind tip on, the
+        // reorged blocks included
+        let live_cells = LiveCells::new(dir.path());
+        let set = live_cells.get_or_load(&key());
+        set.lock().unwrap().apply(&submits(&sent));
+        let applied = IndexerTip {
+            block_hash: sent[0].0.hash.clone(),
+            block_number: 10.into(),
+        };
+        set.lock()
+            .unwrap()
+            .revert(&undo, Vec::new(), Some(applied.clone()));
+        let live = submits(&sent)
+            .into_iter()
+            .flat_map(|s| s.outputs)
+            .filter(|(o, _)| live_cells.lookup(&key(), o.clone()).unwrap().cell.is_some())
+            .count();
+        assert_eq!(live, 1);
+        assert_eq!(
+            live_cells.capacity(&key()).unwrap().tip.unwrap().block_hash,
+            applied.block_hash
+        );
+
+        // the new fork is what the task scans again
+        archive.truncate_cells(&key(), 11).unwrap();
+        let rescanned = fetch_submits(&client, key(), 11.into(), 16.into())
+            .await
+            .unwrap();
+        assert_eq!(hashes(&rescanned), hashes(&submits(&client.0[1..])));
+    }
+
+    #[tokio::test]
+    async fn merges_blocks_sent_in_several_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = Archive::new(dir.path());
+        let blocks = chain(10..12, 0);
+        // block 10 went out in two batches, with one cell each
+        let mut split = submits(&blocks);
+        split[0].outputs[0].0.index = 1.into();
+        archive.save_cells(&key(), &submits(&blocks[..1]), None);
+        archive.save_cells(&key(), &split, None);
+        // block 11 was sent again after a failure
+        archive.save_cells(&key(), &submits(&blocks[1..]), None);
+
+        let client = MockChain(Vec::new());
+        let undo = cells_to_undo(&archive, &client, &key(), 10, 12)
+            .await
+            .unwrap();
+        assert_eq!(hashes(&undo), hashes(&submits(&blocks)));
+        assert_eq!(undo[0].outputs.len(), 2);
+        assert_eq!(undo[1].outputs.len(), 1);
+    }
+
+    #[test]
+    fn rolls_back_newest_batches_first() {
+        // block 344 was sent twice, across where the first batch would start
+        let mut blocks = (0..600u64).collect::<Vec<_>>();
+        blocks.insert(344, 344);
+        let summary = rollback_batches(&blocks, |n| *n)
+            .iter()
+            .map(|(batch, tip)| (batch[0], batch[batch.len() - 1], batch.len(), *tip))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (344, 599, 257, Some(344)),
+                (88, 343, 256, Some(88)),
+                (0, 87, 88, None),
+            ]
+        );
+        assert!(rollback_batches(&[] as &[u64], |n| *n).is_empty());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_chain_without_an_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = Archive::new(dir.path());
+        let client = MockChain(chain(10..15, 1));
+
+        let undo = cells_to_undo(&archive, &client, &key(), 12, 15)
+            .await
+            .unwrap();
+        assert_eq!(hashes(&undo), hashes(&submits(&client.0[2..])));
+        assert!(cells_to_undo(&archive, &client, &key(), 15, 15)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -1,134 +1,1483 @@
-use ckb_jsonrpc_types::BlockNumber;
+use anyhow::anyhow;
+use ckb_jsonrpc_types::{BlockNumber, JsonBytes, OutPoint, Uint32, Uint64};
 use emitter_core::{
-    cell_process::CellProcess,
+    address::{resolve_search_key, NetworkType},
+    cell_process::fetch_submits,
     rpc_client::RpcClient,
-    types::{IndexerTip, RpcSearchKey},
+    schema,
+    types::{
+        IndexerScriptSearchMode, IndexerTip, RpcSearchKey, RpcSearchKeyFilter, RpcSearchKeyParam,
+    },
+    Submit, TipState,
 };
 use jsonrpsee::{
     core::{async_trait, Error},
     proc_macros::rpc,
+    types::error::CallError,
 };
 
-use std::sync::{
-    atomic::{AtomicPtr, Ordering},
-    Arc,
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicPtr, Ordering},
+        Arc,
+    },
 };
+use tokio::sync::Notify;
+
+/// Most blocks one `preview` call scans
+const PREVIEW_MAX_BLOCKS: u64 = 10_000;
+
+use crate::{
+    archive::{self, HistoryPage, ReplayReport},
+    emit_data::tx_data::convert_blocks,
+    global_state::{
+        spawn_cell_process, spawn_header_sync_process, HeaderHandle, Registration, ScanTask, State,
+    },
+    live_cells::{LiveCellLookup, LiveCellsCapacity, LiveCellsPage},
+    rewind::{self, RewindReport},
+    snapshot::{self, ImportMode, ImportReport, Snapshot},
+    status::{now_ms, target_tip, HeaderSyncState, HeaderSyncStatus, StatusReport, TaskStats},
+    ScanTip, ScanTipInner, SubmitContext,
+};
+
+/// Caller supplied part of a registration's metadata
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+pub struct RegisterMeta {
+    pub label: Option<String>,
+    pub owner: Option<String>,
+    pub notes: Option<String>,
+}
+
+/// One registration of `register_batch`, with the parameters of `register`
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct RegisterItem {
+    pub search_key: RpcSearchKeyParam,
+    #[schemars(with = "schema::Uint64")]
+    pub start: BlockNumber,
+    pub meta: Option<RegisterMeta>,
+    #[schemars(with = "Option<schema::Uint64>")]
+    pub end: Option<BlockNumber>,
+}
+
+/// Outcome of one item of a batch, in the order given
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct BatchItem {
+    /// Null if the key could not be decoded
+    pub search_key: Option<RpcSearchKey>,
+    /// False for keys already registered, or not registered on delete, and
+    /// for every item of a rejected batch
+    pub applied: bool,
+    /// Why the item is invalid
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct BatchReport {
+    /// False if some item is invalid, nothing is changed then
+    pub applied: bool,
+    pub items: Vec<BatchItem>,
+}
+
+impl BatchReport {
+    fn rejected(mut items: Vec<BatchItem>) -> Self {
+        for item in items.iter_mut() {
+            item.applied = false;
+        }
+        BatchReport {
+            applied: false,
+            items,
+        }
+    }
+}
+
+/// Only return registrations matching all of the given fields
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+pub struct InfoFilter {
+    pub label: Option<String>,
+    pub owner: Option<String>,
+}
 
-use crate::{global_state::State, RpcSubmit, ScanTip, ScanTipInner};
+impl InfoFilter {
+    fn matches(&self, registration: &Registration) -> bool {
+        (self.label.is_none() || self.label == registration.label)
+            && (self.owner.is_none() || self.owner == registration.owner)
+    }
+}
+
+/// Result of changing the filter or search mode of a registration
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct UpdateReport {
+    /// The key the registration is known by from now on
+    pub search_key: RpcSearchKey,
+    /// The tip the new version is scanned from
+    pub tip: IndexerTip,
+}
 
-#[rpc(server)]
+/// What a registration would submit for a block range
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct Preview {
+    pub submits: Vec<Submit>,
+    /// Image cell contract calldata for all submits in a single call, only
+    /// if asked for
+    #[schemars(with = "Option<schema::JsonBytes>")]
+    pub calldata: Option<JsonBytes>,
+    #[schemars(with = "Option<schema::Uint64>")]
+    pub calldata_size: Option<Uint64>,
+}
+
+#[cfg_attr(feature = "client", rpc(server, client))]
+#[cfg_attr(not(feature = "client"), rpc(server))]
 pub trait Emitter {
     #[method(name = "register")]
-    async fn register(&self, search_key: RpcSearchKey, start: BlockNumber) -> Result<bool, Error>;
+    async fn register(
+        &self,
+        search_key: RpcSearchKeyParam,
+        start: BlockNumber,
+        meta: Option<RegisterMeta>,
+        end: Option<BlockNumber>,
+    ) -> Result<bool, Error>;
+
+    #[method(name = "register_batch")]
+    async fn register_batch(&self, items: Vec<RegisterItem>) -> Result<BatchReport, Error>;
 
     #[method(name = "delete")]
     async fn delete(&self, search_key: RpcSearchKey) -> Result<bool, Error>;
 
+    #[method(name = "delete_batch")]
+    async fn delete_batch(&self, search_keys: Vec<RpcSearchKey>) -> Result<BatchReport, Error>;
+
+    #[method(name = "update")]
+    async fn update(
+        &self,
+        search_key: RpcSearchKey,
+        filter: Option<RpcSearchKeyFilter>,
+        script_search_mode: Option<IndexerScriptSearchMode>,
+        rescan_from: Option<BlockNumber>,
+    ) -> Result<UpdateReport, Error>;
+
     #[method(name = "info")]
-    async fn info(&self) -> Result<State, Error>;
+    async fn info(&self, filter: Option<InfoFilter>) -> Result<State, Error>;
 
     #[method(name = "header_sync_start")]
-    async fn header_sync_start(&self, number: BlockNumber) -> Result<bool, Error>;
+    async fn header_sync_start(&self, number: Option<BlockNumber>) -> Result<bool, Error>;
+
+    #[method(name = "export_state")]
+    async fn export_state(&self) -> Result<Snapshot, Error>;
+
+    #[method(name = "import_state")]
+    async fn import_state(
+        &self,
+        snapshot: Snapshot,
+        mode: Option<ImportMode>,
+    ) -> Result<ImportReport, Error>;
+
+    #[method(name = "get_live_cells")]
+    async fn get_live_cells(
+        &self,
+        search_key: RpcSearchKey,
+        limit: Uint32,
+        after: Option<OutPoint>,
+    ) -> Result<LiveCellsPage, Error>;
+
+    #[method(name = "get_live_cells_capacity")]
+    async fn get_live_cells_capacity(
+        &self,
+        search_key: RpcSearchKey,
+    ) -> Result<LiveCellsCapacity, Error>;
+
+    #[method(name = "get_live_cell")]
+    async fn get_live_cell(
+        &self,
+        search_key: RpcSearchKey,
+        out_point: OutPoint,
+    ) -> Result<LiveCellLookup, Error>;
+
+    #[method(name = "pause")]
+    async fn pause(&self, search_key: Option<RpcSearchKey>) -> Result<Vec<RpcSearchKey>, Error>;
+
+    #[method(name = "resume")]
+    async fn resume(&self, search_key: Option<RpcSearchKey>) -> Result<Vec<RpcSearchKey>, Error>;
+
+    #[method(name = "header_sync_pause")]
+    async fn header_sync_pause(&self) -> Result<bool, Error>;
+
+    #[method(name = "header_sync_resume")]
+    async fn header_sync_resume(&self) -> Result<bool, Error>;
+
+    #[method(name = "rewind")]
+    async fn rewind(
+        &self,
+        search_key: RpcSearchKey,
+        number: BlockNumber,
+        rollback: Option<bool>,
+    ) -> Result<RewindReport, Error>;
+
+    #[method(name = "header_sync_rewind")]
+    async fn header_sync_rewind(
+        &self,
+        number: BlockNumber,
+        rollback: Option<bool>,
+    ) -> Result<RewindReport, Error>;
+
+    #[method(name = "status")]
+    async fn status(&self, search_key: Option<RpcSearchKey>) -> Result<StatusReport, Error>;
+
+    #[method(name = "replay")]
+    async fn replay(
+        &self,
+        from: BlockNumber,
+        to: BlockNumber,
+        axon_url: Option<String>,
+    ) -> Result<ReplayReport, Error>;
+
+    #[method(name = "preview")]
+    async fn preview(
+        &self,
+        search_key: RpcSearchKey,
+        from: BlockNumber,
+        to: BlockNumber,
+        calldata: Option<bool>,
+    ) -> Result<Preview, Error>;
+
+    #[method(name = "history")]
+    async fn history(
+        &self,
+        search_key: RpcSearchKey,
+        from: BlockNumber,
+        to: BlockNumber,
+        limit: Uint32,
+        after: Option<BlockNumber>,
+    ) -> Result<HistoryPage, Error>;
+
+    #[method(name = "header_sync_stop")]
+    async fn header_sync_stop(&self) -> Result<bool, Error>;
+
+    #[method(name = "header_sync_restart")]
+    async fn header_sync_restart(&self) -> Result<bool, Error>;
+
+    #[method(name = "header_sync_status")]
+    async fn header_sync_status(&self) -> Result<HeaderSyncStatus, Error>;
 }
 
+#[derive(Clone)]
 pub(crate) struct EmitterRpc {
     pub state: State,
-    pub cell_handles: Arc<dashmap::DashMap<RpcSearchKey, tokio::task::JoinHandle<()>>>,
+    pub cell_handles: Arc<dashmap::DashMap<RpcSearchKey, ScanTask>>,
+    pub header_handle: HeaderHandle,
+    /// Asks `GlobalState` to save the state now
+    pub save: Arc<Notify>,
     pub client: RpcClient,
-    pub axon_url: String,
+    pub ctx: SubmitContext,
 }
 
 #[async_trait]
 impl EmitterServer for EmitterRpc {
-    async fn register(&self, search_key: RpcSearchKey, start: BlockNumber) -> Result<bool, Error> {
+    async fn register(
+        &self,
+        search_key: RpcSearchKeyParam,
+        start: BlockNumber,
+        meta: Option<RegisterMeta>,
+        end: Option<BlockNumber>,
+    ) -> Result<bool, Error> {
+        let search_key = resolve_search_key(&self.client, search_key)
+            .await
+            .map_err(|e| Error::Custom(e.to_string()))?;
         if self.state.cell_states.contains_key(&search_key) {
             return Ok(false);
         }
+        check_end(start, end).map_err(invalid_params)?;
         let indexer_tip = self
             .client
             .get_indexer_tip()
             .await
             .map_err(|e| Error::Custom(e.to_string()))?;
+        if start >= indexer_tip.block_number {
+            return Err(invalid_params(format!(
+                "start block {} is not below the indexer tip {}",
+                start.value(),
+                indexer_tip.block_number.value()
+            )));
+        }
+
+        let header = self
+            .client
+            .get_header_by_number(start)
+            .await
+            .map_err(|e| Error::Custom(e.to_string()))?;
+
+        let scan_tip = {
+            let tip = IndexerTip {
+                block_hash: header.hash,
+                block_number: header.inner.number,
+            };
+            ScanTip(Arc::new(ScanTipInner(AtomicPtr::new(Box::into_raw(
+                Box::new(tip),
+            )))))
+        };
+
+        let registration = new_registration(meta, header.inner.number, end);
+
+        self.state
+            .registrations
+            .insert(search_key.clone(), registration);
+        self.state
+            .cell_states
+            .insert(search_key.clone(), scan_tip.clone());
 
-        if indexer_tip.block_number > start {
-            let header = self
+        let handle = spawn_cell_process(
+            search_key.clone(),
+            scan_tip,
+            end,
+            self.client.clone(),
+            &self.ctx,
+            self.save.clone(),
+        );
+
+        self.cell_handles.insert(search_key, handle);
+        self.save.notify_one();
+        Ok(true)
+    }
+
+    async fn register_batch(&self, items: Vec<RegisterItem>) -> Result<BatchReport, Error> {
+        let indexer_tip = self
+            .client
+            .get_indexer_tip()
+            .await
+            .map_err(|e| Error::Custom(e.to_string()))?;
+        let network = if items.iter().any(|i| i.search_key.has_address()) {
+            let info = self
                 .client
-                .get_header_by_number(start)
+                .get_blockchain_info()
                 .await
                 .map_err(|e| Error::Custom(e.to_string()))?;
+            NetworkType::from_chain(&info.chain)
+        } else {
+            // not looked at
+            NetworkType::Mainnet
+        };
+
+        // validate everything before touching the state
+        let mut headers = HashMap::new();
+        let mut seen = HashSet::new();
+        let mut results = Vec::with_capacity(items.len());
+        let mut valid = Vec::new();
+        for item in items {
+            let (search_key, checked) = self
+                .check_register_item(item, &indexer_tip, network, &mut headers, &mut seen)
+                .await;
+            let (applied, error) = match checked {
+                Ok(Some(new)) => {
+                    valid.push((results.len(), new));
+                    (true, None)
+                }
+                Ok(None) => (false, None),
+                Err(e) => (false, Some(e)),
+            };
+            results.push(BatchItem {
+                search_key,
+                applied,
+                error,
+            });
+        }
+        if results.iter().any(|r| r.error.is_some()) {
+            return Ok(BatchReport::rejected(results));
+        }
 
-            let scan_tip = {
-                let tip = IndexerTip {
+        let mut inserted = Vec::with_capacity(valid.len());
+        for (index, (key, tip, registration)) in valid {
+            let vacant = match self.state.cell_states.entry(key.clone()) {
+                dashmap::mapref::entry::Entry::Occupied(_) => false,
+                dashmap::mapref::entry::Entry::Vacant(entry) => {
+                    self.state.registrations.insert(key.clone(), registration);
+                    entry.insert(ScanTip::from(tip));
+                    true
+                }
+            };
+            if !vacant {
+                // registered in the meantime, take back what this batch added
+                for key in inserted {
+                    self.state.cell_states.remove(&key);
+                    self.state.registrations.remove(&key);
+                }
+                results[index].error = Some("search key was registered concurrently".to_string());
+                return Ok(BatchReport::rejected(results));
+            }
+            inserted.push(key);
+        }
+        for key in inserted.iter() {
+            self.spawn_cells(key);
+        }
+        self.save.notify_one();
+        Ok(BatchReport {
+            applied: true,
+            items: results,
+        })
+    }
+
+    async fn delete(&self, search_key: RpcSearchKey) -> Result<bool, Error> {
+        let removed = self.remove_registration(&search_key).await;
+        self.save.notify_one();
+        Ok(removed)
+    }
+
+    async fn delete_batch(&self, search_keys: Vec<RpcSearchKey>) -> Result<BatchReport, Error> {
+        let mut seen = HashSet::new();
+        let mut results = search_keys
+            .into_iter()
+            .map(|key| BatchItem {
+                error: (!seen.insert(key.clone())).then(|| "duplicate search key".to_string()),
+                search_key: Some(key),
+                applied: false,
+            })
+            .collect::<Vec<_>>();
+        if results.iter().any(|r| r.error.is_some()) {
+            return Ok(BatchReport::rejected(results));
+        }
+
+        for item in results.iter_mut() {
+            if let Some(ref key) = item.search_key {
+                item.applied = self.remove_registration(key).await;
+            }
+        }
+        self.save.notify_one();
+        Ok(BatchReport {
+            applied: true,
+            items: results,
+        })
+    }
+
+    async fn update(
+        &self,
+        search_key: RpcSearchKey,
+        filter: Option<RpcSearchKeyFilter>,
+        script_search_mode: Option<IndexerScriptSearchMode>,
+        rescan_from: Option<BlockNumber>,
+    ) -> Result<UpdateReport, Error> {
+        self.scan_tip(&search_key)?;
+        let new_key = RpcSearchKey {
+            filter,
+            script_search_mode,
+            ..search_key.clone()
+        };
+        if new_key == search_key {
+            return Err(Error::Custom(
+                "filter and search mode are unchanged".to_string(),
+            ));
+        }
+        if self.state.cell_states.contains_key(&new_key) {
+            return Err(Error::Custom(
+                "updated search key is already registered".to_string(),
+            ));
+        }
+        let rescan_tip = match rescan_from {
+            Some(number) => {
+                let header = self.client.get_header_by_number(number).await?;
+                Some(IndexerTip {
                     block_hash: header.hash,
                     block_number: header.inner.number,
-                };
-                ScanTip(Arc::new(ScanTipInner(AtomicPtr::new(Box::into_raw(
-                    Box::new(tip),
-                )))))
+                })
+            }
+            None => None,
+        };
+
+        self.stop_cells(&search_key).await;
+        let res = self.update_key(&search_key, &new_key, rescan_tip).await;
+        match res {
+            Ok(_) => self.spawn_cells(&new_key),
+            Err(_) => self.spawn_cells(&search_key),
+        }
+        self.save.notify_one();
+        res.map_err(|e| Error::Custom(format!("{:#}", e)))
+    }
+
+    async fn info(&self, filter: Option<InfoFilter>) -> Result<State, Error> {
+        match filter {
+            Some(filter) => Ok(self.state.filter(|_, r| filter.matches(r))),
+            None => Ok(self.state.clone()),
+        }
+    }
+
+    async fn header_sync_start(&self, number: Option<BlockNumber>) -> Result<bool, Error> {
+        if self.header_handle.lock().unwrap().disabled {
+            return Err(header_sync_disabled());
+        }
+        let number = match number {
+            Some(number) => number,
+            None => {
+                let mut header = self.header_handle.lock().unwrap();
+                header.stopped = false;
+                if header.task.as_ref().is_some_and(|t| !t.is_finished()) {
+                    return Ok(false);
+                }
+                drop(header);
+                return Ok(self.spawn_header_sync());
+            }
+        };
+        if number < self.state.header_state.tip().load().block_number {
+            return Ok(false);
+        }
+        let new_header = self.client.get_header_by_number(number).await?;
+
+        self.stop_header_sync().await;
+        self.header_handle.lock().unwrap().stopped = false;
+        // checked again, the tip may have moved while the task was stopping
+        let moved = number >= self.state.header_state.tip().load().block_number;
+        if moved {
+            // the task is gone, nothing else updates the tip now
+            self.state.header_state.reset(IndexerTip {
+                block_hash: new_header.hash,
+                block_number: new_header.inner.number,
+            });
+        }
+        self.spawn_header_sync();
+        Ok(moved)
+    }
+
+    async fn export_state(&self) -> Result<Snapshot, Error> {
+        Snapshot::new(&self.state).map_err(|e| Error::Custom(e.to_string()))
+    }
+
+    async fn import_state(
+        &self,
+        snapshot: Snapshot,
+        mode: Option<ImportMode>,
+    ) -> Result<ImportReport, Error> {
+        let imported = snapshot
+            .into_state()
+            .map_err(|e| Error::Custom(format!("{:#}", e)))?;
+        snapshot::validate(&imported, &self.client)
+            .await
+            .map_err(|e| Error::Custom(format!("{:#}", e)))?;
+
+        let mode = mode.unwrap_or(ImportMode::Merge);
+        // replace drops every registration and takes the header tip from the
+        // snapshot, nothing may go on scanning from the old tips
+        if mode == ImportMode::Replace {
+            self.stop_header_sync().await;
+            let keys = self
+                .cell_handles
+                .iter()
+                .map(|kv| kv.key().clone())
+                .collect::<Vec<_>>();
+            for key in keys {
+                self.stop_cells(&key).await;
+            }
+        }
+        let report = snapshot::import_into(&self.state, imported, mode);
+        if mode == ImportMode::Replace {
+            self.spawn_header_sync();
+        }
+        let added = report.added.iter().collect::<HashSet<_>>();
+        for key in report.removed.iter() {
+            // registered again from the snapshot, the cells sent for it stay
+            if added.contains(key) {
+                continue;
+            }
+            self.ctx.live_cells.remove(key);
+            self.ctx.stats.remove(key);
+        }
+        for key in report.added.iter() {
+            self.spawn_cells(key);
+        }
+        self.save.notify_one();
+        Ok(report)
+    }
+
+    async fn get_live_cells(
+        &self,
+        search_key: RpcSearchKey,
+        limit: Uint32,
+        after: Option<OutPoint>,
+    ) -> Result<LiveCellsPage, Error> {
+        self.scan_tip(&search_key)?;
+        self.ctx
+            .live_cells
+            .page(&search_key, limit.value() as usize, after)
+            .ok_or_else(not_registered)
+    }
+
+    async fn get_live_cells_capacity(
+        &self,
+        search_key: RpcSearchKey,
+    ) -> Result<LiveCellsCapacity, Error> {
+        self.scan_tip(&search_key)?;
+        self.ctx
+            .live_cells
+            .capacity(&search_key)
+            .ok_or_else(not_registered)
+    }
+
+    async fn get_live_cell(
+        &self,
+        search_key: RpcSearchKey,
+        out_point: OutPoint,
+    ) -> Result<LiveCellLookup, Error> {
+        self.scan_tip(&search_key)?;
+        self.ctx
+            .live_cells
+            .lookup(&search_key, out_point)
+            .ok_or_else(not_registered)
+    }
+
+    async fn pause(&self, search_key: Option<RpcSearchKey>) -> Result<Vec<RpcSearchKey>, Error> {
+        let mut paused = Vec::new();
+        for key in self.target_keys(search_key)? {
+            // stop the task before flagging the key, so that a concurrent
+            // resume can not start a second task on the same tip
+            self.stop_cells(&key).await;
+            if let Some(mut registration) = self.state.registrations.get_mut(&key) {
+                if !registration.paused {
+                    registration.paused = true;
+                    paused.push(key);
+                }
+            }
+        }
+        self.save.notify_one();
+        Ok(paused)
+    }
+
+    async fn resume(&self, search_key: Option<RpcSearchKey>) -> Result<Vec<RpcSearchKey>, Error> {
+        let mut resumed = Vec::new();
+        for key in self.target_keys(search_key)? {
+            let was_paused = match self.state.registrations.get_mut(&key) {
+                Some(mut registration) => std::mem::replace(&mut registration.paused, false),
+                None => false,
             };
+            if !was_paused {
+                continue;
+            }
+            self.spawn_cells(&key);
+            resumed.push(key);
+        }
+        self.save.notify_one();
+        Ok(resumed)
+    }
 
-            self.state
-                .cell_states
-                .insert(search_key.clone(), scan_tip.clone());
+    async fn header_sync_pause(&self) -> Result<bool, Error> {
+        self.stop_header_sync().await;
+        let paused = !self.state.header_paused.swap(true, Ordering::AcqRel);
+        self.save.notify_one();
+        Ok(paused)
+    }
+
+    async fn header_sync_resume(&self) -> Result<bool, Error> {
+        if !self.state.header_paused.swap(false, Ordering::AcqRel) {
+            return Ok(false);
+        }
+        self.spawn_header_sync();
+        self.save.notify_one();
+        Ok(true)
+    }
+
+    async fn rewind(
+        &self,
+        search_key: RpcSearchKey,
+        number: BlockNumber,
+        rollback: Option<bool>,
+    ) -> Result<RewindReport, Error> {
+        self.scan_tip(&search_key)?;
+        let header = self.client.get_header_by_number(number).await?;
+        let tip = IndexerTip {
+            block_hash: header.hash,
+            block_number: header.inner.number,
+        };
 
-            let mut cell_process = CellProcess::new(
-                search_key.clone(),
-                scan_tip,
+        self.stop_cells(&search_key).await;
+        let res = self
+            .rewind_cells(&search_key, tip, rollback.unwrap_or(false))
+            .await;
+        // on failure the task goes on from the tip it stopped at
+        self.spawn_cells(&search_key);
+        self.save.notify_one();
+        res.map_err(|e| Error::Custom(format!("{:#}", e)))
+    }
+
+    async fn header_sync_rewind(
+        &self,
+        number: BlockNumber,
+        rollback: Option<bool>,
+    ) -> Result<RewindReport, Error> {
+        let header = self.client.get_header_by_number(number).await?;
+        let tip = IndexerTip {
+            block_hash: header.hash,
+            block_number: header.inner.number,
+        };
+
+        self.stop_header_sync().await;
+        let res = self.rewind_headers(tip, rollback.unwrap_or(false)).await;
+        self.spawn_header_sync();
+        self.save.notify_one();
+        res.map_err(|e| Error::Custom(format!("{:#}", e)))
+    }
+
+    async fn status(&self, search_key: Option<RpcSearchKey>) -> Result<StatusReport, Error> {
+        let indexer_tip = match self.client.get_indexer_tip().await {
+            Ok(tip) => Some(tip),
+            Err(e) => {
+                log::warn!("Failed to get indexer tip for status, error: {:?}", e);
+                None
+            }
+        };
+
+        let header_sync = self.header_status(indexer_tip.as_ref());
+
+        let mut registrations = Vec::new();
+        for kv in self.state.cell_states.iter() {
+            let key = kv.key();
+            if search_key.as_ref().map(|k| k != key).unwrap_or(false) {
+                continue;
+            }
+            let tip = kv.value().load().clone();
+            let running = self
+                .cell_handles
+                .get(key)
+                .map(|h| !h.is_finished())
+                .unwrap_or(false);
+            let (paused, end, completed) = match self.state.registrations.get(key) {
+                Some(r) => (r.paused, r.end_block, r.completed.clone()),
+                None => (false, None, None),
+            };
+            let target = target_tip(indexer_tip.as_ref(), end);
+            let mut status = match self.ctx.stats.cells(key) {
+                Some(stats) => stats.lock().unwrap().status(tip, target, running, paused),
+                None => TaskStats::default().status(tip, target, running, paused),
+            };
+            status.completed = completed;
+            registrations.push((key.clone(), status));
+        }
+        if search_key.is_some() && registrations.is_empty() {
+            return Err(not_registered());
+        }
+
+        Ok(StatusReport {
+            indexer_tip,
+            header_sync,
+            registrations,
+        })
+    }
+
+    async fn replay(
+        &self,
+        from: BlockNumber,
+        to: BlockNumber,
+        axon_url: Option<String>,
+    ) -> Result<ReplayReport, Error> {
+        let axon_url = axon_url.unwrap_or_else(|| self.ctx.axon_url.clone());
+        archive::replay(&self.ctx.archive, from.value(), to.value(), &axon_url)
+            .await
+            .map_err(|e| Error::Custom(format!("{:#}", e)))
+    }
+
+    async fn preview(
+        &self,
+        search_key: RpcSearchKey,
+        from: BlockNumber,
+        to: BlockNumber,
+        calldata: Option<bool>,
+    ) -> Result<Preview, Error> {
+        if from > to {
+            return Err(Error::Custom(format!(
+                "invalid block range, from {} is above to {}",
+                from.value(),
+                to.value()
+            )));
+        }
+        if to.value() - from.value() >= PREVIEW_MAX_BLOCKS {
+            return Err(Error::Custom(format!(
+                "block range too large, at most {} blocks can be previewed",
+                PREVIEW_MAX_BLOCKS
+            )));
+        }
+        // blocks the indexer has not seen yet would come back empty
+        let indexer_tip = self.client.get_indexer_tip().await?.block_number.value();
+        let end = std::cmp::min(to.value(), indexer_tip)
+            .checked_add(1)
+            .ok_or_else(|| Error::Custom("block number overflow".to_string()))?;
+        let submits = if end > from.value() {
+            fetch_submits(&self.client, search_key, from, end.into())
+                .await
+                .map_err(|e| Error::Custom(e.to_string()))?
+        } else {
+            Vec::new()
+        };
+
+        let (calldata, calldata_size) = if calldata.unwrap_or(false) {
+            let data = convert_blocks(&submits);
+            let size = data.len() as u64;
+            (Some(JsonBytes::from_vec(data)), Some(size.into()))
+        } else {
+            (None, None)
+        };
+        Ok(Preview {
+            submits,
+            calldata,
+            calldata_size,
+        })
+    }
+
+    async fn history(
+        &self,
+        search_key: RpcSearchKey,
+        from: BlockNumber,
+        to: BlockNumber,
+        limit: Uint32,
+        after: Option<BlockNumber>,
+    ) -> Result<HistoryPage, Error> {
+        if from > to {
+            return Err(Error::Custom(format!(
+                "invalid block range, from {} is above to {}",
+                from.value(),
+                to.value()
+            )));
+        }
+        self.ctx
+            .archive
+            .history(
+                &search_key,
+                from.value(),
+                to.value(),
+                limit.value() as usize,
+                after.map(|a| a.value()),
+            )
+            .map_err(|e| Error::Custom(format!("{:#}", e)))
+    }
+
+    async fn header_sync_stop(&self) -> Result<bool, Error> {
+        let task = {
+            let mut header = self.header_handle.lock().unwrap();
+            header.stopped = true;
+            header.task.take()
+        };
+        match task {
+            Some(task) => {
+                let running = !task.is_finished();
+                task.stop().await;
+                Ok(running)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn header_sync_restart(&self) -> Result<bool, Error> {
+        if self.header_handle.lock().unwrap().disabled {
+            return Err(header_sync_disabled());
+        }
+        self.stop_header_sync().await;
+        self.header_handle.lock().unwrap().stopped = false;
+        Ok(self.spawn_header_sync())
+    }
+
+    async fn header_sync_status(&self) -> Result<HeaderSyncStatus, Error> {
+        let indexer_tip = match self.client.get_indexer_tip().await {
+            Ok(tip) => Some(tip),
+            Err(e) => {
+                log::warn!("Failed to get indexer tip for status, error: {:?}", e);
+                None
+            }
+        };
+        Ok(self.header_status(indexer_tip.as_ref()))
+    }
+}
+
+impl EmitterRpc {
+    /// Check one item of a batch registration. Gives the tip and registration
+    /// to add, none if the key is already registered.
+    async fn check_register_item(
+        &self,
+        item: RegisterItem,
+        indexer_tip: &IndexerTip,
+        network: NetworkType,
+        headers: &mut HashMap<u64, IndexerTip>,
+        seen: &mut HashSet<RpcSearchKey>,
+    ) -> (
+        Option<RpcSearchKey>,
+        Result<Option<(RpcSearchKey, IndexerTip, Registration)>, String>,
+    ) {
+        let key = match item.search_key.resolve(network) {
+            Ok(key) => key,
+            Err(e) => return (None, Err(e.to_string())),
+        };
+        if !seen.insert(key.clone()) {
+            return (Some(key), Err("duplicate search key".to_string()));
+        }
+        if self.state.cell_states.contains_key(&key) {
+            return (Some(key), Ok(None));
+        }
+        if let Err(e) = check_end(item.start, item.end) {
+            return (Some(key), Err(e));
+        }
+        if item.start >= indexer_tip.block_number {
+            return (
+                Some(key),
+                Err(format!(
+                    "start block {} is not below the indexer tip {}",
+                    item.start.value(),
+                    indexer_tip.block_number.value()
+                )),
+            );
+        }
+
+        let tip = match headers.get(&item.start.value()) {
+            Some(tip) => tip.clone(),
+            None => match self.client.get_header_by_number(item.start).await {
+                Ok(header) => {
+                    let tip = IndexerTip {
+                        block_hash: header.hash,
+                        block_number: header.inner.number,
+                    };
+                    headers.insert(item.start.value(), tip.clone());
+                    tip
+                }
+                Err(e) => return (Some(key), Err(e.to_string())),
+            },
+        };
+        let registration = new_registration(item.meta, tip.block_number, item.end);
+        (Some(key.clone()), Ok(Some((key, tip, registration))))
+    }
+
+    /// Drop a registration with everything kept for it, its archived
+    /// batches included, false if the key is not registered
+    async fn remove_registration(&self, key: &RpcSearchKey) -> bool {
+        if !self.state.cell_states.contains_key(key) {
+            return false;
+        }
+        // nothing may be sent or written for the key once it is gone
+        self.stop_cells(key).await;
+        if self.state.cell_states.remove(key).is_none() {
+            return false;
+        }
+        self.state.registrations.remove(key);
+        self.ctx.live_cells.remove(key);
+        self.ctx.stats.remove(key);
+        if let Err(e) = self.ctx.archive.remove_cells(key) {
+            log::warn!("Failed to remove archived cells, error: {:#}", e);
+        }
+        true
+    }
+
+    /// Spawn the scan task of `key`, unless it is paused, finished or gone
+    fn spawn_cells(&self, key: &RpcSearchKey) {
+        if self.state.is_idle(key) {
+            return;
+        }
+        if let Some(tip) = self.state.cell_states.get(key) {
+            let task = spawn_cell_process(
+                key.clone(),
+                tip.value().clone(),
+                self.state.end_block(key),
                 self.client.clone(),
-                RpcSubmit {
-                    axon_url: self.axon_url.clone(),
-                },
+                &self.ctx,
+                self.save.clone(),
             );
+            self.cell_handles.insert(key.clone(), task);
+        }
+    }
 
-            let handle = tokio::spawn(async move {
-                cell_process.run().await;
-            });
+    /// Stop the scan task of `key` and wait for it to exit
+    async fn stop_cells(&self, key: &RpcSearchKey) {
+        if let Some((_, task)) = self.cell_handles.remove(key) {
+            task.stop().await;
+        }
+    }
 
-            self.cell_handles.insert(search_key, handle);
-            return Ok(true);
+    /// Spawn header sync, unless it is paused, stopped or disabled. Returns
+    /// whether it was spawned.
+    fn spawn_header_sync(&self) -> bool {
+        let mut header = self.header_handle.lock().unwrap();
+        if header.disabled || header.stopped || self.state.header_paused.load(Ordering::Acquire) {
+            return false;
         }
+        header.task = Some(spawn_header_sync_process(
+            self.state.header_state.tip(),
+            self.client.clone(),
+            &self.ctx,
+        ));
+        true
+    }
 
-        Ok(false)
+    /// Stop header sync and wait for it to exit
+    async fn stop_header_sync(&self) {
+        let task = self.header_handle.lock().unwrap().task.take();
+        if let Some(task) = task {
+            task.stop().await;
+        }
     }
 
-    async fn delete(&self, search_key: RpcSearchKey) -> Result<bool, Error> {
-        if self.state.cell_states.remove(&search_key).is_some() {
-            if let Some(handle) = self.cell_handles.get(&search_key) {
-                handle.abort();
-                return Ok(true);
-            }
+    fn header_status(&self, indexer_tip: Option<&IndexerTip>) -> HeaderSyncStatus {
+        let header = self.header_handle.lock().unwrap();
+        let running = header.task.as_ref().is_some_and(|t| !t.is_finished());
+        let paused = self.state.header_paused.load(Ordering::Acquire);
+        let state = if header.disabled {
+            HeaderSyncState::Disabled
+        } else if paused {
+            HeaderSyncState::Paused
+        } else if header.stopped {
+            HeaderSyncState::Stopped
+        } else if running {
+            HeaderSyncState::Running
+        } else {
+            HeaderSyncState::Dead
+        };
+        drop(header);
+
+        HeaderSyncStatus {
+            state,
+            task: self.ctx.stats.headers().lock().unwrap().status(
+                self.state.header_state.tip().load().clone(),
+                target_tip(indexer_tip, None),
+                running,
+                paused,
+            ),
         }
-        Ok(false)
     }
 
-    async fn info(&self) -> Result<State, Error> {
-        Ok(self.state.clone())
+    /// Move a registration with a stopped task over to `new_key`, scanning
+    /// again from `rescan_tip` if given
+    async fn update_key(
+        &self,
+        key: &RpcSearchKey,
+        new_key: &RpcSearchKey,
+        rescan_tip: Option<IndexerTip>,
+    ) -> anyhow::Result<UpdateReport> {
+        let previous = match self.state.cell_states.get(key) {
+            Some(scan_tip) => scan_tip.load().clone(),
+            None => return Err(anyhow!("search key is not registered")),
+        };
+        if let Some(ref tip) = rescan_tip {
+            if tip.block_number > previous.block_number {
+                return Err(anyhow!(
+                    "block {} is above the current tip {}",
+                    tip.block_number.value(),
+                    previous.block_number.value()
+                ));
+            }
+            // undo what the old version submitted past the block, so that the
+            // live cells are those axon has seen up to it
+            if tip.block_number < previous.block_number {
+                self.rewind_cells(key, tip.clone(), false).await?;
+            }
+        }
+
+        let (_, scan_tip) = self
+            .state
+            .cell_states
+            .remove(key)
+            .ok_or_else(|| anyhow!("search key is not registered"))?;
+        // no other shard lock may be held while the entry is, both keys may
+        // share one
+        let vacant = match self.state.cell_states.entry(new_key.clone()) {
+            dashmap::mapref::entry::Entry::Occupied(_) => false,
+            dashmap::mapref::entry::Entry::Vacant(entry) => {
+                entry.insert(scan_tip.clone());
+                true
+            }
+        };
+        if !vacant {
+            self.state.cell_states.insert(key.clone(), scan_tip);
+            return Err(anyhow!("updated search key is already registered"));
+        }
+        let tip = scan_tip.load().clone();
+        let mut registration = self
+            .state
+            .registrations
+            .remove(key)
+            .map(|(_, r)| r)
+            .unwrap_or_default();
+        registration.push_version(key, new_key, tip.block_number);
+        self.state
+            .registrations
+            .insert(new_key.clone(), registration);
+        // the cells in the set were matched by the old version, even below
+        // a rescan, which only goes back to where the new version starts
+        self.ctx.live_cells.reset(key, new_key);
+        self.ctx.stats.remove(key);
+
+        Ok(UpdateReport {
+            search_key: new_key.clone(),
+            tip,
+        })
     }
 
-    async fn header_sync_start(&self, number: BlockNumber) -> Result<bool, Error> {
-        let current =
-            unsafe { (*self.state.header_state.0 .0.load(Ordering::Acquire)).block_number };
-        if number < current {
-            Ok(false)
+    /// Move a registration with a stopped task back to `tip`
+    async fn rewind_cells(
+        &self,
+        key: &RpcSearchKey,
+        tip: IndexerTip,
+        rollback: bool,
+    ) -> anyhow::Result<RewindReport> {
+        let previous = match self.state.cell_states.get(key) {
+            Some(scan_tip) => scan_tip.load().clone(),
+            None => return Err(anyhow!("search key is not registered")),
+        };
+        check_rewind(&tip, &previous)?;
+
+        // blocks before the start of the registration were never submitted
+        let (since, changed) = match self.state.registrations.get(key) {
+            Some(r) => (
+                r.start_block.map(|b| b.value()).unwrap_or_default(),
+                r.history.last().and_then(|v| v.from_block),
+            ),
+            None => (0, None),
+        };
+        // what was sent before that was matched by another filter
+        if let Some(changed) = changed {
+            if tip.block_number < changed {
+                return Err(anyhow!(
+                    "block {} is below block {} where the filter last changed",
+                    tip.block_number.value(),
+                    changed.value()
+                ));
+            }
+        }
+        let from = std::cmp::max(tip.block_number.value(), since);
+        let submits = rewind::cells_to_undo(
+            &self.ctx.archive,
+            &self.client,
+            key,
+            from,
+            previous.block_number.value(),
+        )
+        .await?;
+        // with a rollback the blocks are undone a batch at a time, newest
+        // first, and the local state follows each batch axon took back
+        let mut batches = if rollback {
+            rewind::rollback_batches(&submits, |s| s.header.inner.number.value())
         } else {
-            let new_header = self.client.get_header_by_number(number).await?;
-            let new_tip = {
-                IndexerTip {
-                    block_hash: new_header.hash,
-                    block_number: new_header.inner.number,
+            Vec::new()
+        };
+        if batches.is_empty() {
+            batches.push((&submits[..], None));
+        }
+        // look everything up before anything is sent
+        let mut steps = Vec::with_capacity(batches.len());
+        for (batch, step_tip) in batches {
+            let step_tip = match step_tip {
+                Some(number) => self.tip_at(number).await?,
+                None => tip.clone(),
+            };
+            let spent = rewind::spent_cells(&self.client, batch, since).await?;
+            // the live cells hold the blocks below the tip
+            let applied = match step_tip.block_number.value().checked_sub(1) {
+                Some(number) => Some(self.tip_at(number).await?),
+                None => None,
+            };
+            steps.push((batch, spent, applied, step_tip));
+        }
+
+        let mut report = RewindReport {
+            tip: previous.clone(),
+            previous,
+            rollback_txs: 0.into(),
+            error: None,
+        };
+        for (batch, spent, applied, step_tip) in steps {
+            if rollback && !batch.is_empty() {
+                if let Err(e) = rewind::rollback_cells(&self.ctx.axon_url, batch).await {
+                    report.error = Some(format!("{:#}", e));
+                    break;
                 }
+                report.rollback_txs = (report.rollback_txs.value() + 1).into();
+            }
+            self.ctx
+                .live_cells
+                .get_or_load(key)
+                .lock()
+                .unwrap()
+                .revert(batch, spent, applied);
+            if let Err(e) = self
+                .ctx
+                .archive
+                .truncate_cells(key, step_tip.block_number.value())
+            {
+                log::warn!("Failed to truncate archived cells, error: {:#}", e);
+            }
+            // a new tip instead of swapping the old one, which no task owns now
+            self.state
+                .cell_states
+                .insert(key.clone(), ScanTip::from(step_tip.clone()));
+            // a finished job has blocks to scan again
+            if let Some(mut registration) = self.state.registrations.get_mut(key) {
+                registration.completed = None;
+            }
+            report.tip = step_tip;
+        }
+        Ok(report)
+    }
+
+    /// Move header sync with a stopped task back to `tip`
+    async fn rewind_headers(
+        &self,
+        tip: IndexerTip,
+        rollback: bool,
+    ) -> anyhow::Result<RewindReport> {
+        let previous = self.state.header_state.tip().load().clone();
+        check_rewind(&tip, &previous)?;
+
+        let headers = if rollback {
+            rewind::headers_to_undo(
+                &self.ctx.archive,
+                &self.client,
+                tip.block_number.value(),
+                previous.block_number.value(),
+            )
+            .await?
+        } else {
+            Vec::new()
+        };
+        // undone a batch at a time like the cells of `rewind_cells`
+        let mut batches = rewind::rollback_batches(&headers, |(number, _)| *number);
+        if batches.is_empty() {
+            batches.push((&headers[..], None));
+        }
+        let mut steps = Vec::with_capacity(batches.len());
+        for (batch, step_tip) in batches {
+            let step_tip = match step_tip {
+                Some(number) => self.tip_at(number).await?,
+                None => tip.clone(),
             };
-            let raw = self
-                .state
-                .header_state
-                .0
-                 .0
-                .swap(Box::into_raw(Box::new(new_tip)), Ordering::AcqRel);
+            steps.push((batch, step_tip));
+        }
 
-            unsafe {
-                drop(Box::from_raw(raw));
+        let mut report = RewindReport {
+            tip: previous.clone(),
+            previous,
+            rollback_txs: 0.into(),
+            error: None,
+        };
+        for (batch, step_tip) in steps {
+            if !batch.is_empty() {
+                if let Err(e) = rewind::rollback_headers(&self.ctx.axon_url, batch).await {
+                    report.error = Some(format!("{:#}", e));
+                    break;
+                }
+                report.rollback_txs = (report.rollback_txs.value() + 1).into();
             }
-            Ok(true)
+            // the task is gone, nothing else updates the tip now
+            self.state.header_state.reset(step_tip.clone());
+            if let Err(e) = self
+                .ctx
+                .archive
+                .truncate_headers(step_tip.block_number.value())
+            {
+                log::warn!("Failed to truncate archived headers, error: {:#}", e);
+            }
+            report.tip = step_tip;
+        }
+        Ok(report)
+    }
+
+    /// The block at `number` on the chain
+    async fn tip_at(&self, number: u64) -> anyhow::Result<IndexerTip> {
+        let header = self.client.get_header_by_number(number.into()).await?;
+        Ok(IndexerTip {
+            block_hash: header.hash,
+            block_number: header.inner.number,
+        })
+    }
+
+    /// The given key, or every registered key if none is given
+    fn target_keys(&self, search_key: Option<RpcSearchKey>) -> Result<Vec<RpcSearchKey>, Error> {
+        match search_key {
+            Some(key) if self.state.cell_states.contains_key(&key) => Ok(vec![key]),
+            Some(_) => Err(not_registered()),
+            None => Ok(self
+                .state
+                .cell_states
+                .iter()
+                .map(|kv| kv.key().clone())
+                .collect()),
         }
     }
+
+    fn scan_tip(&self, search_key: &RpcSearchKey) -> Result<ScanTip, Error> {
+        self.state
+            .cell_states
+            .get(search_key)
+            .map(|tip| tip.value().clone())
+            .ok_or_else(not_registered)
+    }
+}
+
+fn new_registration(
+    meta: Option<RegisterMeta>,
+    start_block: BlockNumber,
+    end: Option<BlockNumber>,
+) -> Registration {
+    let meta = meta.unwrap_or_default();
+    Registration {
+        label: meta.label,
+        owner: meta.owner,
+        notes: meta.notes,
+        created_at: Some(now_ms().into()),
+        start_block: Some(start_block),
+        paused: false,
+        end_block: end,
+        completed: None,
+        history: Vec::new(),
+    }
+}
+
+fn check_end(start: BlockNumber, end: Option<BlockNumber>) -> Result<(), String> {
+    match end {
+        Some(end) if end < start => Err(format!(
+            "end block {} is below start block {}",
+            end.value(),
+            start.value()
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_rewind(tip: &IndexerTip, previous: &IndexerTip) -> anyhow::Result<()> {
+    if tip.block_number >= previous.block_number {
+        return Err(anyhow!(
+            "block {} is not below the current tip {}",
+            tip.block_number.value(),
+            previous.block_number.value()
+        ));
+    }
+    Ok(())
+}
+
+fn not_registered() -> Error {
+    Error::Custom("search key is not registered".to_string())
+}
+
+fn invalid_params(message: String) -> Error {
+    Error::Call(CallError::InvalidParams(anyhow!(message)))
+}
+
+fn header_sync_disabled() -> Error {
+    Error::Custom("header sync is disabled by --no-header-sync".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        archive::Archive, global_state::HeaderTask, live_cells::LiveCells, openrpc, status::Stats,
+        ws_subscription::relay_events,
+    };
+    use ckb_types::{
+        core::{EpochNumberWithFraction, HeaderBuilder, HeaderView},
+        prelude::{Pack, Unpack},
+    };
+    use jsonrpsee::{
+        server::{ServerBuilder, ServerHandle},
+        RpcModule,
+    };
+    use serde_json::{json, Value};
+    use std::{path::Path, sync::Mutex};
+
+    fn header(number: u64) -> HeaderView {
+        HeaderBuilder::default()
+            .number(number.pack())
+            .epoch(EpochNumberWithFraction::new(0, 0, 1).pack())
+            .build()
+    }
+
+    /// A ckb node that has a header at every height
+    async fn ckb() -> (RpcClient, ServerHandle) {
+        let mut module = RpcModule::new(());
+        module
+            .register_method("get_header_by_number", |params, _| {
+                let number: BlockNumber = params.one()?;
+                Ok(ckb_jsonrpc_types::HeaderView::from(header(number.value())))
+            })
+            .unwrap();
+        let server = ServerBuilder::default().build("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", server.local_addr().unwrap());
+        (RpcClient::new(&url), server.start(module).unwrap())
+    }
+
+    /// Header sync at `header`, stopped and paused so that no task is spawned
+    fn emitter(client: RpcClient, header: u64, dir: &Path) -> EmitterRpc {
+        let state = serde_json::from_value(json!({
+            "cell_states": [],
+            "registrations": [],
+            "header_state": {
+                "block_hash": format!("0x{:064x}", header),
+                "block_number": format!("{:#x}", header),
+            },
+            "header_paused": true,
+        }))
+        .unwrap();
+        EmitterRpc {
+            state,
+            cell_handles: Default::default(),
+            header_handle: Arc::new(Mutex::new(HeaderTask {
+                stopped: true,
+                ..Default::default()
+            })),
+            save: Default::default(),
+            client,
+            ctx: SubmitContext {
+                axon_url: String::new(),
+                live_cells: LiveCells::new(dir),
+                archive: Archive::new(dir),
+                stats: Stats::default(),
+                events: relay_events(),
+            },
+        }
+    }
+
+    async fn header_sync_start(rpc: &EmitterRpc, params: Value) -> bool {
+        let module = openrpc::validated_module(rpc.clone().into_rpc());
+        let response = module
+            .raw_json_request(
+                &json!({
+                    "jsonrpc": "2.0",
+                    "id": 1,
+                    "method": "header_sync_start",
+                    "params": params,
+                })
+                .to_string(),
+            )
+            .await
+            .unwrap()
+            .0;
+        let response: Value = serde_json::from_str(&response.result).unwrap();
+        response["result"].as_bool().unwrap()
+    }
+
+    #[tokio::test]
+    async fn header_sync_start_without_a_block_keeps_the_tip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (client, _ckb) = ckb().await;
+        let rpc = emitter(client, 100, dir.path());
+
+        for params in [json!([]), json!([null])] {
+            rpc.header_handle.lock().unwrap().stopped = true;
+            // paused, so it is not started, but no longer stopped either
+            assert!(!header_sync_start(&rpc, params).await);
+            assert!(!rpc.header_handle.lock().unwrap().stopped);
+            assert_eq!(
+                rpc.state.header_state.tip().load().block_number.value(),
+                100
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn header_sync_start_with_a_block_moves_the_tip_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let (client, _ckb) = ckb().await;
+        let rpc = emitter(client, 100, dir.path());
+
+        assert!(!header_sync_start(&rpc, json!(["0x50"])).await);
+        assert_eq!(
+            rpc.state.header_state.tip().load().block_number.value(),
+            100
+        );
+
+        assert!(header_sync_start(&rpc, json!(["0xc8"])).await);
+        assert!(!rpc.header_handle.lock().unwrap().stopped);
+        let tip = rpc.state.header_state.tip().load().clone();
+        assert_eq!(tip.block_number.value(), 200);
+        assert_eq!(tip.block_hash, header(200).hash().unpack());
+    }
 }
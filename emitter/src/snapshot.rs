@@ -0,0 +1,305 @@
+use anyhow::{anyhow, Context, Result};
+use ckb_types::H256;
+use emitter_core::{rpc_client::RpcClient, schema, types::RpcSearchKey, TipState};
+use ethers::utils::keccak256;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+    sync::atomic::Ordering,
+};
+
+use crate::{
+    backup,
+    global_state::{read_state, write_state, State},
+    migrate::{self, STATE_VERSION},
+    ScanTip,
+};
+
+/// A portable copy of the whole `State`, used to move an emitter to another host
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct Snapshot {
+    /// State layout version of `state`
+    pub version: u64,
+    /// keccak256 of the compact json encoding of `state`
+    #[schemars(with = "schema::H256")]
+    pub checksum: H256,
+    pub state: Value,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    /// Add registrations that do not exist yet, keep everything else
+    Merge,
+    /// Drop all current registrations and take header sync's tip and pause
+    /// flag from the snapshot
+    Replace,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+pub struct ImportReport {
+    /// Registrations taken from the snapshot
+    pub added: Vec<RpcSearchKey>,
+    /// Registrations in the snapshot that already exist locally, merge mode only
+    pub skipped: Vec<RpcSearchKey>,
+    /// Registrations dropped from the local state, replace mode only. Keys
+    /// the snapshot has as well are also in `added`.
+    pub removed: Vec<RpcSearchKey>,
+}
+
+impl Snapshot {
+    pub fn new(state: &State) -> Result<Snapshot> {
+        let state = serde_json::to_value(state)?;
+        Ok(Snapshot {
+            version: STATE_VERSION,
+            checksum: checksum(&state)?,
+            state,
+        })
+    }
+
+    /// Verify the checksum and decode the state, migrating it if the snapshot
+    /// was taken by an older emitter
+    pub fn into_state(self) -> Result<State> {
+        let actual = checksum(&self.state)?;
+        if actual != self.checksum {
+            return Err(anyhow!(
+                "snapshot checksum mismatch, expect {:#x}, got {:#x}",
+                self.checksum,
+                actual
+            ));
+        }
+        let mut doc = migrate::migrate(json!({ "version": self.version, "state": self.state }))?;
+        serde_json::from_value(doc["state"].take()).context("invalid state in snapshot")
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Snapshot> {
+        let f = File::open(path.as_ref())
+            .with_context(|| format!("failed to open {:?}", path.as_ref()))?;
+        serde_json::from_reader(BufReader::new(f))
+            .with_context(|| format!("failed to parse snapshot {:?}", path.as_ref()))
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let f = File::create(path.as_ref())
+            .with_context(|| format!("failed to create {:?}", path.as_ref()))?;
+        let mut writer = BufWriter::new(f);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn checksum(state: &Value) -> Result<H256> {
+    Ok(H256(keccak256(serde_json::to_vec(state)?)))
+}
+
+/// Check that every tip in `state` is a block on the chain of the connected
+/// ckb node
+pub async fn validate(state: &State, client: &RpcClient) -> Result<()> {
+    let mut tips: Vec<(String, ScanTip)> =
+        vec![("header sync".to_string(), state.header_state.tip())];
+    for kv in state.cell_states.iter() {
+        tips.push((serde_json::to_string(kv.key())?, kv.value().clone()));
+    }
+
+    let mut invalid = Vec::new();
+    for (name, tip) in tips {
+        let tip = tip.load().clone();
+        match client.get_header(tip.block_hash.clone()).await? {
+            Some(header) if header.inner.number == tip.block_number => (),
+            _ => invalid.push(format!(
+                "{}: block {:#x} at {} is not on the chain",
+                name,
+                tip.block_hash,
+                tip.block_number.value()
+            )),
+        }
+    }
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "snapshot does not match the ckb node\n{}",
+            invalid.join("\n")
+        ))
+    }
+}
+
+/// Apply an imported state to `target` in place.
+///
+/// Only the maps are touched, stopping and spawning the scan tasks of the
+/// keys in the report is up to the caller.
+pub fn import_into(target: &State, imported: State, mode: ImportMode) -> ImportReport {
+    let mut report = ImportReport::default();
+
+    if mode == ImportMode::Replace {
+        report.removed = target
+            .cell_states
+            .iter()
+            .map(|kv| kv.key().clone())
+            .collect();
+        target.cell_states.clear();
+        target.registrations.clear();
+        target
+            .header_state
+            .reset(imported.header_state.tip().load().clone());
+        target.header_paused.store(
+            imported.header_paused.load(Ordering::Acquire),
+            Ordering::Release,
+        );
+    }
+
+    for kv in imported.cell_states.iter() {
+        let key = kv.key();
+        if target.cell_states.contains_key(key) {
+            report.skipped.push(key.clone());
+            continue;
+        }
+        let registration = imported
+            .registrations
+            .get(key)
+            .map(|r| r.value().clone())
+            .unwrap_or_default();
+        target.registrations.insert(key.clone(), registration);
+        target.cell_states.insert(key.clone(), kv.value().clone());
+        report.added.push(key.clone());
+    }
+    report
+}
+
+/// Write a snapshot of the state db of a store to `output`
+pub fn export_store<P: AsRef<Path>>(store_path: P, output: P) -> Result<()> {
+    let (state, _) = read_state(store_path.as_ref().join("scan_state"))?;
+    Snapshot::new(&state)?.write(output)
+}
+
+/// Import a snapshot file into the state db of a stopped emitter, the current
+/// state db is kept as `scan_state.import.<timestamp>`
+pub async fn import_store<P: AsRef<Path>>(
+    store_path: P,
+    input: P,
+    mode: ImportMode,
+    client: &RpcClient,
+) -> Result<ImportReport> {
+    let store_path = store_path.as_ref();
+    let imported = Snapshot::read(input)?.into_state()?;
+    validate(&imported, client).await?;
+
+    let db_path = store_path.join("scan_state");
+    if !db_path.exists() {
+        let report = ImportReport {
+            added: imported
+                .cell_states
+                .iter()
+                .map(|kv| kv.key().clone())
+                .collect(),
+            ..Default::default()
+        };
+        write_state(store_path, &imported)?;
+        return Ok(report);
+    }
+
+    let (state, _) = read_state(&db_path)?;
+    backup::keep_copy(store_path, "import")?;
+    let report = import_into(&state, imported, mode);
+    write_state(store_path, &state)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tip(number: u64) -> Value {
+        json!({
+            "block_hash": format!("0x{:064x}", number),
+            "block_number": format!("{:#x}", number),
+        })
+    }
+
+    fn key(args: &str) -> Value {
+        json!({
+            "script": {
+                "code_hash": format!("0x{:064x}", 1),
+                "hash_type": "type",
+                "args": args,
+            },
+            "script_type": "lock",
+            "script_search_mode": null,
+            "filter": null,
+        })
+    }
+
+    fn state(keys: &[(&str, u64)], header: u64, paused: bool) -> State {
+        serde_json::from_value(json!({
+            "cell_states": keys.iter().map(|(k, n)| json!([key(k), tip(*n)])).collect::<Vec<_>>(),
+            "registrations": keys.iter().map(|(k, _)| json!([key(k), {}])).collect::<Vec<_>>(),
+            "header_state": tip(header),
+            "header_paused": paused,
+        }))
+        .unwrap()
+    }
+
+    fn args(keys: &[RpcSearchKey]) -> Vec<String> {
+        let mut args = keys
+            .iter()
+            .map(|k| format!("0x{}", hex::encode(k.script.args.as_bytes())))
+            .collect::<Vec<_>>();
+        args.sort();
+        args
+    }
+
+    #[test]
+    fn replace_takes_everything_from_the_snapshot() {
+        let target = state(&[("0x01", 10), ("0x02", 20)], 30, false);
+        let imported = state(&[("0x02", 25), ("0x03", 35)], 40, true);
+        let report = import_into(&target, imported, ImportMode::Replace);
+
+        assert_eq!(args(&report.removed), vec!["0x01", "0x02"]);
+        assert_eq!(args(&report.added), vec!["0x02", "0x03"]);
+        assert!(report.skipped.is_empty());
+        assert_eq!(target.cell_states.len(), 2);
+        let key: RpcSearchKey = serde_json::from_value(key("0x02")).unwrap();
+        assert_eq!(
+            target
+                .cell_states
+                .get(&key)
+                .unwrap()
+                .load()
+                .block_number
+                .value(),
+            25
+        );
+        assert_eq!(target.header_state.tip().load().block_number.value(), 40);
+        assert!(target.header_paused.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn merge_keeps_what_exists() {
+        let target = state(&[("0x01", 10), ("0x02", 20)], 30, false);
+        let imported = state(&[("0x02", 25), ("0x03", 35)], 40, true);
+        let report = import_into(&target, imported, ImportMode::Merge);
+
+        assert!(report.removed.is_empty());
+        assert_eq!(args(&report.added), vec!["0x03"]);
+        assert_eq!(args(&report.skipped), vec!["0x02"]);
+        assert_eq!(target.cell_states.len(), 3);
+        let key: RpcSearchKey = serde_json::from_value(key("0x02")).unwrap();
+        assert_eq!(
+            target
+                .cell_states
+                .get(&key)
+                .unwrap()
+                .load()
+                .block_number
+                .value(),
+            20
+        );
+        assert_eq!(target.header_state.tip().load().block_number.value(), 30);
+        assert!(!target.header_paused.load(Ordering::Acquire));
+    }
+}
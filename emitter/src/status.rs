@@ -0,0 +1,236 @@
+use ckb_jsonrpc_types::{BlockNumber, Timestamp, Uint32, Uint64};
+use emitter_core::{
+    schema,
+    types::{IndexerTip, RpcSearchKey},
+    CONFIRMATIONS,
+};
+use ethers::types::TxHash;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::global_state::JobRecord;
+use std::{
+    any::Any,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Progress and errors of one scan task, written by its submitter
+#[derive(Default)]
+pub struct TaskStats {
+    /// When the task was spawned, in milliseconds
+    started_at: u64,
+    /// The tip the task was spawned with
+    start_block: u64,
+    last_submit_at: Option<u64>,
+    last_error: Option<String>,
+    last_error_at: Option<u64>,
+    last_batch: Option<LastBatch>,
+}
+
+impl TaskStats {
+    fn new(tip: &IndexerTip) -> Self {
+        TaskStats {
+            started_at: now_ms(),
+            start_block: tip.block_number.value(),
+            ..Default::default()
+        }
+    }
+
+    pub fn submitted(&mut self, batch: LastBatch) {
+        self.last_submit_at = Some(batch.at.value());
+        self.last_batch = Some(batch);
+    }
+
+    pub fn failed(&mut self, error: String) {
+        self.last_error = Some(error);
+        self.last_error_at = Some(now_ms());
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.clone()
+    }
+
+    /// Record the panic that ended the task
+    pub fn panicked(&mut self, payload: Box<dyn Any + Send>) {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        self.failed(format!("task panicked: {}", message));
+    }
+
+    /// `target` is the tip the task heads for, lag and eta are unknown
+    /// without it
+    pub fn status(
+        &self,
+        tip: IndexerTip,
+        target: Option<u64>,
+        running: bool,
+        paused: bool,
+    ) -> TaskStatus {
+        let current = tip.block_number.value();
+        let lag = target.map(|t| t.saturating_sub(current));
+
+        // average speed since the task was spawned
+        let done = current.saturating_sub(self.start_block);
+        let elapsed = now_ms().saturating_sub(self.started_at);
+        let eta_secs = match lag {
+            Some(0) => Some(0),
+            Some(lag) if done > 0 && running => {
+                Some((lag as u128 * elapsed as u128 / done as u128 / 1000) as u64)
+            }
+            _ => None,
+        };
+
+        TaskStatus {
+            tip,
+            lag: lag.map(Into::into),
+            eta_secs: eta_secs.map(Into::into),
+            last_submit_at: self.last_submit_at.map(Into::into),
+            last_error: self.last_error.clone(),
+            last_error_at: self.last_error_at.map(Into::into),
+            last_batch: self.last_batch.clone(),
+            running,
+            paused,
+            completed: None,
+        }
+    }
+}
+
+/// The last batch a task sent to axon
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct LastBatch {
+    #[schemars(with = "schema::Uint64")]
+    pub from: BlockNumber,
+    #[schemars(with = "schema::Uint64")]
+    pub to: BlockNumber,
+    /// Number of headers, or of blocks with cell changes
+    #[schemars(with = "schema::Uint32")]
+    pub size: Uint32,
+    #[schemars(with = "schema::H256")]
+    pub axon_tx_hash: TxHash,
+    #[schemars(with = "schema::Uint64")]
+    pub at: Timestamp,
+}
+
+impl LastBatch {
+    /// A batch of the blocks `numbers`, sent now
+    pub fn new(numbers: &[u64], axon_tx_hash: TxHash) -> Self {
+        LastBatch {
+            from: numbers.iter().min().copied().unwrap_or_default().into(),
+            to: numbers.iter().max().copied().unwrap_or_default().into(),
+            size: (numbers.len() as u32).into(),
+            axon_tx_hash,
+            at: now_ms().into(),
+        }
+    }
+}
+
+/// The tip a task follows the chain to, `CONFIRMATIONS` blocks below the
+/// indexer tip, or the block after `end` for a bounded job
+pub fn target_tip(indexer_tip: Option<&IndexerTip>, end: Option<BlockNumber>) -> Option<u64> {
+    let confirmed = indexer_tip?
+        .block_number
+        .value()
+        .saturating_sub(CONFIRMATIONS);
+    Some(match end {
+        Some(end) => confirmed.min(end.value() + 1),
+        None => confirmed,
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct TaskStatus {
+    pub tip: IndexerTip,
+    /// Blocks left until the tip is `CONFIRMATIONS` blocks below the indexer
+    /// tip, or past the end block of a bounded job
+    #[schemars(with = "Option<schema::Uint64>")]
+    pub lag: Option<BlockNumber>,
+    /// Estimated seconds to catch up at the average speed since the task
+    /// started, null if it has not made any progress yet or is not running
+    #[schemars(with = "Option<schema::Uint64>")]
+    pub eta_secs: Option<Uint64>,
+    #[schemars(with = "Option<schema::Uint64>")]
+    pub last_submit_at: Option<Timestamp>,
+    pub last_error: Option<String>,
+    #[schemars(with = "Option<schema::Uint64>")]
+    pub last_error_at: Option<Timestamp>,
+    pub last_batch: Option<LastBatch>,
+    /// Whether the scan task is still alive
+    pub running: bool,
+    pub paused: bool,
+    /// The record of a finished bounded job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed: Option<JobRecord>,
+}
+
+/// Why header sync runs or not, the first that applies
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderSyncState {
+    /// Turned off by `--no-header-sync`
+    Disabled,
+    Paused,
+    /// Stopped by `header_sync_stop`
+    Stopped,
+    Running,
+    /// The task ended on its own, see `last_error`
+    Dead,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct HeaderSyncStatus {
+    pub state: HeaderSyncState,
+    #[serde(flatten)]
+    pub task: TaskStatus,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct StatusReport {
+    /// Null if the ckb node can not be reached
+    pub indexer_tip: Option<IndexerTip>,
+    pub header_sync: HeaderSyncStatus,
+    pub registrations: Vec<(RpcSearchKey, TaskStatus)>,
+}
+
+/// Stats of all running scan tasks
+#[derive(Clone, Default)]
+pub struct Stats {
+    cells: Arc<dashmap::DashMap<RpcSearchKey, Arc<Mutex<TaskStats>>>>,
+    header: Arc<Mutex<TaskStats>>,
+}
+
+impl Stats {
+    /// Reset the stats of `key` for a newly spawned task
+    pub fn start_cells(&self, key: &RpcSearchKey, tip: &IndexerTip) -> Arc<Mutex<TaskStats>> {
+        let stats = Arc::new(Mutex::new(TaskStats::new(tip)));
+        self.cells.insert(key.clone(), stats.clone());
+        stats
+    }
+
+    pub fn start_headers(&self, tip: &IndexerTip) -> Arc<Mutex<TaskStats>> {
+        *self.header.lock().unwrap() = TaskStats::new(tip);
+        self.header.clone()
+    }
+
+    pub fn cells(&self, key: &RpcSearchKey) -> Option<Arc<Mutex<TaskStats>>> {
+        self.cells.get(key).map(|s| s.value().clone())
+    }
+
+    pub fn headers(&self) -> Arc<Mutex<TaskStats>> {
+        self.header.clone()
+    }
+
+    pub fn remove(&self, key: &RpcSearchKey) {
+        self.cells.remove(key);
+    }
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
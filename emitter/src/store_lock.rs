@@ -0,0 +1,244 @@
+use anyhow::{anyhow, Context, Result};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{create_dir_all, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const LOCK_FILE: &str = "LOCK";
+
+/// Who is holding the store, written into `<store_path>/LOCK`
+#[derive(Serialize, Deserialize)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub host: String,
+    pub started_at: u64,
+}
+
+impl LockOwner {
+    /// Whether the owner is a process still running on this host, none if
+    /// that can not be told
+    fn is_alive(&self) -> Option<bool> {
+        let proc = Path::new("/proc");
+        if self.host != hostname() || !proc.is_dir() {
+            return None;
+        }
+        Some(proc.join(self.pid.to_string()).exists())
+    }
+
+    fn is_current(&self) -> bool {
+        self.pid == std::process::id() && self.host == hostname()
+    }
+
+    fn current() -> Self {
+        LockOwner {
+            pid: std::process::id(),
+            host: hostname(),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+}
+
+impl fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pid {} on host {}, started at {}",
+            self.pid,
+            self.host,
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(self.started_at))
+        )
+    }
+}
+
+/// Advisory exclusive lock on a store directory, released on drop.
+///
+/// The lock itself is an OS file lock, so it goes away with the process that
+/// holds it. Owner metadata left behind in the lock file by a process that
+/// did not exit cleanly is reported as a stale lock and taken over.
+pub struct StoreLock {
+    file: File,
+    /// False when started with `--ignore-lock` while another process held
+    /// the OS lock
+    locked: bool,
+}
+
+impl StoreLock {
+    pub fn acquire<P: AsRef<Path>>(store_path: P, ignore_lock: bool) -> Result<StoreLock> {
+        let store_path = store_path.as_ref();
+        create_dir_all(store_path)?;
+        let lock_path = store_path.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .with_context(|| format!("failed to open lock file {:?}", lock_path))?;
+
+        let previous = read_owner(&mut file);
+        let locked = match file.try_lock_exclusive() {
+            Ok(()) => {
+                if let Some(owner) = previous {
+                    log::warn!("Take over stale store lock left by {}", owner);
+                }
+                true
+            }
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                let alive = previous.as_ref().and_then(LockOwner::is_alive);
+                let owner = previous
+                    .map(|o| o.to_string())
+                    .unwrap_or_else(|| "an unknown process".to_string());
+                if !ignore_lock {
+                    return Err(anyhow!(
+                        "store {:?} is locked by {}\n\
+                         stop that emitter first, or pass `--ignore-lock` if you are sure it is not running",
+                        store_path,
+                        owner
+                    ));
+                }
+                if alive == Some(true) {
+                    return Err(anyhow!(
+                        "store {:?} is locked by {}, which is still running\n\
+                         `--ignore-lock` only overrides the lock of an emitter that is gone",
+                        store_path,
+                        owner
+                    ));
+                }
+                log::warn!(
+                    "Store {:?} is locked by {}, continue because of `--ignore-lock`",
+                    store_path,
+                    owner
+                );
+                false
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to lock {:?}", lock_path));
+            }
+        };
+
+        let owner = serde_json::to_vec(&LockOwner::current())?;
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(&owner))
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("failed to write lock file {:?}", lock_path))?;
+
+        Ok(StoreLock { file, locked })
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        // an empty lock file means the previous owner exited cleanly, unless
+        // an emitter started with `--ignore-lock` wrote itself in since
+        let _ = self.file.seek(SeekFrom::Start(0));
+        if read_owner(&mut self.file).is_some_and(|o| o.is_current()) {
+            let _ = self.file.set_len(0);
+        }
+        if self.locked {
+            let _ = self.file.unlock();
+        }
+    }
+}
+
+fn read_owner(file: &mut File) -> Option<LockOwner> {
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).ok()?;
+    serde_json::from_slice(&buf).ok()
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner(dir: &Path) -> Option<LockOwner> {
+        read_owner(&mut File::open(dir.join(LOCK_FILE)).unwrap())
+    }
+
+    /// Hold the OS lock of `dir` as `pid` would
+    fn hold(dir: &Path, pid: u32) -> File {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.join(LOCK_FILE))
+            .unwrap();
+        file.try_lock_exclusive().unwrap();
+        let owner = LockOwner {
+            pid,
+            ..LockOwner::current()
+        };
+        file.write_all(&serde_json::to_vec(&owner).unwrap())
+            .unwrap();
+        file
+    }
+
+    #[test]
+    fn acquires_a_free_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = StoreLock::acquire(dir.path(), false).unwrap();
+        assert!(lock.locked);
+        assert!(owner(dir.path()).unwrap().is_current());
+
+        // a clean exit leaves an empty lock file behind
+        drop(lock);
+        assert!(owner(dir.path()).is_none());
+        let lock = StoreLock::acquire(dir.path(), false).unwrap();
+        assert!(lock.locked);
+    }
+
+    #[test]
+    fn takes_over_a_stale_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        // left by a process that did not exit cleanly, without the OS lock
+        drop(hold(dir.path(), u32::MAX));
+        let lock = StoreLock::acquire(dir.path(), false).unwrap();
+        assert!(lock.locked);
+        assert!(owner(dir.path()).unwrap().is_current());
+    }
+
+    #[test]
+    fn refuses_a_live_holder() {
+        let dir = tempfile::tempdir().unwrap();
+        let _held = StoreLock::acquire(dir.path(), false).unwrap();
+        let e = StoreLock::acquire(dir.path(), false).err().unwrap();
+        assert!(e.to_string().contains("--ignore-lock"), "{}", e);
+        // the holder is this very process, which is running, as far as
+        // can be told without /proc
+        if Path::new("/proc").is_dir() {
+            let e = StoreLock::acquire(dir.path(), true).err().unwrap();
+            assert!(e.to_string().contains("still running"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn ignore_lock_takes_over_from_a_dead_holder() {
+        let dir = tempfile::tempdir().unwrap();
+        // no such process, but its lock is still held
+        let _held = hold(dir.path(), u32::MAX);
+        assert!(StoreLock::acquire(dir.path(), false).is_err());
+
+        let lock = StoreLock::acquire(dir.path(), true).unwrap();
+        assert!(!lock.locked);
+        assert!(owner(dir.path()).unwrap().is_current());
+        drop(lock);
+        assert!(owner(dir.path()).is_none());
+    }
+}
@@ -0,0 +1,283 @@
+//! The tips the scan tasks keep and what they send their data with
+
+use async_trait::async_trait;
+use emitter_core::{
+    types::{HeaderViewWithExtension, IndexerTip, RpcSearchKey},
+    Submit, SubmitProcess, TipState,
+};
+use ethers::types::TxHash;
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use std::borrow::Cow;
+use std::sync::{
+    atomic::{AtomicBool, AtomicPtr, Ordering},
+    Arc, Mutex,
+};
+
+use crate::{
+    archive::Archive,
+    emit_data::eth_tx::{send_eth_tx, CKB_LIGHT_CLIENT_ADDRESS, IMAGE_CELL_ADDRESS},
+    emit_data::tx_data::{convert_blocks, convert_headers},
+    live_cells::{LiveCellSet, LiveCells},
+    status::{LastBatch, Stats, TaskStats},
+    ws_subscription::{RelayEvent, RelayEvents},
+};
+
+/// Returns the hash of the axon transaction, none if it failed
+async fn submit_cells(
+    axon_url: &str,
+    submits: &[Submit],
+    stats: &Mutex<TaskStats>,
+) -> Option<TxHash> {
+    let numbers: Vec<u64> = submits
+        .iter()
+        .map(|s| s.header.inner.number.value())
+        .collect();
+    match send_eth_tx(axon_url, convert_blocks(submits), IMAGE_CELL_ADDRESS).await {
+        Ok(hash) => {
+            stats
+                .lock()
+                .unwrap()
+                .submitted(LastBatch::new(&numbers, hash));
+            Some(hash)
+        }
+        Err(e) => {
+            println!("emitter submit cells tx error: {e}");
+            stats
+                .lock()
+                .unwrap()
+                .failed(format!("submit cells: {:#}", e));
+            None
+        }
+    }
+}
+
+/// Returns the hash of the axon transaction, none if it failed
+async fn submit_headers(
+    axon_url: &str,
+    headers: Vec<HeaderViewWithExtension>,
+    stats: &Mutex<TaskStats>,
+) -> Option<TxHash> {
+    let numbers: Vec<u64> = headers
+        .iter()
+        .map(|h| h.inner.inner.number.value())
+        .collect();
+    match send_eth_tx(axon_url, convert_headers(headers), CKB_LIGHT_CLIENT_ADDRESS).await {
+        Ok(hash) => {
+            stats
+                .lock()
+                .unwrap()
+                .submitted(LastBatch::new(&numbers, hash));
+            Some(hash)
+        }
+        Err(e) => {
+            println!("emitter submit headers tx error: {e}");
+            stats
+                .lock()
+                .unwrap()
+                .failed(format!("submit headers: {:#}", e));
+            None
+        }
+    }
+}
+
+pub(crate) struct ScanTipInner(pub(crate) AtomicPtr<IndexerTip>);
+
+pub struct ScanTip(pub(crate) Arc<ScanTipInner>);
+
+impl Drop for ScanTipInner {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.0.load(Ordering::Relaxed))) }
+    }
+}
+
+impl From<IndexerTip> for ScanTip {
+    fn from(tip: IndexerTip) -> Self {
+        ScanTip(Arc::new(ScanTipInner(AtomicPtr::new(Box::into_raw(
+            Box::new(tip),
+        )))))
+    }
+}
+
+impl Clone for ScanTip {
+    fn clone(&self) -> Self {
+        ScanTip(self.0.clone())
+    }
+}
+
+impl TipState for ScanTip {
+    fn load(&self) -> &IndexerTip {
+        unsafe { &*self.0 .0.load(Ordering::Acquire) }
+    }
+
+    fn update(&mut self, current: IndexerTip) {
+        let new_number = current.block_number;
+        let new_ptr = Box::into_raw(Box::new(current));
+        if let Ok(raw) = self
+            .0
+             .0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |raw| {
+                if unsafe { (*raw).block_number } < new_number {
+                    Some(new_ptr)
+                } else {
+                    None
+                }
+            })
+        {
+            unsafe {
+                drop(Box::from_raw(raw));
+            }
+        } else {
+            unsafe { drop(Box::from_raw(new_ptr)) }
+        }
+    }
+}
+
+impl Serialize for ScanTip {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let inner = unsafe { &*self.0 .0.load(Ordering::Acquire) };
+
+        inner.serialize(serializer)
+    }
+}
+
+impl JsonSchema for ScanTip {
+    fn schema_name() -> String {
+        IndexerTip::schema_name()
+    }
+
+    fn schema_id() -> Cow<'static, str> {
+        IndexerTip::schema_id()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        IndexerTip::json_schema(gen)
+    }
+}
+
+impl<'a> Deserialize<'a> for ScanTip {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'a>,
+    {
+        let inner = IndexerTip::deserialize(deserializer)?;
+
+        Ok(ScanTip(Arc::new(ScanTipInner(AtomicPtr::new(
+            Box::into_raw(Box::new(inner)),
+        )))))
+    }
+}
+
+/// What the scan tasks need to emit their data, shared by `GlobalState` and
+/// the rpc server
+#[derive(Clone)]
+pub(crate) struct SubmitContext {
+    pub axon_url: String,
+    pub live_cells: LiveCells,
+    pub archive: Archive,
+    pub stats: Stats,
+    pub events: RelayEvents,
+}
+
+impl SubmitContext {
+    /// Submitter of a cell task starting at `tip`
+    pub fn cells(&self, search_key: &RpcSearchKey, tip: &IndexerTip) -> RpcSubmit {
+        RpcSubmit {
+            axon_url: self.axon_url.clone(),
+            cells: Some((search_key.clone(), self.live_cells.get_or_load(search_key))),
+            archive: self.archive.clone(),
+            stats: self.stats.start_cells(search_key, tip),
+            events: self.events.clone(),
+            closed: Default::default(),
+        }
+    }
+
+    /// Submitter of the header sync task starting at `tip`
+    pub fn headers(&self, tip: &IndexerTip) -> RpcSubmit {
+        RpcSubmit {
+            axon_url: self.axon_url.clone(),
+            cells: None,
+            archive: self.archive.clone(),
+            stats: self.stats.start_headers(tip),
+            events: self.events.clone(),
+            closed: Default::default(),
+        }
+    }
+}
+
+pub(crate) struct RpcSubmit {
+    axon_url: String,
+    /// The registration being scanned and its live cell image, none for header sync
+    cells: Option<(RpcSearchKey, Arc<Mutex<LiveCellSet>>)>,
+    archive: Archive,
+    stats: Arc<Mutex<TaskStats>>,
+    events: RelayEvents,
+    /// Set to stop the task, nothing is submitted after that
+    closed: Arc<AtomicBool>,
+}
+
+impl RpcSubmit {
+    pub fn stats(&self) -> Arc<Mutex<TaskStats>> {
+        self.stats.clone()
+    }
+
+    pub fn closed(&self) -> Arc<AtomicBool> {
+        self.closed.clone()
+    }
+}
+
+#[async_trait]
+impl SubmitProcess for RpcSubmit {
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    async fn submit_cells(&mut self, cells: Vec<Submit>) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+        if let Some((_, ref live_cells)) = self.cells {
+            live_cells.lock().unwrap().apply(&cells);
+        }
+        let tx_hash = submit_cells(&self.axon_url, &cells, &self.stats).await;
+        // archived after sending to keep the hash of the axon transaction,
+        // failed batches are archived too so that they can be replayed
+        if let Some((ref search_key, _)) = self.cells {
+            self.archive.save_cells(search_key, &cells, tx_hash);
+            if self.events.receiver_count() > 0 {
+                let _ = self.events.send(Arc::new(RelayEvent::Cells {
+                    search_key: Box::new(search_key.clone()),
+                    submits: cells,
+                    axon_tx_hash: tx_hash,
+                }));
+            }
+        }
+        true
+    }
+
+    async fn submit_headers(&mut self, headers: Vec<HeaderViewWithExtension>) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+        self.archive.save_headers(&headers);
+        let event_headers = (self.events.receiver_count() > 0).then(|| headers.clone());
+        let tx_hash = submit_headers(&self.axon_url, headers, &self.stats).await;
+        if let Some(headers) = event_headers {
+            let _ = self.events.send(Arc::new(RelayEvent::Headers {
+                headers,
+                axon_tx_hash: tx_hash,
+            }));
+        }
+        true
+    }
+
+    fn scanned(&mut self, last: IndexerTip) {
+        if let Some((_, ref live_cells)) = self.cells {
+            live_cells.lock().unwrap().advance(last);
+        }
+    }
+}
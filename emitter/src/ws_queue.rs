@@ -0,0 +1,517 @@
+//! Bounded queues of the `cell_filter` and `header_sync` subscriptions. The
+//! emitter cannot see how far a websocket has written what it was handed, so
+//! a notification counts as queued until the subscriber acknowledges it with
+//! `emitter_ack`. Queue depths are reported by the `subscription_status` rpc,
+//! there is no other export of them.
+
+use jsonrpsee::{server::SubscriptionSink, types::error::CallError, types::SubscriptionId};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Semaphore;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::ws_subscription::{Notification, ResumeToken};
+
+/// How often a scan blocked on a full queue checks if its subscriber is gone
+const BLOCKED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a subscription does once its queue is full
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumer {
+    /// Pause the scan until the subscriber acknowledges
+    Block,
+    /// Close the subscription, it can resume from the last token it received
+    Drop,
+    /// Merge what comes next into one notification, sent on the next
+    /// acknowledgement
+    Coalesce,
+}
+
+/// Limits of the subscription queues, as set by `--ws-queue` and
+/// `--ws-slow-consumer`
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+    /// Notifications a subscriber may leave unacknowledged
+    pub capacity: usize,
+    pub policy: SlowConsumer,
+}
+
+/// Queue depths of the open subscriptions
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SubscriptionReport {
+    pub capacity: usize,
+    pub policy: SlowConsumer,
+    /// Subscriptions closed for being too slow since start
+    pub dropped: u64,
+    pub subscriptions: Vec<QueueStatus>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueStatus {
+    pub subscription: String,
+    /// `cell_filter` or `header_sync`
+    pub kind: String,
+    /// Notifications sent and not acknowledged yet
+    pub depth: usize,
+    /// Items merged while the queue was full, waiting for an acknowledgement
+    pub coalesced: usize,
+}
+
+/// The queues of the open subscriptions, by subscription id
+#[derive(Clone)]
+pub struct Queues {
+    config: QueueConfig,
+    queues: Arc<Mutex<HashMap<String, Arc<Queue>>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Queues {
+    pub fn new(config: QueueConfig) -> Self {
+        Queues {
+            config,
+            queues: Default::default(),
+            dropped: Default::default(),
+        }
+    }
+
+    /// Queue the notifications of an accepted subscription. Without resume
+    /// `tokens` the subscriber can not acknowledge, nothing is tracked.
+    pub(crate) fn open(
+        &self,
+        kind: &'static str,
+        sink: SubscriptionSink,
+        tokens: bool,
+    ) -> Subscriber {
+        let id = sink
+            .subscription_id()
+            .map(|id| subscription_key(&id))
+            .unwrap_or_default();
+        let queue = Arc::new(Queue {
+            id: id.clone(),
+            kind,
+            room: Semaphore::new(self.config.capacity),
+            inner: Mutex::new(Inner {
+                sink: Some(sink),
+                tokens,
+                unacked: VecDeque::new(),
+                coalesced: None,
+            }),
+        });
+        self.queues.lock().unwrap().insert(id, queue.clone());
+        Subscriber {
+            queues: self.clone(),
+            queue,
+        }
+    }
+
+    /// Acknowledge the notifications of subscription `id` up to the one
+    /// carrying `token`, returns how many left the queue
+    pub fn ack(&self, id: &Value, token: &ResumeToken) -> Result<usize, CallError> {
+        let key = match id {
+            Value::Number(n) => n.to_string(),
+            Value::String(s) => s.clone(),
+            _ => String::new(),
+        };
+        let queue = match self.queues.lock().unwrap().get(&key) {
+            Some(queue) => queue.clone(),
+            None => return Err(invalid(format!("no subscription {}", id))),
+        };
+        let mut inner = queue.inner.lock().unwrap();
+        let count = match inner.unacked.iter().position(|t| t == token) {
+            Some(i) => i + 1,
+            None => {
+                return Err(invalid(format!(
+                    "resume token is not pending on subscription {}",
+                    id
+                )))
+            }
+        };
+        inner.unacked.drain(..count);
+        queue.room.add_permits(count);
+
+        if inner.coalesced.is_some() {
+            if let Ok(permit) = queue.room.try_acquire() {
+                permit.forget();
+                let (items, resume) = inner.coalesced.take().unwrap();
+                inner.send(items, resume);
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn report(&self) -> SubscriptionReport {
+        let mut subscriptions: Vec<QueueStatus> = self
+            .queues
+            .lock()
+            .unwrap()
+            .values()
+            .map(|queue| {
+                let inner = queue.inner.lock().unwrap();
+                QueueStatus {
+                    subscription: queue.id.clone(),
+                    kind: queue.kind.to_string(),
+                    depth: inner.unacked.len(),
+                    coalesced: inner.coalesced.as_ref().map_or(0, |(items, _)| items.len()),
+                }
+            })
+            .collect();
+        subscriptions.sort_unstable_by(|a, b| a.subscription.cmp(&b.subscription));
+        SubscriptionReport {
+            capacity: self.config.capacity,
+            policy: self.config.policy,
+            dropped: self.dropped.load(Ordering::Relaxed),
+            subscriptions,
+        }
+    }
+}
+
+fn subscription_key(id: &SubscriptionId) -> String {
+    match id {
+        SubscriptionId::Num(n) => n.to_string(),
+        SubscriptionId::Str(s) => s.to_string(),
+    }
+}
+
+fn invalid(message: String) -> CallError {
+    CallError::from_std_error(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+struct Queue {
+    id: String,
+    kind: &'static str,
+    /// Room left for notifications, only used with resume tokens
+    room: Semaphore,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// None once closed for being too slow
+    sink: Option<SubscriptionSink>,
+    /// Send `Notification`s rather than the bare items
+    tokens: bool,
+    /// Resume tokens of the notifications sent and not acknowledged yet,
+    /// oldest first
+    unacked: VecDeque<ResumeToken>,
+    /// Items merged while the queue was full, with the token of the last one
+    coalesced: Option<(Vec<Value>, ResumeToken)>,
+}
+
+impl Inner {
+    /// Send a batch and keep its token until it is acknowledged, false if
+    /// the subscriber is gone
+    fn send<T: Serialize>(&mut self, items: Vec<T>, resume: ResumeToken) -> bool {
+        let sink = match self.sink {
+            Some(ref mut sink) => sink,
+            None => return false,
+        };
+        let sent = if self.tokens {
+            sink.send(&Notification::Batch {
+                items,
+                resume: resume.clone(),
+            })
+        } else {
+            sink.send(&items)
+        };
+        match sent {
+            Ok(true) => {
+                self.unacked.push_back(resume);
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                log::error!("submit error: {}", e);
+                false
+            }
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sink.as_ref().is_none_or(|sink| sink.is_closed())
+    }
+}
+
+/// The queue of one subscription, removed once the subscription ends
+pub(crate) struct Subscriber {
+    queues: Queues,
+    queue: Arc<Queue>,
+}
+
+impl Subscriber {
+    pub fn is_closed(&self) -> bool {
+        self.queue.inner.lock().unwrap().is_closed()
+    }
+
+    /// Send a batch as the queue allows, false once the subscription should
+    /// end
+    pub async fn push<T: Serialize>(&mut self, items: Vec<T>, resume: ResumeToken) -> bool {
+        let config = self.queues.config;
+        {
+            let mut inner = self.queue.inner.lock().unwrap();
+            if !inner.tokens {
+                let sent = inner.send(items, resume);
+                // nothing acknowledges them
+                inner.unacked.clear();
+                return sent;
+            }
+        }
+
+        match config.policy {
+            SlowConsumer::Block => loop {
+                match tokio::time::timeout(BLOCKED_CHECK_INTERVAL, self.queue.room.acquire()).await
+                {
+                    Ok(Ok(permit)) => {
+                        permit.forget();
+                        break;
+                    }
+                    Ok(Err(_)) => return false,
+                    Err(_) if self.is_closed() => return false,
+                    Err(_) => (),
+                }
+            },
+            SlowConsumer::Drop => match self.queue.room.try_acquire() {
+                Ok(permit) => permit.forget(),
+                Err(_) => {
+                    self.drop_slow();
+                    return false;
+                }
+            },
+            SlowConsumer::Coalesce => {
+                let mut inner = self.queue.inner.lock().unwrap();
+                let items = match serde_json::to_value(items) {
+                    Ok(Value::Array(items)) => items,
+                    _ => return false,
+                };
+                let items = match inner.coalesced.take() {
+                    Some((mut coalesced, _)) => {
+                        coalesced.extend(items);
+                        coalesced
+                    }
+                    None => items,
+                };
+                // earlier items are still waiting, the new ones go after them
+                return match self.queue.room.try_acquire() {
+                    Ok(permit) => {
+                        permit.forget();
+                        inner.send(items, resume)
+                    }
+                    Err(_) => {
+                        inner.coalesced = Some((items, resume));
+                        !inner.is_closed()
+                    }
+                };
+            }
+        }
+
+        self.queue.inner.lock().unwrap().send(items, resume)
+    }
+
+    fn drop_slow(&self) {
+        let mut inner = self.queue.inner.lock().unwrap();
+        let message = format!(
+            "subscriber left {} notifications unacknowledged, resume from the last token received",
+            inner.unacked.len()
+        );
+        if let Some(sink) = inner.sink.take() {
+            sink.close(CallError::from_std_error(io::Error::other(message)));
+            self.queues.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.queues.queues.lock().unwrap().remove(&self.queue.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_types::H256;
+    use jsonrpsee::{core::server::rpc_module::Subscription, RpcModule};
+    use tokio::sync::mpsc;
+
+    struct Harness {
+        queues: Queues,
+        subscribers: mpsc::UnboundedSender<Subscriber>,
+    }
+
+    /// Subscribe over an in-process module, `tokens` as the subscriber asked
+    async fn subscribe(queues: &Queues, tokens: bool) -> (Subscription, Subscriber) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut module = RpcModule::new(Harness {
+            queues: queues.clone(),
+            subscribers: tx,
+        });
+        module
+            .register_subscription("sub", "sub", "unsub", |params, mut sink, ctx| {
+                let tokens: bool = params.one()?;
+                sink.accept()?;
+                let _ = ctx.subscribers.send(ctx.queues.open("test", sink, tokens));
+                Ok(())
+            })
+            .unwrap();
+        let subscription = module.subscribe("sub", [tokens]).await.unwrap();
+        (subscription, rx.recv().await.unwrap())
+    }
+
+    fn queues(capacity: usize, policy: SlowConsumer) -> Queues {
+        Queues::new(QueueConfig { capacity, policy })
+    }
+
+    fn token(number: u64) -> ResumeToken {
+        ResumeToken {
+            block_number: number.into(),
+            block_hash: H256::default(),
+            position: 0.into(),
+        }
+    }
+
+    fn id(subscription: &Subscription) -> Value {
+        serde_json::to_value(subscription.subscription_id()).unwrap()
+    }
+
+    fn depth(queues: &Queues) -> (usize, usize) {
+        let report = queues.report();
+        assert_eq!(report.subscriptions.len(), 1);
+        let status = &report.subscriptions[0];
+        (status.depth, status.coalesced)
+    }
+
+    async fn batch(subscription: &mut Subscription) -> (Vec<u64>, u64) {
+        let (notification, _) = subscription
+            .next::<Notification<u64>>()
+            .await
+            .unwrap()
+            .unwrap();
+        match notification {
+            Notification::Batch { items, resume } => (items, resume.block_number.value()),
+            Notification::Reorg { .. } => panic!("unexpected reorg"),
+        }
+    }
+
+    #[tokio::test]
+    async fn acks_up_to_a_token() {
+        let queues = queues(3, SlowConsumer::Drop);
+        let (mut subscription, mut subscriber) = subscribe(&queues, true).await;
+        for n in 1..=3 {
+            assert!(subscriber.push(vec![n], token(n)).await);
+        }
+        assert_eq!(depth(&queues), (3, 0));
+        for n in 1..=3 {
+            assert_eq!(batch(&mut subscription).await, (vec![n], n));
+        }
+
+        let id = id(&subscription);
+        assert_eq!(queues.ack(&id, &token(2)).unwrap(), 2);
+        assert_eq!(depth(&queues), (1, 0));
+        // already acknowledged
+        assert!(queues.ack(&id, &token(1)).is_err());
+        assert_eq!(queues.ack(&id, &token(3)).unwrap(), 1);
+        assert_eq!(depth(&queues), (0, 0));
+
+        assert!(queues.ack(&Value::from(12345), &token(3)).is_err());
+    }
+
+    #[tokio::test]
+    async fn drop_closes_a_full_queue() {
+        let queues = queues(2, SlowConsumer::Drop);
+        let (mut subscription, mut subscriber) = subscribe(&queues, true).await;
+        assert!(subscriber.push(vec![1], token(1)).await);
+        assert!(subscriber.push(vec![2], token(2)).await);
+        assert!(!subscriber.push(vec![3], token(3)).await);
+
+        let report = queues.report();
+        assert_eq!(report.dropped, 1);
+        assert_eq!(report.subscriptions[0].depth, 2);
+        assert!(subscriber.is_closed());
+
+        assert_eq!(batch(&mut subscription).await, (vec![1], 1));
+        assert_eq!(batch(&mut subscription).await, (vec![2], 2));
+        assert!(subscription.next::<Value>().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_an_ack() {
+        let queues = queues(1, SlowConsumer::Block);
+        let (mut subscription, mut subscriber) = subscribe(&queues, true).await;
+        assert!(subscriber.push(vec![1], token(1)).await);
+
+        let pushed = tokio::spawn(async move {
+            let sent = subscriber.push(vec![2], token(2)).await;
+            (sent, subscriber)
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!pushed.is_finished());
+        assert_eq!(depth(&queues), (1, 0));
+
+        assert_eq!(queues.ack(&id(&subscription), &token(1)).unwrap(), 1);
+        let (sent, _subscriber) = pushed.await.unwrap();
+        assert!(sent);
+        assert_eq!(depth(&queues), (1, 0));
+        assert_eq!(batch(&mut subscription).await, (vec![1], 1));
+        assert_eq!(batch(&mut subscription).await, (vec![2], 2));
+    }
+
+    #[tokio::test]
+    async fn coalesce_merges_until_an_ack() {
+        let queues = queues(1, SlowConsumer::Coalesce);
+        let (mut subscription, mut subscriber) = subscribe(&queues, true).await;
+        assert!(subscriber.push(vec![1], token(1)).await);
+        assert!(subscriber.push(vec![2], token(2)).await);
+        assert!(subscriber.push(vec![3, 4], token(4)).await);
+        assert_eq!(depth(&queues), (1, 3));
+        assert_eq!(batch(&mut subscription).await, (vec![1], 1));
+
+        let id = id(&subscription);
+        // the ack frees room for the merged batch, which takes it at once
+        assert_eq!(queues.ack(&id, &token(1)).unwrap(), 1);
+        assert_eq!(depth(&queues), (1, 0));
+        assert_eq!(batch(&mut subscription).await, (vec![2, 3, 4], 4));
+
+        // the tokens of merged items were never sent
+        assert!(queues.ack(&id, &token(2)).is_err());
+        assert_eq!(queues.ack(&id, &token(4)).unwrap(), 1);
+        assert_eq!(depth(&queues), (0, 0));
+
+        // with room again nothing is held back
+        assert!(subscriber.push(vec![5], token(5)).await);
+        assert_eq!(depth(&queues), (1, 0));
+        assert_eq!(batch(&mut subscription).await, (vec![5], 5));
+    }
+
+    #[tokio::test]
+    async fn untracked_without_tokens() {
+        let queues = queues(1, SlowConsumer::Drop);
+        let (mut subscription, mut subscriber) = subscribe(&queues, false).await;
+        for n in 1..=3 {
+            assert!(subscriber.push(vec![n], token(n)).await);
+        }
+        assert_eq!(depth(&queues), (0, 0));
+        assert_eq!(queues.report().dropped, 0);
+        for n in 1..=3 {
+            let (items, _) = subscription.next::<Vec<u64>>().await.unwrap().unwrap();
+            assert_eq!(items, vec![n]);
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_subscriptions_leave_the_report() {
+        let queues = queues(1, SlowConsumer::Drop);
+        let (subscription, subscriber) = subscribe(&queues, true).await;
+        assert_eq!(queues.report().subscriptions[0].kind, "test");
+        drop(subscriber);
+        assert!(queues.report().subscriptions.is_empty());
+        assert!(queues.ack(&id(&subscription), &token(1)).is_err());
+    }
+}
@@ -1,20 +1,134 @@
-use ckb_jsonrpc_types::BlockNumber;
+use ckb_jsonrpc_types::{BlockNumber, Uint32};
+use ckb_types::H256;
 use emitter_core::{
+    address::resolve_search_key,
     cell_process::CellProcess,
     header_sync::HeaderSyncProcess,
     rpc_client::RpcClient,
-    types::{HeaderViewWithExtension, IndexerTip, RpcSearchKey},
+    types::{HeaderViewWithExtension, IndexerTip, RpcSearchKey, RpcSearchKeyParam},
     Submit, SubmitProcess,
 };
+use ethers::types::TxHash;
+use futures::{future, TryStreamExt};
 use jsonrpsee::{
-    core::async_trait,
+    core::{async_trait, error::SubscriptionClosed},
     server::{RpcModule, SubscriptionSink},
-    types::error::CallError,
+    types::{
+        error::{CallError, ErrorObject, ErrorObjectOwned, INVALID_PARAMS_CODE},
+        Params, ParamsSequence,
+    },
 };
+use serde::{de::DeserializeOwned, Deserialize, Serialize, Serializer};
+use serde_json::Value;
+use tokio::sync::broadcast::{self, error::RecvError};
 
-use std::io;
+use std::{io, sync::Arc};
 
-struct WsSubmit(SubscriptionSink);
+use crate::ws_queue::{Queues, Subscriber};
+
+/// Events a `registrations` subscriber may fall behind by before it is
+/// dropped
+const RELAY_EVENTS_CAPACITY: usize = 1024;
+
+/// `emitter_subscription` could not ask the ckb node to check its
+/// parameters, subscribing again later may work
+pub const CKB_UNAVAILABLE_CODE: i32 = -32001;
+/// The subscription kind is not served by this emitter
+pub const UNSUPPORTED_CODE: i32 = -32002;
+
+/// A batch the relay sent to axon
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RelayEvent {
+    Cells {
+        search_key: Box<RpcSearchKey>,
+        submits: Vec<Submit>,
+        /// Null if sending failed
+        axon_tx_hash: Option<TxHash>,
+    },
+    Headers {
+        headers: Vec<HeaderViewWithExtension>,
+        axon_tx_hash: Option<TxHash>,
+    },
+}
+
+/// Where a `cell_filter` or `header_sync` subscription stands after the
+/// last item a client received
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ResumeToken {
+    pub block_number: BlockNumber,
+    pub block_hash: H256,
+    /// Transactions of the block delivered so far, always 0 for headers
+    pub position: Uint32,
+}
+
+/// Where a `cell_filter` or `header_sync` subscription starts
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Start {
+    /// The first block to scan
+    Block(BlockNumber),
+    /// Right after the last item delivered before
+    Resume(ResumeToken),
+}
+
+impl From<BlockNumber> for Start {
+    fn from(number: BlockNumber) -> Self {
+        Start::Block(number)
+    }
+}
+
+impl From<u64> for Start {
+    fn from(number: u64) -> Self {
+        Start::Block(number.into())
+    }
+}
+
+impl From<ResumeToken> for Start {
+    fn from(token: ResumeToken) -> Self {
+        Start::Resume(token)
+    }
+}
+
+/// A notification of the `cell_filter` or `header_sync` subscriptions that
+/// asked for resume tokens, the others get the bare items
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Notification<T> {
+    /// Items in chain order, `resume` picks up after the last one
+    Batch { items: Vec<T>, resume: ResumeToken },
+    /// The block of the token the subscription resumed from is no longer on
+    /// the chain, the subscription ends
+    Reorg {
+        orphaned: ResumeToken,
+        /// The block at that height now
+        canonical_hash: H256,
+    },
+}
+
+/// Every batch the relay sends, for the `registrations` subscription
+pub type RelayEvents = broadcast::Sender<Arc<RelayEvent>>;
+
+pub fn relay_events() -> RelayEvents {
+    broadcast::channel(RELAY_EVENTS_CAPACITY).0
+}
+
+struct SharedEvent(Arc<RelayEvent>);
+
+impl Serialize for SharedEvent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+pub struct WsContext {
+    client: RpcClient,
+    /// None without a relay in the same process
+    events: Option<RelayEvents>,
+    queues: Queues,
+}
+
+struct WsSubmit(Subscriber);
 
 #[async_trait]
 impl SubmitProcess for WsSubmit {
@@ -23,88 +137,125 @@ impl SubmitProcess for WsSubmit {
     }
 
     async fn submit_cells(&mut self, cells: Vec<Submit>) -> bool {
-        if cells.is_empty() {
-            return true;
-        }
-        match self.0.send(&cells) {
-            Ok(r) => r,
-            Err(e) => {
-                log::error!("submit cells error: {}", e);
-                false
-            }
-        }
+        self.submit_cells_at(cells, 0).await
+    }
+
+    async fn submit_cells_at(&mut self, cells: Vec<Submit>, position: u32) -> bool {
+        let resume = match cells.last() {
+            Some(last) => ResumeToken {
+                block_number: last.header.inner.number,
+                block_hash: last.header.hash.clone(),
+                position: position.into(),
+            },
+            None => return true,
+        };
+        self.0.push(cells, resume).await
     }
 
     async fn submit_headers(&mut self, headers: Vec<HeaderViewWithExtension>) -> bool {
-        if headers.is_empty() {
-            return true;
-        }
-        match self.0.send(&headers) {
-            Ok(r) => r,
-            Err(e) => {
-                log::error!("submit headers error: {}", e);
-                false
-            }
-        }
+        let resume = match headers.last() {
+            Some(last) => ResumeToken {
+                block_number: last.inner.inner.number,
+                block_hash: last.inner.hash.clone(),
+                position: 0.into(),
+            },
+            None => return true,
+        };
+        self.0.push(headers, resume).await
     }
 }
 
-pub async fn ws_subscription_module(client: RpcClient) -> RpcModule<RpcClient> {
-    let mut rpc = RpcModule::new(client);
+/// The subscription methods, modules given the same `queues` share their
+/// queues and `subscription_status`
+pub async fn ws_subscription_module(
+    client: RpcClient,
+    events: Option<RelayEvents>,
+    queues: Queues,
+) -> RpcModule<WsContext> {
+    let mut rpc = RpcModule::new(WsContext {
+        client,
+        events,
+        queues,
+    });
 
     rpc.register_subscription(
         "emitter_subscription",
         "emitter_subscription",
         "emitter_unsubscribe",
         |params, mut sink, ctx| {
-            sink.accept()?;
-            let mut iter = params.sequence();
-            let method: &str = iter.next()?;
-
-            match method {
-                "cell_filter" => {
-                    let key: RpcSearchKey = iter.next()?;
-                    let start: BlockNumber = iter.next()?;
-                    let client = ctx.as_ref().clone();
+            let subscribe = match Subscribe::parse(params) {
+                Ok(subscribe) => subscribe,
+                Err(e) => {
+                    let _ = sink.reject(e);
+                    return Ok(());
+                }
+            };
+            let client = ctx.client.clone();
+            let queues = ctx.queues.clone();
 
+            match subscribe {
+                Subscribe::CellFilter(key, start, tokens) => {
                     tokio::spawn(async move {
-                        match cell_process(start, client.clone()).await {
-                            Ok(tip) => {
-                                let mut cell_process =
-                                    CellProcess::new(key, tip, client, WsSubmit(sink));
-
-                                tokio::spawn(async move {
-                                    cell_process.run().await;
-                                });
+                        let checked = check_cell_filter(&client, key, start).await;
+                        let (key, tip, position) = match checked {
+                            Ok((key, Begin::At(tip, position))) => (key, tip, position),
+                            Ok((_, Begin::Orphaned(token, hash))) => {
+                                return send_reorg(sink, token, hash);
                             }
                             Err(e) => {
                                 let _ = sink.reject(e);
+                                return;
                             }
+                        };
+                        if sink.accept().is_err() {
+                            return;
                         }
+                        let submit = WsSubmit(queues.open("cell_filter", sink, tokens));
+                        let mut cell_process =
+                            CellProcess::new(key, tip, client, submit).resume_at(position);
+                        cell_process.run().await;
                     });
                 }
-                "header_sync" => {
-                    let start: BlockNumber = iter.next()?;
-                    let client = ctx.as_ref().clone();
+                Subscribe::HeaderSync(start, tokens) => {
                     tokio::spawn(async move {
-                        let start_tip = {
-                            let header = client.get_header_by_number(start).await.unwrap();
-                            IndexerTip {
-                                block_hash: header.hash,
-                                block_number: header.inner.number,
+                        let resumed = matches!(start, Start::Resume(_));
+                        let start_tip = match begin(&client, start).await {
+                            // header sync goes by the number only, the next
+                            // block may not be there yet
+                            Ok(Begin::At(tip, _)) if resumed => IndexerTip {
+                                block_hash: tip.block_hash,
+                                block_number: (tip.block_number.value() + 1).into(),
+                            },
+                            Ok(Begin::At(tip, _)) => tip,
+                            Ok(Begin::Orphaned(token, hash)) => {
+                                return send_reorg(sink, token, hash);
+                            }
+                            Err(e) => {
+                                let _ = sink.reject(e);
+                                return;
                             }
                         };
-                        let mut header_sync =
-                            HeaderSyncProcess::new(start_tip, client, WsSubmit(sink));
+                        if sink.accept().is_err() {
+                            return;
+                        }
+                        let submit = WsSubmit(queues.open("header_sync", sink, tokens));
+                        let mut header_sync = HeaderSyncProcess::new(start_tip, client, submit);
                         header_sync.run().await;
                     });
                 }
-                _ => {
-                    return Err(CallError::from_std_error(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("invalid method: {}", method),
-                    ))
-                    .into());
+                Subscribe::Registrations(search_key) => {
+                    let events = match ctx.events {
+                        Some(ref events) => events.subscribe(),
+                        None => {
+                            let _ = sink.reject(ErrorObject::owned(
+                                UNSUPPORTED_CODE,
+                                "registrations are only served together with the http rpc",
+                                None::<()>,
+                            ));
+                            return Ok(());
+                        }
+                    };
+                    tokio::spawn(pipe_relay_events(sink, events, search_key));
                 }
             }
 
@@ -112,28 +263,225 @@ pub async fn ws_subscription_module(client: RpcClient) -> RpcModule<RpcClient> {
         },
     )
     .unwrap();
+
+    rpc.register_method("emitter_ack", |params, ctx| {
+        let mut iter = params.sequence();
+        let subscription: Value = iter.next()?;
+        let token: ResumeToken = iter.next()?;
+        ctx.queues.ack(&subscription, &token).map_err(Into::into)
+    })
+    .unwrap();
+
+    rpc.register_method("subscription_status", |_, ctx| Ok(ctx.queues.report()))
+        .unwrap();
     rpc
 }
 
-async fn cell_process(start: BlockNumber, client: RpcClient) -> Result<IndexerTip, CallError> {
-    let indexer_tip = client.get_indexer_tip().await.map_err(|e| {
-        CallError::from_std_error(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
-    })?;
+/// Forward the batches of the registration `search_key`, or of all
+/// registrations and header sync if none, until the subscriber goes away or
+/// falls too far behind
+async fn pipe_relay_events(
+    mut sink: SubscriptionSink,
+    events: broadcast::Receiver<Arc<RelayEvent>>,
+    search_key: Option<RpcSearchKey>,
+) {
+    let stream = futures::stream::unfold(events, |mut events| async move {
+        match events.recv().await {
+            Ok(event) => Some((Ok(SharedEvent(event)), events)),
+            Err(RecvError::Lagged(n)) => Some((
+                Err(format!("subscriber fell behind by {} events", n)),
+                events,
+            )),
+            Err(RecvError::Closed) => None,
+        }
+    })
+    .try_filter(move |event| {
+        future::ready(match (&search_key, &*event.0) {
+            (None, _) => true,
+            (Some(key), RelayEvent::Cells { search_key, .. }) => key == &**search_key,
+            (Some(_), RelayEvent::Headers { .. }) => false,
+        })
+    });
+
+    match sink.pipe_from_try_stream(Box::pin(stream)).await {
+        SubscriptionClosed::Success => {
+            let err: ErrorObjectOwned = SubscriptionClosed::Success.into();
+            sink.close(err);
+        }
+        SubscriptionClosed::RemotePeerAborted => (),
+        SubscriptionClosed::Failed(err) => {
+            sink.close(err);
+        }
+    }
+}
 
-    if indexer_tip.block_number > start {
-        let header = client.get_header_by_number(start).await.map_err(|e| {
-            CallError::from_std_error(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
-        })?;
+/// What `emitter_subscription` was asked for, parsed before it is accepted.
+/// The flags tell whether notifications carry resume tokens.
+enum Subscribe {
+    CellFilter(RpcSearchKeyParam, Start, bool),
+    HeaderSync(Start, bool),
+    Registrations(Option<RpcSearchKey>),
+}
 
-        let tip = IndexerTip {
-            block_hash: header.hash,
-            block_number: header.inner.number,
+impl Subscribe {
+    fn parse(params: Params) -> Result<Self, ErrorObjectOwned> {
+        let mut iter = params.sequence();
+        let kind: String = required(&mut iter, "kind")?;
+        let subscribe = match kind.as_str() {
+            "cell_filter" => {
+                let key = required(&mut iter, "search_key")?;
+                let start = start(&mut iter)?;
+                let tokens = resume_tokens(&mut iter, &start)?;
+                Subscribe::CellFilter(key, start, tokens)
+            }
+            "header_sync" => {
+                let start = start(&mut iter)?;
+                let tokens = resume_tokens(&mut iter, &start)?;
+                Subscribe::HeaderSync(start, tokens)
+            }
+            "registrations" => Subscribe::Registrations(
+                iter.optional_next()
+                    .map_err(|e| invalid_param("search_key", e))?,
+            ),
+            _ => {
+                return Err(invalid_params(format!(
+                    "unknown subscription {}, expected cell_filter, header_sync or registrations",
+                    kind
+                )))
+            }
         };
-        Ok(tip)
-    } else {
-        Err(CallError::from_std_error(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid start block number".to_string(),
-        )))
+        Ok(subscribe)
+    }
+}
+
+fn required<T: DeserializeOwned>(
+    iter: &mut ParamsSequence,
+    name: &str,
+) -> Result<T, ErrorObjectOwned> {
+    match iter.optional_next() {
+        Ok(Some(value)) => Ok(value),
+        Ok(None) => Err(invalid_params(format!("{} is required", name))),
+        Err(e) => Err(invalid_param(name, e)),
+    }
+}
+
+fn invalid_param(name: &str, e: CallError) -> ErrorObjectOwned {
+    let reason = match e {
+        CallError::InvalidParams(e) => e.to_string(),
+        e => e.to_string(),
+    };
+    invalid_params(format!("invalid {}: {}", name, reason))
+}
+
+/// `required` for the start, serde has nothing to say about an untagged enum
+/// but that it did not match
+fn start(iter: &mut ParamsSequence) -> Result<Start, ErrorObjectOwned> {
+    match iter.optional_next() {
+        Ok(Some(start)) => Ok(start),
+        Ok(None) => Err(invalid_params("start is required")),
+        Err(_) => Err(invalid_params(
+            "start must be a block number or a resume token",
+        )),
+    }
+}
+
+/// Whether notifications carry resume tokens, as asked for after the start.
+/// Subscribers resuming from a token already know them.
+fn resume_tokens(iter: &mut ParamsSequence, start: &Start) -> Result<bool, ErrorObjectOwned> {
+    let asked = iter
+        .optional_next::<bool>()
+        .map_err(|e| invalid_param("resume_tokens", e))?;
+    Ok(asked.unwrap_or(matches!(start, Start::Resume(_))))
+}
+
+fn invalid_params(message: impl Into<String>) -> ErrorObjectOwned {
+    ErrorObject::owned(INVALID_PARAMS_CODE, message.into(), None::<()>)
+}
+
+fn ckb_unavailable(what: &str, e: io::Error) -> ErrorObjectOwned {
+    ErrorObject::owned(
+        CKB_UNAVAILABLE_CODE,
+        format!("Failed to {}, error: {}", what, e),
+        None::<()>,
+    )
+}
+
+/// A malformed address is the subscriber's fault, anything else is the node's
+fn search_key_error(e: io::Error) -> ErrorObjectOwned {
+    match e.kind() {
+        io::ErrorKind::InvalidInput => invalid_params(format!("invalid search_key: {}", e)),
+        _ => ckb_unavailable("resolve the search key", e),
+    }
+}
+
+async fn check_cell_filter(
+    client: &RpcClient,
+    key: RpcSearchKeyParam,
+    start: Start,
+) -> Result<(RpcSearchKey, Begin), ErrorObjectOwned> {
+    let key = resolve_search_key(client, key)
+        .await
+        .map_err(search_key_error)?;
+    Ok((key, begin(client, start).await?))
+}
+
+/// Where a checked subscription starts
+enum Begin {
+    /// The tip to scan from, with the transactions of its block delivered
+    /// before
+    At(IndexerTip, u32),
+    /// The block of the token was orphaned, by the block with this hash
+    Orphaned(ResumeToken, H256),
+}
+
+async fn begin(client: &RpcClient, start: Start) -> Result<Begin, ErrorObjectOwned> {
+    match start {
+        Start::Block(number) => Ok(Begin::At(indexed_header(client, number).await?, 0)),
+        Start::Resume(token) => {
+            let tip = indexed_header(client, token.block_number).await?;
+            if tip.block_hash != token.block_hash {
+                return Ok(Begin::Orphaned(token, tip.block_hash));
+            }
+            Ok(Begin::At(tip, token.position.value()))
+        }
+    }
+}
+
+/// Block `number`, which the ckb indexer must have reached
+async fn indexed_header(
+    client: &RpcClient,
+    number: BlockNumber,
+) -> Result<IndexerTip, ErrorObjectOwned> {
+    let indexer_tip = client
+        .get_indexer_tip()
+        .await
+        .map_err(|e| ckb_unavailable("get the indexer tip", e))?;
+    if number > indexer_tip.block_number {
+        return Err(invalid_params(format!(
+            "block {} is above the indexer tip {}",
+            number.value(),
+            indexer_tip.block_number.value()
+        )));
+    }
+    let header = client
+        .get_header_by_number(number)
+        .await
+        .map_err(|e| ckb_unavailable("get the header", e))?;
+    Ok(IndexerTip {
+        block_hash: header.hash,
+        block_number: header.inner.number,
+    })
+}
+
+/// Tell a subscriber resuming from an orphaned block so, and end the
+/// subscription
+fn send_reorg(mut sink: SubscriptionSink, orphaned: ResumeToken, canonical_hash: H256) {
+    let reorg = Notification::<Submit>::Reorg {
+        orphaned,
+        canonical_hash,
+    };
+    if let Err(e) = sink.send(&reorg) {
+        log::error!("submit error: {}", e);
     }
+    sink.close(SubscriptionClosed::Success);
 }